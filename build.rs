@@ -0,0 +1,150 @@
+// Generates the opcode tables of `src/opcodes.rs` from `data/opcodes_table.csv`
+use std::fmt::Write;
+
+const CSV: &str = "data/opcodes_table.csv";
+
+struct Row {
+    code: u8,
+    name: String,
+    /// Name of the `AddressingMode` variant
+    mode: String,
+    size: u8,
+    cycles: u8,
+    page_penalty: u8,
+    cpus: Vec<String>,
+}
+
+fn parse_row(line_number: usize, line: &str) -> Row {
+    let fields: Vec<&str> = line.trim().split(',').collect();
+    let number = |i: usize, radix: u32| -> u8 {
+        u8::from_str_radix(fields[i], radix)
+            .unwrap_or_else(|e| panic!("{}:{}: {:?} {}", CSV, line_number, fields[i], e))
+    };
+    if fields.len() != 7 {
+        panic!("{}:{}: expected 7 columns", CSV, line_number);
+    }
+    Row {
+        code: number(0, 16),
+        name: fields[1].to_string(),
+        mode: fields[2].to_ascii_uppercase(),
+        size: number(3, 10),
+        cycles: number(4, 10),
+        page_penalty: number(5, 10),
+        cpus: fields[6].split(' ').map(String::from).collect(),
+    }
+}
+
+/// Every different value, in the order they first appear
+fn unique<'r>(values: impl Iterator<Item = &'r String>) -> Vec<&'r String> {
+    let mut seen: Vec<&String> = Vec::new();
+    for value in values {
+        if !seen.contains(&value) {
+            seen.push(value);
+        }
+    }
+    seen
+}
+
+fn main() -> std::fmt::Result {
+    println!("cargo:rerun-if-changed={}", CSV);
+    println!("cargo:rerun-if-changed=build.rs");
+    let csv = std::fs::read_to_string(CSV).expect("Couldn't read the opcodes table");
+    let rows: Vec<Row> = csv
+        .lines()
+        .enumerate()
+        .skip(1)
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| parse_row(i + 1, line))
+        .collect();
+    let names = unique(rows.iter().map(|row| &row.name));
+    let cpus = unique(rows.iter().flat_map(|row| row.cpus.iter()));
+
+    let mut out = String::new();
+    writeln!(out, "// Generated by build.rs from {}", CSV)?;
+    writeln!(out, "#[derive(Debug, PartialEq, Copy, Clone)]")?;
+    writeln!(out, "pub enum OpcodeType {{")?;
+    for name in &names {
+        writeln!(out, "    {},", name)?;
+    }
+    writeln!(out, "}}")?;
+    writeln!(out, "impl OpcodeType {{")?;
+    writeln!(out, "    pub const COUNT: usize = {};", names.len())?;
+    writeln!(out, "    /// The mnemonic, in upper case")?;
+    writeln!(
+        out,
+        "    fn from_mnemonic(mnemonic: &str) -> Option<OpcodeType> {{"
+    )?;
+    writeln!(out, "        match mnemonic {{")?;
+    for name in &names {
+        writeln!(out, "            {:?} => Some(OpcodeType::{}),", name, name)?;
+    }
+    writeln!(out, "            _ => None,")?;
+    writeln!(out, "        }}")?;
+    writeln!(out, "    }}")?;
+    writeln!(out, "}}")?;
+
+    for cpu in &cpus {
+        let ident = cpu.to_ascii_uppercase();
+        let mut opcodes: Vec<Option<&Row>> = vec![None; 256];
+        for row in rows.iter().filter(|row| row.cpus.contains(cpu)) {
+            if let Some(other) = opcodes[row.code as usize] {
+                panic!(
+                    "{:#04X} is both {} {} and {} {} on the {}",
+                    row.code, other.name, other.mode, row.name, row.mode, cpu
+                );
+            }
+            opcodes[row.code as usize] = Some(row);
+        }
+        writeln!(out, "\n/// Opcodes of the {}, by code", cpu)?;
+        writeln!(
+            out,
+            "pub static OPCODES_{}: [Option<OpcodeData>; 256] = [",
+            ident
+        )?;
+        for opcode in &opcodes {
+            match opcode {
+                None => writeln!(out, "    None,")?,
+                Some(row) => writeln!(
+                    out,
+                    "    Some(OpcodeData {{ name: OpcodeType::{}, addr_mode: AddressingMode::{}, size: {}, cycles: {}, page_penalty: {} }}),",
+                    row.name, row.mode, row.size, row.cycles, row.page_penalty
+                )?,
+            }
+        }
+        writeln!(out, "];")?;
+        // When an instruction has more than one code, the first one on the table is used
+        writeln!(
+            out,
+            "\n/// Code of each instruction and addressing mode on the {}",
+            cpu
+        )?;
+        writeln!(
+            out,
+            "static CODES_{}: [[Option<u8>; MODES]; OpcodeType::COUNT] = {{",
+            ident
+        )?;
+        writeln!(
+            out,
+            "    let mut codes = [[None; MODES]; OpcodeType::COUNT];"
+        )?;
+        let mut done: Vec<(&String, &String)> = Vec::new();
+        for row in rows.iter().filter(|row| row.cpus.contains(cpu)) {
+            if done.contains(&(&row.name, &row.mode)) {
+                continue;
+            }
+            done.push((&row.name, &row.mode));
+            writeln!(
+                out,
+                "    codes[OpcodeType::{} as usize][AddressingMode::{} as usize] = Some({:#04X});",
+                row.name, row.mode, row.code
+            )?;
+        }
+        writeln!(out, "    codes")?;
+        writeln!(out, "}};")?;
+    }
+
+    let path = std::path::PathBuf::from(std::env::var("OUT_DIR").expect("OUT_DIR isn't set"))
+        .join("opcodes_table.rs");
+    std::fs::write(path, out).expect("Couldn't write the opcodes table");
+    Ok(())
+}
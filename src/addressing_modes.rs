@@ -2,9 +2,15 @@ pub fn get_size(addr_mode: AddressingMode) -> usize {
     OP_SIZES[addr_mode as usize]
 }
 
-//A,abs,absX,absY,imm,impl,ind,indX,indY,rel,zpg,zpgX,zpgY
-//1,  3,   3,   3,   2,  1,  3,   2,   2,  2,  2,   2,   2
-pub static OP_SIZES: [usize; 13] = [1, 3, 3, 3, 2, 1, 3, 2, 2, 2, 2, 2, 2];
+//A,abs,absX,absY,imm,impl,ind,indX,indY,rel,zpg,zpgX,zpgY,zpgInd,absIndX,zpgRel
+//1,  3,   3,   3,   2,  1,  3,   2,   2,  2,  2,   2,   2,     2,      3,     3
+//absL,absLX,dpIndL,dpIndLY,sr,srIndY,blk,relL,absIndL
+//   4,    4,     2,      2, 2,     2,  3,   3,      3
+/// Number of addressing modes
+pub const MODES: usize = 25;
+pub static OP_SIZES: [usize; MODES] = [
+    1, 3, 3, 3, 2, 1, 3, 2, 2, 2, 2, 2, 2, 2, 3, 3, 4, 4, 2, 2, 2, 2, 3, 3, 3,
+];
 
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum AddressingMode {
@@ -34,4 +40,28 @@ pub enum AddressingMode {
     ZPGX,
     /// Ej.: `LDA $10,Y`
     ZPGY,
+    /// Ej.: `LDA ($40)` (65C02)
+    ZPGIND,
+    /// Ej.: `JMP ($1234,X)` (65C02)
+    ABSINDX,
+    /// Ej.: `BBR0 $10,LABEL` (65C02)
+    ZPGREL,
+    /// Ej.: `LDA $7E2000` (65816)
+    ABSL,
+    /// Ej.: `LDA $7E2000,X` (65816)
+    ABSLX,
+    /// Ej.: `LDA [$10]` (65816)
+    DPINDL,
+    /// Ej.: `LDA [$10],Y` (65816)
+    DPINDLY,
+    /// Ej.: `LDA $03,S` (65816)
+    SR,
+    /// Ej.: `LDA ($03,S),Y` (65816)
+    SRINDY,
+    /// Ej.: `MVN $7E,$01` (65816)
+    BLK,
+    /// Ej.: `BRL LABEL` (65816)
+    RELL,
+    /// Ej.: `JML [$FFFC]` (65816)
+    ABSINDL,
 }
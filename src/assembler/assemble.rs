@@ -1,15 +1,19 @@
-use super::{Code, LabelUse, LineType, LineType::*, Metadata, Value};
-use crate::opcodes::get_code;
-use crate::{addressing_modes, Error};
-use std::collections::HashMap;
+use super::{
+    Assembled, Code, Expect, Expr, Label, LabelUse, LineType, Metadata, Proc, SourceLine, Symbols,
+    Test, Value,
+};
+use crate::addressing_modes::{self, AddressingMode};
+use crate::error::Error;
+use crate::opcodes::{get_code, Cpu, OpcodeType};
+use crate::parser::{parse_source, Assertion, Expectation, Target};
 use std::fs::File;
 use std::io::Read;
 
 macro_rules! impl_macros {
-    ($type:ident,$arg:ident, $($name:literal => { $($pattern:pat => $code:expr),+ }),+  ) => {{
+    ($type:ident,$arg:ident, $($($name:literal)|+ => { $($pattern:pat => $code:expr),+ }),+  ) => {{
         match &*$type {
             $(
-                $name => {
+                $($name)|+ => {
                     match $arg {
                         $($pattern => {$code}),+
                         _ => {
@@ -29,77 +33,669 @@ macro_rules! impl_macros {
     }};
 }
 
-pub fn assemble(parsed_code: Vec<LineType>, metadata: &Metadata) -> Result<[u8; 0x10000], Error> {
+/// Place the value of `label_use.expr` on `label_use.location`
+fn place_value(code: &mut Code, label_use: &LabelUse, value: isize) -> Result<(), Error> {
+    code.pointer = label_use.location;
+    if label_use.is_relative {
+        // Calc de diff between the 2 addresses
+        // The + size is to skip the opcode's argument
+        let relative = value - (label_use.logical + label_use.size) as isize;
+        let max = if label_use.size == 1 { 0x7F } else { 0x7FFF };
+        if relative < -max - 1 || relative > max {
+            return Err(Error::Assembler {
+                cause: format!(
+                    "Branch to {:#06X} from {:#06X} is out of range ({})",
+                    value, label_use.pc, relative
+                ),
+            });
+        }
+        if label_use.size == 1 {
+            code.push_byte((relative & 0xFF) as u8);
+        } else {
+            code.push_long((relative & 0xFFFF) as u16);
+        }
+    } else if label_use.size == 1 {
+        if !(-0x80..=0xFF).contains(&value) {
+            return Err(Error::Assembler {
+                cause: format!("{:?} = {:#X} doesn't fit in a byte", label_use.expr, value),
+            });
+        }
+        code.push_byte((value & 0xFF) as u8);
+    } else if label_use.size == 2 {
+        // Addresses on the bank of the code can be reached with 16 bits (65816)
+        let same_bank = value > 0xFFFF && value >> 16 == (label_use.logical >> 16) as isize;
+        if !(-0x8000..=0xFFFF).contains(&value) && !same_bank {
+            return Err(Error::Assembler {
+                cause: format!("{:?} = {:#X} doesn't fit in a word", label_use.expr, value),
+            });
+        }
+        code.push_long((value & 0xFFFF) as u16);
+    } else {
+        if !(0..=0xFF_FFFF).contains(&value) {
+            return Err(Error::Assembler {
+                cause: format!("{:?} = {:#X} isn't a long address", label_use.expr, value),
+            });
+        }
+        code.push_far(value as u32);
+    }
+    Ok(())
+}
+
+/// Evaluate `label_use.expr` and place it on the code.
+/// If it uses a label that hasn't been defined yet, leave room for it and save it for later
+fn use_expr(
+    code: &mut Code,
+    symbols: &Symbols,
+    labels_used_on: &mut Vec<LabelUse>,
+    label_use: LabelUse,
+) -> Result<(), Error> {
+    match label_use.expr.eval(Some(label_use.pc as isize), &|name| {
+        symbols.lookup(&label_use.scope, name)
+    }) {
+        Ok(value) => place_value(code, &label_use, value),
+        Err(Error::UndefLabel { .. }) => {
+            // Since we don't know what to place here, just skip the argument
+            code.skip(label_use.size);
+            labels_used_on.push(label_use);
+            Ok(())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Evaluate an expression that must be known right away (Ej.: the argument of `.org`)
+fn eval_now(expr: &Expr, code: &Code, symbols: &Symbols, scope: &str) -> Result<isize, Error> {
+    expr.eval(Some(code.pc() as isize), &|name| {
+        symbols.lookup(scope, name)
+    })
+    .map_err(|e| match e {
+        Error::UndefLabel { labels } => Error::Assembler {
+            cause: format!(
+                "{:?} must be known when it is used, but {} hasn't been defined yet",
+                expr, labels
+            ),
+        },
+        e => e,
+    })
+}
+
+fn is_local(name: &str) -> bool {
+    name.starts_with('@') || name.starts_with('.')
+}
+
+fn is_anonymous(name: &str) -> bool {
+    name.starts_with(':') && !name.starts_with("::")
+}
+
+/// Name under which the anonymous label number `index` is stored
+fn anonymous_name(index: usize) -> String {
+    format!(":{}", index)
+}
+
+/// Local labels (`@name` or `.name`) belong to the last global label,
+/// so they are stored as `global.name`.
+/// References to anonymous labels (`:+`, `:--`, ...) are replaced by the one they point to,
+/// `anonymous` is how many of them have been defined so far
+fn qualify(name: String, global: &Option<String>, anonymous: usize) -> Result<String, Error> {
+    if is_anonymous(&name) {
+        let distance = name.len() - 1;
+        return if name.ends_with('+') {
+            Ok(anonymous_name(anonymous + distance - 1))
+        } else if distance <= anonymous {
+            Ok(anonymous_name(anonymous - distance))
+        } else {
+            Err(Error::Assembler {
+                cause: format!(
+                    "'{}' points {} anonymous labels back, but only {} were defined before it",
+                    name, distance, anonymous
+                ),
+            })
+        };
+    }
+    Ok(match global {
+        Some(global) if is_local(&name) => format!("{}.{}", global, &name[1..]),
+        _ => name,
+    })
+}
+
+fn qualify_value(value: Value, global: &Option<String>, anonymous: usize) -> Result<Value, Error> {
+    Ok(match value {
+        Value::Label(name) => Value::Label(qualify(name, global, anonymous)?),
+        Value::Expr(expr) => {
+            Value::Expr(expr.map_labels(&|name| qualify(name, global, anonymous))?)
+        }
+        Value::Pair(first, second) => Value::Pair(
+            first.map_labels(&|name| qualify(name, global, anonymous))?,
+            second.map_labels(&|name| qualify(name, global, anonymous))?,
+        ),
+        Value::Array(values) => Value::Array(
+            values
+                .into_iter()
+                .map(|value| qualify_value(value, global, anonymous))
+                .collect::<Result<_, _>>()?,
+        ),
+        Value::Assertion(assertion) => {
+            let qualify = |name| qualify(name, global, anonymous);
+            Value::Assertion(Assertion {
+                lhs: assertion.lhs.map_labels(&qualify)?,
+                check: match assertion.check {
+                    Some((comparison, rhs)) => Some((comparison, rhs.map_labels(&qualify)?)),
+                    None => None,
+                },
+                ..assertion
+            })
+        }
+        value => value,
+    })
+}
+
+/// Move the code pointer (`.org`)
+fn org(code: &mut Code, addr: isize, cpu: Cpu) -> Result<(), Error> {
+    if code.logical.is_some() {
+        return Err(Error::Assembler {
+            cause: "'.org' can't be used inside a '.logical' block".to_string(),
+        });
+    }
+    if addr < 0 || addr as usize > cpu.max_address() {
+        return Err(Error::Assembler {
+            cause: format!("Can't place code on {:#X}", addr),
+        });
+    }
+    code.pointer = addr as usize;
+    Ok(())
+}
+
+/// Start a `.logical` block: the code keeps being placed on `code.pointer`,
+/// but labels and `*` take addresses starting from `addr`
+fn start_logical(code: &mut Code, addr: isize, cpu: Cpu) -> Result<(), Error> {
+    if code.logical.is_some() {
+        return Err(Error::Assembler {
+            cause: "'.logical' blocks can't be nested".to_string(),
+        });
+    }
+    if addr < 0 || addr as usize > cpu.max_address() {
+        return Err(Error::Assembler {
+            cause: format!("Can't run code on {:#X}", addr),
+        });
+    }
+    code.logical = Some(addr - code.pointer as isize);
+    Ok(())
+}
+
+/// Absolute mode with the same index as the zero page mode `mode`
+fn absolute_mode(mode: AddressingMode) -> Option<AddressingMode> {
+    match mode {
+        AddressingMode::ZPG => Some(AddressingMode::ABS),
+        AddressingMode::ZPGX => Some(AddressingMode::ABSX),
+        AddressingMode::ZPGY => Some(AddressingMode::ABSY),
+        _ => None,
+    }
+}
+
+/// Instructions that don't do what they seem on `cpu`.
+/// `decimal` is set by `SED` and cleared by `CLD`
+fn target_warning(cpu: Cpu, name: OpcodeType, decimal: bool) -> Option<String> {
+    match (cpu, name) {
+        (Cpu::Ricoh2A03, OpcodeType::SED) => {
+            Some("SED does nothing on the 2A03, it doesn't have decimal mode".to_string())
+        }
+        (Cpu::Ricoh2A03, OpcodeType::ADC) | (Cpu::Ricoh2A03, OpcodeType::SBC) if decimal => {
+            Some(format!(
+                "{:?} after SED works in binary on the 2A03, it doesn't have decimal mode",
+                name
+            ))
+        }
+        _ => None,
+    }
+}
+
+/// A `.scope` or `.proc` that hasn't been closed yet
+struct Scope {
+    name: String,
+    /// Where the `.proc` started (`None` for a `.scope`)
+    proc_start: Option<usize>,
+    /// Cycles set with `.budget`
+    budget: Option<usize>,
+    /// Last global label before the scope was opened
+    outer_global: Option<String>,
+}
+
+/// Prefix of the names defined inside `scopes` (Ej.: `Player::Sprite::`)
+fn scope_prefix(scopes: &[Scope]) -> String {
+    scopes.iter().map(|s| format!("{}::", s.name)).collect()
+}
+
+/// Close the innermost scope with `.endscope` or `.endproc`
+fn close_scope(scopes: &mut Vec<Scope>, r#type: &str) -> Result<Scope, Error> {
+    let closed = scopes.pop().ok_or_else(|| Error::Assembler {
+        cause: format!("'.{}' without a '.scope' or '.proc'", r#type),
+    })?;
+    if (r#type == "endproc") != closed.proc_start.is_some() {
+        return Err(Error::Assembler {
+            cause: format!("'.{}' can't close '{}'", r#type, closed.name),
+        });
+    }
+    Ok(closed)
+}
+
+/// Qualify the labels used by an `.expect`
+fn qualify_expectation(
+    expectation: Expectation,
+    global: &Option<String>,
+    anonymous: usize,
+) -> Result<Expectation, Error> {
+    let qualify = |name| qualify(name, global, anonymous);
+    Ok(Expectation {
+        target: match expectation.target {
+            Target::Memory(addr) => Target::Memory(addr.map_labels(&qualify)?),
+            target => target,
+        },
+        value: expectation.value.map_labels(&qualify)?,
+        ..expectation
+    })
+}
+
+/// `.budget`: the most cycles a call to the innermost `.proc` may take
+fn set_budget(scopes: &mut [Scope], cycles: usize) -> Result<(), Error> {
+    match scopes.iter_mut().rev().find(|s| s.proc_start.is_some()) {
+        Some(proc) => {
+            proc.budget = Some(cycles);
+            Ok(())
+        }
+        None => Err(Error::Assembler {
+            cause: "'.budget' can only be used inside a '.proc'".to_string(),
+        }),
+    }
+}
+
+/// Ej.: `$1F`, or `-3` for negative values
+fn show_value(value: isize) -> String {
+    if value < 0 {
+        value.to_string()
+    } else {
+        format!("${:X}", value)
+    }
+}
+
+/// Parse and assemble `source`, as if it was read from a file
+pub fn assemble_source(source: &str, metadata: &Metadata) -> Result<Assembled, Error> {
+    assemble_lines(parse_source(source)?, metadata)
+}
+
+/// Assemble the parsed code, with the number of the source line each `LineType` came from
+pub fn assemble_lines(
+    parsed_code: Vec<(usize, LineType)>,
+    metadata: &Metadata,
+) -> Result<Assembled, Error> {
     let mut code = Code::new(); // code: holds the code
-    let mut labels: HashMap<String, usize> = HashMap::default(); // labels: holds the addrs of each label
-    let mut labels_used_on: HashMap<String, Vec<LabelUse>> = HashMap::default(); // labels_used_on: holds the addresses where a label was used
-    for line in parsed_code {
+    let mut symbols = Symbols::new(!metadata.case_sensitive_labels); // symbols: holds the value of each label and constant
+    let mut pending_consts: Vec<(String, Expr, usize, String)> = Vec::new(); // pending_consts: constants that use labels defined later
+    let mut labels_used_on: Vec<LabelUse> = Vec::new(); // labels_used_on: holds the places where a label was used
+    let mut global: Option<String> = None; // global: last global label, owner of the local ones
+    let mut anonymous: usize = 0; // anonymous: how many anonymous labels have been defined
+    let mut scopes: Vec<Scope> = Vec::new(); // scopes: `.scope`s and `.proc`s that are open
+    let mut scope = String::new(); // scope: prefix for the names defined on the innermost scope
+    let mut procs: Vec<Proc> = Vec::new();
+    let mut cpu = metadata.cpu; // cpu: instruction set, changed with `.cpu`
+    let mut wide_accumulator = false; // wide_accumulator: 16 bit accumulator (65816)
+    let mut wide_index = false; // wide_index: 16 bit index registers (65816)
+    let mut decimal = false; // decimal: `SED` was used (and not undone by `CLD`)
+    let mut warnings: Vec<String> = Vec::new();
+    let mut labels: Vec<Label> = Vec::new();
+    let mut instructions: Vec<SourceLine> = Vec::new();
+    let mut tests: Vec<Test> = Vec::new();
+    let mut in_test: Option<bool> = None; // in_test: inside a `.test` block, and if it's being assembled
+    let mut expect_scopes: Vec<String> = Vec::new(); // expect_scopes: scope of each `.expect`, to evaluate them at the end
+    let mut asserts: Vec<(usize, usize, String, Assertion)> = Vec::new(); // asserts: line, PC and scope of each `.assert`, checked at the end
+    let mut prints: Vec<(usize, usize, String, Vec<Value>)> = Vec::new(); // prints: `.print`s, shown once the addresses are known
+    for (number, line) in parsed_code {
+        // Skipped tests only need their `.endtest`
+        if in_test == Some(false) {
+            match &line {
+                LineType::Macro(name, _) if name == "test" || name == "endtest" => {}
+                _ => continue,
+            }
+        }
+        let line = match line {
+            LineType::LabelDef(name) => {
+                if !is_local(&name) {
+                    global = Some(name.clone());
+                }
+                LineType::LabelDef(qualify(name, &global, anonymous)?)
+            }
+            LineType::AnonLabelDef => {
+                anonymous += 1;
+                LineType::LabelDef(anonymous_name(anonymous - 1))
+            }
+            LineType::ConstDef(name, expr) => LineType::ConstDef(
+                qualify(name, &global, anonymous)?,
+                expr.map_labels(&|name| qualify(name, &global, anonymous))?,
+            ),
+            LineType::Opcode(mut opcode) => {
+                opcode.arg.1 = qualify_value(opcode.arg.1, &global, anonymous)?;
+                LineType::Opcode(opcode)
+            }
+            LineType::Macro(name, arg) => {
+                LineType::Macro(name, qualify_value(arg, &global, anonymous)?)
+            }
+        };
         match line {
+            LineType::AnonLabelDef => unreachable!("Anonymous labels were turned into LabelDef"),
             LineType::LabelDef(name) => {
-                labels.insert(name.clone(), code.pointer);
+                // Anonymous labels don't belong to any scope
+                let scope = if is_anonymous(&name) { "" } else { &scope };
+                symbols.define(scope, &name, code.pc() as isize);
+                if !is_anonymous(&name) {
+                    labels.push(Label {
+                        name: format!("{}{}", scope, name),
+                        address: code.pc(),
+                        offset: code.pointer,
+                        line: number,
+                    });
+                }
             }
-            LineType::Opcode(opcode) => {
-                let opcode_number = get_code(opcode.name, opcode.arg.0)?;
-                let size = addressing_modes::get_size(opcode.arg.0);
-                code.push_byte(opcode_number);
-                match &opcode.arg.1 {
-                    Value::Long(long) => code.push_long(*long),
-                    Value::Short(short) => code.push_byte(*short),
-                    Value::Label(name) => {
-                        let label_use = LabelUse {
-                            location: code.pointer,
-                            is_relative: opcode.name.is_branch_op(),
-                        };
-                        if let Some(addresses) = labels_used_on.get_mut(name) {
-                            addresses.push(label_use);
-                        } else {
-                            labels_used_on.insert(name.clone(), vec![label_use]);
+            LineType::ConstDef(name, expr) => {
+                match expr.eval(Some(code.pc() as isize), &|name| {
+                    symbols.lookup(&scope, name)
+                }) {
+                    Ok(value) => symbols.define(&scope, &name, value),
+                    Err(Error::UndefLabel { .. }) => {
+                        pending_consts.push((name, expr, code.pc(), scope.clone()))
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            LineType::Opcode(mut opcode) => {
+                // Zero page modes fall back to the absolute ones (Ej.: `PEA $10`)
+                if get_code(opcode.name, opcode.arg.0, cpu).is_err() {
+                    if let Some(mode) = absolute_mode(opcode.arg.0) {
+                        if get_code(opcode.name, mode, cpu).is_ok() {
+                            opcode.arg.0 = mode;
                         }
-                        // Since we don't know what to place here, just skip the argument (-1 for the opcode)
-                        code.skip(size - 1);
                     }
-                    Value::Array(arr) => {
-                        return Err(Error::Assembler {
-                            cause: format!(
-                                "Arrays haven't been implemented yet (Tried to use {:?})",
-                                arr
-                            ),
-                        })
+                }
+                let opcode_number = get_code(opcode.name, opcode.arg.0, cpu)?;
+                let mut size = addressing_modes::get_size(opcode.arg.0);
+                if opcode.arg.0 == AddressingMode::IMM && cpu == Cpu::Wdc65816 {
+                    // Immediates of 16 bit registers take 2 bytes
+                    if wide_accumulator && opcode.name.is_accumulator_sized()
+                        || wide_index && opcode.name.is_index_sized()
+                    {
+                        size += 1;
                     }
-                    Value::Text(txt) => {
-                        return Err(Error::Assembler {
-                            cause: format!(
+                    // `REP` and `SEP` change the size of the registers
+                    if let Value::Short(bits) = opcode.arg.1 {
+                        let wide = match opcode.name {
+                            OpcodeType::REP => Some(true),
+                            OpcodeType::SEP => Some(false),
+                            _ => None,
+                        };
+                        if let Some(wide) = wide {
+                            if bits & 0x20 != 0 {
+                                wide_accumulator = wide;
+                            }
+                            if bits & 0x10 != 0 {
+                                wide_index = wide;
+                            }
+                        }
+                    }
+                }
+                let pc = code.pc();
+                instructions.push(SourceLine {
+                    line: number,
+                    address: pc,
+                    size,
+                });
+                if let Some(warning) = target_warning(cpu, opcode.name, decimal) {
+                    warnings.push(format!("line {}: {}", number, warning));
+                }
+                match opcode.name {
+                    OpcodeType::SED => decimal = true,
+                    OpcodeType::CLD => decimal = false,
+                    _ => {}
+                }
+                code.push_byte(opcode_number);
+                let is_relative = opcode.name.is_branch_op() || opcode.name.is_long_relative_op();
+                let operands = match opcode.arg {
+                    // `BBR0 $10,label` places a zero page address and then a relative one
+                    (AddressingMode::ZPGREL, Value::Pair(addr, target)) => vec![
+                        (Value::Expr(addr), 1, false),
+                        (Value::Expr(target), 1, true),
+                    ],
+                    // `MVN source,destination` places the destination bank first
+                    (AddressingMode::BLK, Value::Pair(source, destination)) => vec![
+                        (Value::Expr(destination), 1, false),
+                        (Value::Expr(source), 1, false),
+                    ],
+                    (_, value) => vec![(value, size - 1, is_relative)],
+                };
+                for (value, size, is_relative) in operands {
+                    let expr = match &value {
+                        Value::Long(long) if is_relative => {
+                            code.push_long(*long);
+                            None
+                        }
+                        Value::Short(short) if is_relative => {
+                            code.push_byte(*short);
+                            None
+                        }
+                        // Checked and placed with the size of the operand
+                        Value::Long(long) => Some(Expr::Number(*long as isize)),
+                        Value::Short(short) => Some(Expr::Number(*short as isize)),
+                        Value::Far(far) => Some(Expr::Number(*far as isize)),
+                        Value::Label(name) => Some(Expr::Label(name.clone())),
+                        Value::Expr(expr) => Some(expr.clone()),
+                        Value::Array(arr) => {
+                            return Err(Error::Assembler {
+                                cause: format!(
+                                    "Arrays haven't been implemented yet (Tried to use {:?})",
+                                    arr
+                                ),
+                            })
+                        }
+                        Value::Text(txt) => {
+                            return Err(Error::Assembler {
+                                cause: format!(
                                 "Text literals haven't been implemented yet (Tried to use {:?})",
                                 txt
                             ),
-                        })
+                            })
+                        }
+                        Value::Expectations(..) | Value::Assertion(..) => {
+                            unreachable!("Only '.expect' and '.assert' take them")
+                        }
+                        Value::Pair(..) => {
+                            return Err(Error::Assembler {
+                                cause: format!(
+                                    "{:?} doesn't take two operands (Tried to use {:?})",
+                                    opcode.name, value
+                                ),
+                            })
+                        }
+                        Value::None => None,
+                    };
+                    if let Some(expr) = expr {
+                        let label_use = LabelUse::new(&code, pc, &scope, expr, size, is_relative);
+                        use_expr(&mut code, &symbols, &mut labels_used_on, label_use)?;
                     }
-                    Value::None => {}
                 }
-                println!("Assembling {:?} as {:#04X}", opcode, opcode_number);
             }
             LineType::Macro(r#type, arg) => {
-                println!("Interpreting macro {:?} {:X?}", r#type, arg);
+                let pc = code.pc();
                 impl_macros!(r#type,arg,
                     "org" => {
-                        Value::Long(addr) => {code.pointer = addr as usize;}
+                        Value::Long(addr) => org(&mut code, addr as isize, cpu)?,
+                        Value::Short(addr) => org(&mut code, addr as isize, cpu)?,
+                        Value::Far(addr) => org(&mut code, addr as isize, cpu)?,
+                        Value::Label(name) => {
+                            let addr = eval_now(&Expr::Label(name), &code, &symbols, &scope)?;
+                            org(&mut code, addr, cpu)?
+                        },
+                        Value::Expr(expr) => {
+                            let addr = eval_now(&expr, &code, &symbols, &scope)?;
+                            org(&mut code, addr, cpu)?
+                        }
+                    },
+                    "logical" | "base" => {
+                        Value::Long(addr) => start_logical(&mut code, addr as isize, cpu)?,
+                        Value::Short(addr) => start_logical(&mut code, addr as isize, cpu)?,
+                        Value::Far(addr) => start_logical(&mut code, addr as isize, cpu)?,
+                        Value::Label(name) => {
+                            let addr = eval_now(&Expr::Label(name), &code, &symbols, &scope)?;
+                            start_logical(&mut code, addr, cpu)?
+                        },
+                        Value::Expr(expr) => {
+                            let addr = eval_now(&expr, &code, &symbols, &scope)?;
+                            start_logical(&mut code, addr, cpu)?
+                        }
+                    },
+                    "endlogical" | "endbase" => {
+                        Value::None => {
+                            if code.logical.take().is_none() {
+                                return Err(Error::Assembler {
+                                    cause: format!("'{}' without a '.logical' block", r#type),
+                                });
+                            }
+                        }
+                    },
+                    "scope" => {
+                        Value::Label(name) => {
+                            scopes.push(Scope { name, proc_start: None, budget: None, outer_global: global.take() });
+                            scope = scope_prefix(&scopes);
+                        }
+                    },
+                    "proc" => {
+                        Value::Label(name) => {
+                            symbols.define(&scope, &name, pc as isize);
+                            labels.push(Label { name: format!("{}{}", scope, name), address: pc, offset: code.pointer, line: number });
+                            scopes.push(Scope { name, proc_start: Some(pc), budget: None, outer_global: global.take() });
+                            scope = scope_prefix(&scopes);
+                        }
+                    },
+                    "endscope" | "endproc" => {
+                        Value::None => {
+                            let closed = close_scope(&mut scopes, &r#type)?;
+                            scope = scope_prefix(&scopes);
+                            global = closed.outer_global;
+                            if let Some(start) = closed.proc_start {
+                                procs.push(Proc { name: format!("{}{}", scope, closed.name), start, end: pc, budget: closed.budget });
+                            }
+                        }
+                    },
+                    "test" => {
+                        Value::Text(name) => {
+                            if in_test.is_some() {
+                                return Err(Error::Assembler {
+                                    cause: "'.test' blocks can't be nested".to_string(),
+                                });
+                            }
+                            in_test = Some(metadata.test == Some(tests.len()));
+                            let name = String::from_utf8(name.into_vec())?;
+                            tests.push(Test { name, line: number, start: pc, end: pc, expects: Vec::new() });
+                        }
+                    },
+                    "endtest" => {
+                        Value::None => {
+                            match in_test.take() {
+                                Some(true) => tests.last_mut().unwrap().end = pc,
+                                Some(false) => {}
+                                None => return Err(Error::Assembler {
+                                    cause: "'.endtest' without a '.test' block".to_string(),
+                                }),
+                            }
+                        }
+                    },
+                    "expect" => {
+                        Value::Expectations(expectations) => {
+                            let test = match (in_test, tests.last_mut()) {
+                                (Some(true), Some(test)) => test,
+                                _ => return Err(Error::Assembler {
+                                    cause: "'.expect' can only be used inside a '.test' block".to_string(),
+                                }),
+                            };
+                            let expectations = expectations
+                                .into_iter()
+                                .map(|e| qualify_expectation(e, &global, anonymous))
+                                .collect::<Result<_, _>>()?;
+                            test.expects.push(Expect { line: number, address: pc, expectations });
+                            expect_scopes.push(scope.clone());
+                        }
+                    },
+                    "assert" => {
+                        Value::Assertion(assertion) => asserts.push((number, pc, scope.clone(), assertion))
+                    },
+                    "print" => {
+                        Value::Array(items) => prints.push((number, pc, scope.clone(), items))
+                    },
+                    "warning" => {
+                        Value::Text(message) => {
+                            let message = String::from_utf8(message.into_vec())?;
+                            warnings.push(format!("line {}: {}", number, message));
+                        }
+                    },
+                    "error" => {
+                        Value::Text(message) => {
+                            return Err(Error::Assembler {
+                                cause: format!("line {}: {}", number, String::from_utf8(message.into_vec())?),
+                            });
+                        },
+                        Value::None => {
+                            return Err(Error::Assembler {
+                                cause: format!("line {}: '.error' was reached", number),
+                            });
+                        }
+                    },
+                    "budget" => {
+                        Value::Short(cycles) => set_budget(&mut scopes, cycles as usize)?,
+                        Value::Long(cycles) => set_budget(&mut scopes, cycles as usize)?,
+                        Value::Expr(expr) => {
+                            let cycles = eval_now(&expr, &code, &symbols, &scope)?;
+                            set_budget(&mut scopes, cycles as usize)?
+                        }
+                    },
+                    "cpu" => {
+                        Value::Text(name) => {
+                            cpu = String::from_utf8(name.into_vec())?
+                                .parse()
+                                .map_err(|cause| Error::Assembler { cause })?;
+                            // The registers of the 65816 start with 8 bits
+                            wide_accumulator = false;
+                            wide_index = false;
+                        }
+                    },
+                    "a8" => {
+                        Value::None => wide_accumulator = false
+                    },
+                    "a16" => {
+                        Value::None => wide_accumulator = true
+                    },
+                    "i8" => {
+                        Value::None => wide_index = false
+                    },
+                    "i16" => {
+                        Value::None => wide_index = true
                     },
                     "byte" => {
-                        Value::Short(arg) => code.push_byte(arg)
+                        Value::Short(arg) => code.push_byte(arg),
+                        Value::Label(name) => {
+                            let label_use = LabelUse::new(&code, pc, &scope, Expr::Label(name), 1, false);
+                            use_expr(&mut code, &symbols, &mut labels_used_on, label_use)?;
+                        },
+                        Value::Expr(expr) => {
+                            let label_use = LabelUse::new(&code, pc, &scope, expr, 1, false);
+                            use_expr(&mut code, &symbols, &mut labels_used_on, label_use)?;
+                        }
                     },
                     "dw" => {
-                        Value::Long(value) => {code.push_long(value)},
+                        Value::Long(value) => code.push_long(value),
+                        Value::Short(value) => code.push_long(value as u16),
                         Value::Label(name) => {
-                            let label_use = LabelUse {
-                                location: code.pointer,
-                                is_relative: false,
-                            };
-                            if let Some(addresses) = labels_used_on.get_mut(&name) {
-                                addresses.push(label_use);
-                            } else {
-                                labels_used_on.insert(name.clone(), vec![label_use]);
-                            }
-                            code.pointer += 2;
+                            let label_use = LabelUse::new(&code, pc, &scope, Expr::Label(name), 2, false);
+                            use_expr(&mut code, &symbols, &mut labels_used_on, label_use)?;
+                        },
+                        Value::Expr(expr) => {
+                            let label_use = LabelUse::new(&code, pc, &scope, expr, 2, false);
+                            use_expr(&mut code, &symbols, &mut labels_used_on, label_use)?;
                         }
                     },
                     "incbin" => {
@@ -108,12 +704,10 @@ pub fn assemble(parsed_code: Vec<LineType>, metadata: &Metadata) -> Result<[u8;
                         let mut path =
                             std::path::PathBuf::from(metadata.search_path.as_os_str());
                         path.push(arg);
-                        println!("Including bytes from {}", path.display());
                         let mut file = File::open(path)?;
                         let file_size = file.metadata()?.len();
                         let mut buffer = Vec::with_capacity(file_size as usize);
                         file.read_to_end(&mut buffer)?;
-                        println!("Inserting {} bytes", file_size);
                         for byte in buffer {
                             code.push_byte(byte);
                         }}
@@ -124,39 +718,166 @@ pub fn assemble(parsed_code: Vec<LineType>, metadata: &Metadata) -> Result<[u8;
                                 if let Value::Short(byte) = value {code.push_byte(byte);}
                             }
                         },
-                        Value::Short(byte) => {code.push_byte(byte)}
+                        Value::Short(byte) => code.push_byte(byte)
                     }
                 );
             }
         };
     }
-    // Iterate through all the defined labels
-    // removing them from the list of used_labels
-    // and placing their address in the whitespaces left
+    // Constants that depend on labels defined after them,
+    // keep trying until no more of them can be resolved
+    loop {
+        let before = pending_consts.len();
+        let mut unresolved = Vec::new();
+        for (name, expr, pc, scope) in pending_consts.drain(..) {
+            match expr.eval(Some(pc as isize), &|name| symbols.lookup(&scope, name)) {
+                Ok(value) => symbols.define(&scope, &name, value),
+                Err(Error::UndefLabel { .. }) => unresolved.push((name, expr, pc, scope)),
+                Err(e) => return Err(e),
+            }
+        }
+        pending_consts = unresolved;
+        if pending_consts.is_empty() || pending_consts.len() == before {
+            break;
+        }
+    }
+    // Iterate through all the places where a label was used
+    // and place the value of the expression in the whitespaces left
     // on the assembling stage
-    for (label, l_address) in labels.keys().zip(labels.values()) {
-        // Skip if the label wasn't used
-        let addresses_where_used: Vec<LabelUse> = match labels_used_on.remove(label) {
-            None => continue,
-            Some(v) => v,
-        };
-        for address in addresses_where_used {
-            code.pointer = address.location;
-            if address.is_relative {
-                // Calc de diff between the 2 addresses
-                // The +1 is to skip the opcode's argument
-                let relative = (*l_address as isize) - (address.location as isize + 1);
-                let relative = (relative & 0xFF) as u8;
-                code.push_byte(relative);
-            } else {
-                code.push_long(labels[label] as u16);
+    let mut undefined: Vec<String> = Vec::new();
+    for label_use in labels_used_on {
+        match label_use.expr.eval(Some(label_use.pc as isize), &|name| {
+            symbols.lookup(&label_use.scope, name)
+        }) {
+            Ok(value) => place_value(&mut code, &label_use, value)?,
+            Err(Error::UndefLabel { labels }) if is_anonymous(&labels) => {
+                return Err(Error::Assembler {
+                    cause: format!(
+                        "A ':+' reference on {:#06X} points to the anonymous label #{}, but only {} were defined",
+                        label_use.pc,
+                        &labels[1..].parse::<usize>().unwrap_or_default() + 1,
+                        anonymous
+                    ),
+                })
+            }
+            Err(Error::UndefLabel { labels }) => {
+                if !undefined.contains(&labels) {
+                    undefined.push(labels);
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    for (name, _, _, scope) in pending_consts {
+        undefined.push(format!("{}{}", scope, name));
+    }
+    // Only the test that was assembled has `.expect`s
+    let expects = tests.iter_mut().flat_map(|test| test.expects.iter_mut());
+    for (expect, scope) in expects.zip(expect_scopes) {
+        let pc = Some(expect.address as isize);
+        let eval = |expr: &Expr| expr.eval(pc, &|name| symbols.lookup(&scope, name));
+        for expectation in expect.expectations.iter_mut() {
+            let mut exprs = vec![&mut expectation.value];
+            if let Target::Memory(addr) = &mut expectation.target {
+                exprs.push(addr);
+            }
+            for expr in exprs {
+                match eval(expr) {
+                    Ok(value) => *expr = Expr::Number(value),
+                    Err(Error::UndefLabel { labels }) => {
+                        if !undefined.contains(&labels) {
+                            undefined.push(labels);
+                        }
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+    }
+    // `.print`s and `.assert`s see the final addresses
+    let mut printed: Vec<String> = Vec::new();
+    for (line, pc, scope, items) in prints {
+        let mut text = String::new();
+        for item in items {
+            match item {
+                Value::Text(part) => text.push_str(&String::from_utf8(part.into_vec())?),
+                Value::Expr(expr) => {
+                    match expr.eval(Some(pc as isize), &|name| symbols.lookup(&scope, name)) {
+                        Ok(value) => text.push_str(&show_value(value)),
+                        Err(Error::UndefLabel { labels }) => {
+                            text.push('?');
+                            if !undefined.contains(&labels) {
+                                undefined.push(labels);
+                            }
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+                _ => unreachable!("'.print' only takes texts and expressions"),
             }
         }
+        printed.push(format!("line {}: {}", line, text));
     }
-    if !labels_used_on.is_empty() {
+    let mut failed: Vec<String> = Vec::new();
+    for (line, pc, scope, assertion) in asserts {
+        let eval = |expr: &Expr| expr.eval(Some(pc as isize), &|name| symbols.lookup(&scope, name));
+        let holds = eval(&assertion.lhs).and_then(|lhs| {
+            let holds = match &assertion.check {
+                Some((comparison, rhs)) => comparison.holds(lhs, eval(rhs)?),
+                None => lhs != 0,
+            };
+            Ok((holds, lhs))
+        });
+        match holds {
+            Ok((true, _)) => {}
+            Ok((false, lhs)) => failed.push(format!(
+                "line {}: {} ({}, found {})",
+                line,
+                assertion.message.as_deref().unwrap_or("Assertion failed"),
+                assertion.text,
+                show_value(lhs)
+            )),
+            Err(Error::UndefLabel { labels }) => {
+                if !undefined.contains(&labels) {
+                    undefined.push(labels);
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    if let Some(open) = scopes.last() {
+        return Err(Error::Assembler {
+            cause: format!("'{}' is missing its '.endscope' or '.endproc'", open.name),
+        });
+    }
+    if code.logical.is_some() {
+        return Err(Error::Assembler {
+            cause: "A '.logical' block is missing its '.endlogical'".to_string(),
+        });
+    }
+    if in_test.is_some() {
+        return Err(Error::Assembler {
+            cause: format!("'{}' is missing its '.endtest'", tests.last().unwrap().name),
+        });
+    }
+    if !undefined.is_empty() {
         return Err(Error::UndefLabel {
-            labels: format!("{:?}", labels_used_on),
+            labels: format!("{:?}", undefined),
+        });
+    }
+    if !failed.is_empty() {
+        return Err(Error::Assembler {
+            cause: failed.join("\n"),
         });
     }
-    Ok(*code)
+    Ok(Assembled {
+        code: code.into_vec(),
+        procs,
+        labels,
+        instructions,
+        tests,
+        warnings,
+        prints: printed,
+        cpu,
+    })
 }
@@ -4,18 +4,35 @@ fn big_to_little_endian(value: u16) -> (u8, u8) {
 }
 
 pub struct Code {
-    cart: [u8; 0x10000],
+    /// Grows past the first 64KiB if code is placed there (65816)
+    cart: Vec<u8>,
     pub pointer: usize,
+    /// Inside a `.logical` block: difference between the address the code runs at and `pointer`
+    pub logical: Option<isize>,
 }
-impl Code {
-    pub fn new() -> Self {
+impl Default for Code {
+    fn default() -> Self {
         Self {
-            cart: [0x00u8; 0x10000],
+            cart: vec![0x00u8; 0x10000],
             pointer: 0,
+            logical: None,
         }
     }
+}
+impl Code {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Address the CPU will see for self.pointer (the value of `*` and of the labels)
+    pub fn pc(&self) -> usize {
+        (self.pointer as isize + self.logical.unwrap_or(0)) as usize
+    }
     /// Place a u8 on self.pointer, then increment by 1
     pub fn push_byte(&mut self, byte: u8) {
+        if self.pointer >= self.cart.len() {
+            // Grow a whole bank at a time
+            self.cart.resize((self.pointer | 0xFFFF) + 1, 0x00);
+        }
         self.cart[self.pointer] = byte;
         self.pointer += 1;
     }
@@ -25,14 +42,23 @@ impl Code {
         self.push_byte(long.0);
         self.push_byte(long.1);
     }
+    /// Place the 3 lower bytes of a u32 in little endian on self.pointer, then increment by 3
+    pub fn push_far(&mut self, far: u32) {
+        self.push_long((far & 0xFFFF) as u16);
+        self.push_byte(((far >> 16) & 0xFF) as u8);
+    }
+    /// Move the code out, to return it
+    pub fn into_vec(self) -> Vec<u8> {
+        self.cart
+    }
     /// Add <amount> to self.pointer
     pub fn skip(&mut self, amount: usize) {
         self.pointer += amount;
     }
 }
 impl std::ops::Deref for Code {
-    type Target = [u8; 0x10000];
-    fn deref(&self) -> &[u8; 0x10000] {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
         &self.cart
     }
 }
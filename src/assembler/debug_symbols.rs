@@ -0,0 +1,149 @@
+use super::Assembled;
+use std::collections::btree_map::{BTreeMap, Entry};
+
+/// Where the PRG-ROM starts for the CPU, and on an output without an iNES header
+const PRG_START: usize = 0x8000;
+/// Size of the iNES header, and of the trainer that may follow it
+const HEADER_SIZE: usize = 16;
+const TRAINER_SIZE: usize = 512;
+/// FCEUX numbers the PRG-ROM in banks of 16KiB
+const FCEUX_BANK_SIZE: usize = 0x4000;
+
+/// The comment of each line of `source` and if it has code too,
+/// Ej.: `(Some("Wait for the vblank"), false)` for `; Wait for the vblank`
+fn comments(source: &str) -> Vec<(Option<String>, bool)> {
+    source
+        .lines()
+        .map(|line| {
+            let mut in_string = false;
+            let mut escape = false;
+            for (i, c) in line.char_indices() {
+                match c {
+                    '\\' => escape = !escape,
+                    '"' if !escape => in_string = !in_string,
+                    ';' if !in_string => {
+                        let comment = line[i + 1..].trim();
+                        let comment = Some(comment.to_string()).filter(|c| !c.is_empty());
+                        return (comment, !line[..i].trim().is_empty());
+                    }
+                    _ => escape = false,
+                }
+            }
+            (None, !line.trim().is_empty())
+        })
+        .collect()
+}
+
+impl Assembled {
+    /// Where the PRG-ROM starts on the output: after the header (and the trainer) if it begins
+    /// with an iNES header, or on `$8000` for an image of the memory like the CPU sees it
+    fn prg_start(&self) -> usize {
+        if self.starts_with(b"NES\x1A") && self.len() > HEADER_SIZE {
+            let trainer = self[6] & 0x04 != 0;
+            HEADER_SIZE + if trainer { TRAINER_SIZE } else { 0 }
+        } else {
+            PRG_START
+        }
+    }
+
+    /// The first label of each address in the first 64KiB, with the comment of its line and of
+    /// the comment lines right above it (only if `source` is given). Other labels on the same
+    /// address are named on the comment.
+    /// They are kept by their offset on the PRG-ROM (`None` for the RAM and the registers), so
+    /// banks that run on the same addresses don't mix
+    fn debug_symbols(
+        &self,
+        source: Option<&str>,
+    ) -> BTreeMap<(Option<usize>, usize), (String, Vec<String>)> {
+        let lines = source.map(comments).unwrap_or_default();
+        let notes = |line: usize| {
+            let mut notes: Vec<String> = lines[..line.saturating_sub(1).min(lines.len())]
+                .iter()
+                .rev()
+                .take_while(|(comment, code)| comment.is_some() && !code)
+                .filter_map(|(comment, _)| comment.clone())
+                .collect();
+            notes.reverse();
+            if let Some((Some(comment), true)) = line.checked_sub(1).and_then(|i| lines.get(i)) {
+                notes.push(comment.clone());
+            }
+            notes
+        };
+        let prg_start = self.prg_start();
+        let mut symbols: BTreeMap<(Option<usize>, usize), (String, Vec<String>)> = BTreeMap::new();
+        for label in self.labels.iter().filter(|label| label.address <= 0xFFFF) {
+            let prg_offset = if label.address >= PRG_START {
+                match label.offset.checked_sub(prg_start) {
+                    Some(offset) => Some(offset),
+                    // On the header
+                    None => continue,
+                }
+            } else {
+                None
+            };
+            match symbols.entry((prg_offset, label.address)) {
+                Entry::Vacant(entry) => {
+                    entry.insert((label.name.clone(), notes(label.line)));
+                }
+                Entry::Occupied(mut entry) => {
+                    let notes_of_other = notes(label.line);
+                    let notes = &mut entry.get_mut().1;
+                    notes.push(format!("Also {}", label.name));
+                    notes.extend(notes_of_other);
+                }
+            }
+        }
+        symbols
+    }
+
+    /// FCEUX name lists, with lines like `$8000#reset#Comment`: `ram` for the addresses before
+    /// `$8000`, and the number of each 16KiB PRG bank (in hex, like FCEUX looks for them) for
+    /// the ones after it. The bank is the one the label is on in the PRG-ROM, so a 16KiB ROM
+    /// on `$C000` only has bank `0`.
+    /// They go next to the ROM as `game.nes.ram.nl`, `game.nes.0.nl`, ..., `game.nes.A.nl`
+    pub fn fceux_name_lists(&self, source: Option<&str>) -> Vec<(String, String)> {
+        let mut files: BTreeMap<Option<usize>, String> = BTreeMap::new();
+        for ((prg_offset, address), (name, notes)) in self.debug_symbols(source) {
+            let bank = prg_offset.map(|offset| offset / FCEUX_BANK_SIZE);
+            let line = format!("${:04X}#{}#{}\n", address, name, notes.join("; "));
+            files.entry(bank).or_default().push_str(&line);
+        }
+        files
+            .into_iter()
+            .map(|(bank, list)| {
+                let bank = bank.map_or("ram".to_string(), |b| format!("{:X}", b));
+                (bank, list)
+            })
+            .collect()
+    }
+
+    /// Mesen label file (`game.mlb`), with lines like `P:0000:reset:Comment`: `R` for the RAM,
+    /// `G` for the registers (`$2000`-`$401F`), `S` for the save RAM (`$6000`-`$7FFF`) and `P`
+    /// for the PRG-ROM, with the offset on it. Mesen only takes letters, digits, `_` and
+    /// `@` on the names, so `Player::update` is written as `Player__update`
+    pub fn mesen_labels(&self, source: Option<&str>) -> String {
+        let mut file = String::new();
+        for ((prg_offset, address), (name, notes)) in self.debug_symbols(source) {
+            let (kind, offset) = match (prg_offset, address) {
+                (Some(offset), _) => ("P", offset),
+                (None, 0x0000..=0x1FFF) => ("R", address & 0x7FF),
+                (None, 0x2000..=0x401F) => ("G", address),
+                (None, 0x6000..=0x7FFF) => ("S", address - 0x6000),
+                _ => continue,
+            };
+            let name: String = name
+                .chars()
+                .map(|c| match c {
+                    'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '@' => c,
+                    _ => '_',
+                })
+                .collect();
+            file.push_str(&format!("{}:{:04X}:{}", kind, offset, name));
+            if !notes.is_empty() {
+                file.push_str(&format!(":{}", notes.join("\\n")));
+            }
+            file.push('\n');
+        }
+        file
+    }
+}
@@ -0,0 +1,289 @@
+use super::Assembled;
+use std::path::Path;
+
+/// `e_machine` of the 6502 family, the same llvm-mos uses
+const EM_MOS: u16 = 6502;
+const ELF_HEADER_SIZE: usize = 52;
+const SECTION_HEADER_SIZE: usize = 40;
+const SYMBOL_SIZE: usize = 16;
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_NOBITS: u32 = 8;
+/// Writable, allocated and executable: RAM, registers and ROM are on the same bus
+const SHF_WAX: u32 = 0x7;
+const STB_GLOBAL: u8 = 1;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+/// Index of the section that covers the memory
+const MEMORY_SECTION: u16 = 1;
+/// `DW_LANG_Mips_Assembler`, what GDB takes as assembly
+const DW_LANG_ASSEMBLER: u16 = 0x8001;
+const DW_LNS_COPY: u8 = 0x01;
+const DW_LNS_ADVANCE_PC: u8 = 0x02;
+const DW_LNS_ADVANCE_LINE: u8 = 0x03;
+const DW_LNE_END_SEQUENCE: u8 = 0x01;
+const DW_LNE_SET_ADDRESS: u8 = 0x02;
+
+fn uleb128(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn sleb128(out: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+/// Ends with a 0, like every string of ELF and DWARF
+fn push_str(out: &mut Vec<u8>, text: &str) {
+    out.extend(text.as_bytes());
+    out.push(0);
+}
+
+struct Section {
+    name: &'static str,
+    kind: u32,
+    flags: u32,
+    /// Size of a `SHT_NOBITS` section, that has no data on the file
+    size: usize,
+    data: Vec<u8>,
+    link: u32,
+    info: u32,
+    entry_size: u32,
+}
+impl Section {
+    fn new(name: &'static str, kind: u32, data: Vec<u8>) -> Self {
+        Section {
+            name,
+            kind,
+            flags: 0,
+            size: data.len(),
+            data,
+            link: 0,
+            info: 0,
+            entry_size: 0,
+        }
+    }
+}
+
+impl Assembled {
+    /// Addresses covered by the line table and the symbols: 64KiB, or more for the 65816
+    fn memory_size(&self) -> usize {
+        let instructions = self.instructions.iter().map(|i| i.address + i.size);
+        let labels = self.labels.iter().map(|label| label.address + 1);
+        instructions.chain(labels).fold(0x10000, usize::max)
+    }
+
+    /// `.debug_line` (DWARF 2) with the line of each instruction. Instructions placed one
+    /// after the other go on the same sequence, so each `.logical` bank has its own
+    fn debug_line(&self, file: &str) -> Vec<u8> {
+        let mut program = Vec::new();
+        let end_sequence = |program: &mut Vec<u8>, length: usize| {
+            program.push(DW_LNS_ADVANCE_PC);
+            uleb128(program, length as u64);
+            program.extend(&[0, 1, DW_LNE_END_SEQUENCE]);
+        };
+        // Address of the last row and where its instruction ends
+        let mut sequence: Option<(usize, usize)> = None;
+        let mut line: i64 = 1;
+        for instruction in &self.instructions {
+            match sequence {
+                Some((address, end)) if end == instruction.address => {
+                    program.push(DW_LNS_ADVANCE_PC);
+                    uleb128(&mut program, (instruction.address - address) as u64);
+                }
+                _ => {
+                    if let Some((address, end)) = sequence {
+                        end_sequence(&mut program, end - address);
+                        line = 1;
+                    }
+                    program.extend(&[0, 5, DW_LNE_SET_ADDRESS]);
+                    program.extend(&(instruction.address as u32).to_le_bytes());
+                }
+            }
+            program.push(DW_LNS_ADVANCE_LINE);
+            sleb128(&mut program, instruction.line as i64 - line);
+            line = instruction.line as i64;
+            program.push(DW_LNS_COPY);
+            sequence = Some((instruction.address, instruction.address + instruction.size));
+        }
+        if let Some((address, end)) = sequence {
+            end_sequence(&mut program, end - address);
+        }
+        // Minimum instruction length, default is_stmt, line base, line range, opcode base and
+        // the operands of the standard opcodes
+        let mut header = vec![1, 1, -5i8 as u8, 14, 13, 0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1];
+        // No include directories, and the source with its full path
+        header.push(0);
+        push_str(&mut header, file);
+        header.extend(&[0, 0, 0, 0]);
+        let mut unit = Vec::new();
+        unit.extend(&((2 + 4 + header.len() + program.len()) as u32).to_le_bytes());
+        unit.extend(&2u16.to_le_bytes());
+        unit.extend(&(header.len() as u32).to_le_bytes());
+        unit.extend(header);
+        unit.extend(program);
+        unit
+    }
+
+    /// `.debug_abbrev` and `.debug_info`: a single compile unit for the source, that points to
+    /// the line table
+    fn debug_info(&self, file: &str, directory: &str) -> (Vec<u8>, Vec<u8>) {
+        let abbrev = vec![
+            1, 0x11, 0, // DW_TAG_compile_unit, without children
+            0x03, 0x08, // DW_AT_name, DW_FORM_string
+            0x1B, 0x08, // DW_AT_comp_dir, DW_FORM_string
+            0x25, 0x08, // DW_AT_producer, DW_FORM_string
+            0x13, 0x05, // DW_AT_language, DW_FORM_data2
+            0x10, 0x06, // DW_AT_stmt_list, DW_FORM_data4
+            0x11, 0x01, // DW_AT_low_pc, DW_FORM_addr
+            0x12, 0x01, // DW_AT_high_pc, DW_FORM_addr
+            0, 0, 0,
+        ];
+        let mut entry = vec![1];
+        push_str(&mut entry, file);
+        push_str(&mut entry, directory);
+        push_str(&mut entry, env!("CARGO_PKG_NAME"));
+        entry.extend(&DW_LANG_ASSEMBLER.to_le_bytes());
+        entry.extend(&0u32.to_le_bytes());
+        entry.extend(&0u32.to_le_bytes());
+        entry.extend(&(self.memory_size() as u32).to_le_bytes());
+        let mut info = Vec::new();
+        info.extend(&((2 + 4 + 1 + entry.len()) as u32).to_le_bytes());
+        info.extend(&2u16.to_le_bytes());
+        // Offset of the abbreviations and size of the addresses
+        info.extend(&0u32.to_le_bytes());
+        info.push(4);
+        info.extend(entry);
+        (abbrev, info)
+    }
+
+    /// `.symtab` and its `.strtab`: the labels, as functions where there is an instruction (with
+    /// the size of the `.proc` if they are one) and as objects elsewhere
+    fn symbols(&self) -> (Vec<u8>, Vec<u8>) {
+        let mut symbols = vec![0; SYMBOL_SIZE];
+        let mut names = vec![0];
+        for label in &self.labels {
+            let code = self.instructions.iter().any(|i| i.address == label.address);
+            let size = self
+                .procs
+                .iter()
+                .find(|proc| proc.name == label.name && proc.start == label.address)
+                .map_or(0, |proc| proc.size());
+            let kind = if code { STT_FUNC } else { STT_OBJECT };
+            symbols.extend(&(names.len() as u32).to_le_bytes());
+            symbols.extend(&(label.address as u32).to_le_bytes());
+            symbols.extend(&(size as u32).to_le_bytes());
+            symbols.extend(&[STB_GLOBAL << 4 | kind, 0]);
+            symbols.extend(&MEMORY_SECTION.to_le_bytes());
+            push_str(&mut names, &label.name);
+        }
+        (symbols, names)
+    }
+
+    /// An ELF file with the labels as symbols and the line of each instruction as DWARF, for
+    /// debuggers like GDB (`file game.elf`, or `symbol-file`). `file` is the path of the source.
+    /// It has no code: the memory is a section without data, so it's read from the target
+    pub fn elf(&self, file: &str) -> Vec<u8> {
+        let directory = Path::new(file)
+            .parent()
+            .map_or(String::new(), |dir| dir.display().to_string());
+        let (abbrev, info) = self.debug_info(file, &directory);
+        let (symbols, names) = self.symbols();
+        let memory = Section {
+            flags: SHF_WAX,
+            size: self.memory_size(),
+            ..Section::new(".text", SHT_NOBITS, vec![])
+        };
+        let symbol_table = Section {
+            // Its names are on the next section, and they are all global
+            link: 6,
+            info: 1,
+            entry_size: SYMBOL_SIZE as u32,
+            ..Section::new(".symtab", SHT_SYMTAB, symbols)
+        };
+        let mut sections = vec![
+            memory,
+            Section::new(".debug_abbrev", SHT_PROGBITS, abbrev),
+            Section::new(".debug_info", SHT_PROGBITS, info),
+            Section::new(".debug_line", SHT_PROGBITS, self.debug_line(file)),
+            symbol_table,
+            Section::new(".strtab", SHT_STRTAB, names),
+        ];
+        let mut section_names = vec![0];
+        let mut name_offsets = Vec::new();
+        for section in sections.iter().map(|s| s.name).chain(Some(".shstrtab")) {
+            name_offsets.push(section_names.len() as u32);
+            push_str(&mut section_names, section);
+        }
+        sections.push(Section::new(".shstrtab", SHT_STRTAB, section_names));
+
+        let mut elf = vec![0; ELF_HEADER_SIZE];
+        let mut offsets = Vec::new();
+        for section in &sections {
+            while elf.len() % 4 != 0 {
+                elf.push(0);
+            }
+            offsets.push(elf.len());
+            elf.extend(&section.data);
+        }
+        while elf.len() % 4 != 0 {
+            elf.push(0);
+        }
+        let section_headers = elf.len();
+        // The null section, then the others
+        elf.extend(&[0; SECTION_HEADER_SIZE]);
+        for ((section, offset), name) in sections.iter().zip(offsets).zip(name_offsets) {
+            let address_align = if section.kind == SHT_SYMTAB { 4 } else { 1 };
+            for field in &[
+                name,
+                section.kind,
+                section.flags,
+                0,
+                offset as u32,
+                section.size as u32,
+                section.link,
+                section.info,
+                address_align,
+                section.entry_size,
+            ] {
+                elf.extend(&field.to_le_bytes());
+            }
+        }
+
+        // 32 bits, little endian, version 1
+        let mut header = vec![0x7F, b'E', b'L', b'F', 1, 1, 1];
+        header.resize(16, 0);
+        header.extend(&2u16.to_le_bytes()); // Executable
+        header.extend(&EM_MOS.to_le_bytes());
+        header.extend(&1u32.to_le_bytes());
+        // No entry point and no program headers
+        header.extend(&0u32.to_le_bytes());
+        header.extend(&0u32.to_le_bytes());
+        header.extend(&(section_headers as u32).to_le_bytes());
+        header.extend(&0u32.to_le_bytes());
+        header.extend(&(ELF_HEADER_SIZE as u16).to_le_bytes());
+        header.extend(&0u16.to_le_bytes());
+        header.extend(&0u16.to_le_bytes());
+        header.extend(&(SECTION_HEADER_SIZE as u16).to_le_bytes());
+        header.extend(&(sections.len() as u16 + 1).to_le_bytes());
+        header.extend(&(sections.len() as u16).to_le_bytes());
+        elf[..ELF_HEADER_SIZE].copy_from_slice(&header);
+        elf
+    }
+}
@@ -1,51 +1,372 @@
-use crate::addressing_modes;
-use crate::error::Error;
-use crate::opcodes::get_code;
-use crate::parser::{LineType, LineType::*, Value};
+use crate::parser::{Expr, LineType, Value};
 
 mod assemble;
 mod code;
+mod debug_symbols;
+mod elf;
+mod symbols;
 mod types;
-pub use assemble::assemble;
+pub use assemble::{assemble_lines, assemble_source};
 pub use code::Code;
+use symbols::Symbols;
 use types::LabelUse;
-pub use types::Metadata;
+pub use types::{Assembled, Expect, Label, Metadata, Proc, SourceLine, Test};
 
 mod tests {
     #[test]
     fn test_assemble() {
-        use crate::assembler::assemble;
-        use crate::parser::{parse_line, LineType};
+        use crate::assembler::assemble_source;
         let metadata = super::Metadata::default();
         let test_code: &str = include_str!("../../assembly/general/basic_opcodes.asm");
-        let test_code: Vec<LineType> = test_code
-            .lines()
-            .map(|l: &str| parse_line(l.as_bytes()).unwrap().1)
-            .collect();
-        let code: [u8; 0x10000] =
-            assemble(test_code, &metadata).expect("This shouldn't have errored");
-        super::dump(&code, Some(0x80), Some(0x80));
+        let code: super::Assembled =
+            assemble_source(test_code, &metadata).expect("This shouldn't have errored");
+        super::dump(&code[..], Some(0x80), Some(0x80));
         assert_eq!(code[0x0000..0x0005], [0xA9, 0xFF, 0x85, 0xFF, 0x18]);
     }
     #[test]
     fn test_labels() {
-        use crate::assembler::assemble;
-        use crate::parser::{parse_line, LineType};
+        use crate::assembler::assemble_source;
         let metadata = super::Metadata::default();
         let test_code: &str = "\tLDA main";
-        let test_code: Vec<LineType> = test_code
-            .lines()
-            .map(|l: &str| parse_line(l.as_bytes()).unwrap().1)
-            .collect();
-        assert!(assemble(test_code, &metadata).is_err());
+        assert!(assemble_source(test_code, &metadata).is_err());
         let test_code: &str = "main:\n\tLDA main";
-        let test_code: Vec<LineType> = test_code
-            .lines()
-            .map(|l: &str| parse_line(l.as_bytes()).unwrap().1)
-            .collect();
-        let code = assemble(test_code, &metadata).unwrap();
+        let code = assemble_source(test_code, &metadata).unwrap();
         assert_eq!(code[0x0000..0x0003], [0xAD, 0x00, 0x00]);
     }
+    #[test]
+    fn test_expressions() {
+        use crate::assembler::assemble_source;
+        let metadata = super::Metadata::default();
+        let test_code: &str = ".org $8000\nstart:\n\tDEX\n\tBNE *-1\n\tLDA #size\n\tJMP end+1\nsize = end - start\n.org * + $10\nend:\n.dw *";
+        let code = assemble_source(test_code, &metadata).unwrap();
+        assert_eq!(
+            code[0x8000..0x800A],
+            [0xCA, 0xD0, 0xFD, 0xA9, 0x18, 0x4C, 0x19, 0x80, 0x00, 0x00]
+        );
+        assert_eq!(code[0x8018..0x801A], [0x18, 0x80]);
+    }
+    #[test]
+    fn test_logical() {
+        use crate::assembler::assemble_source;
+        let metadata = super::Metadata::default();
+        let test_code: &str = ".org $8000\n\tJMP copy\n.logical $0300\nram:\n\tDEX\n\tBNE ram\n\tJMP ram\n.dw *\n.endlogical\ncopy:\n\tJMP ram";
+        let code = assemble_source(test_code, &metadata).unwrap();
+        assert_eq!(
+            code[0x8000..0x8010],
+            [
+                0x4C, 0x0B, 0x80, 0xCA, 0xD0, 0xFD, 0x4C, 0x00, 0x03, 0x06, 0x03, 0x4C, 0x00, 0x03,
+                0x00, 0x00
+            ]
+        );
+        assert_eq!(code[0x0300..0x0303], [0x00, 0x00, 0x00]);
+        let test_code = ".logical $0300\n.org $8000";
+        assert!(assemble_source(test_code, &metadata).is_err());
+    }
+    #[test]
+    fn test_local_labels() {
+        use crate::assembler::assemble_source;
+        let metadata = super::Metadata::default();
+        let test_code: &str = ".org $8000\nfirst:\n@loop:\n\tDEX\n\tBNE @loop\nsecond:\n.loop:\n\tDEY\n\tBNE .loop\n\tJMP first.loop";
+        let code = assemble_source(test_code, &metadata).unwrap();
+        assert_eq!(
+            code[0x8000..0x8009],
+            [0xCA, 0xD0, 0xFD, 0x88, 0xD0, 0xFD, 0x4C, 0x00, 0x80]
+        );
+        let test_code = "first:\n@loop:\nsecond:\n\tJMP @loop";
+        assert!(assemble_source(test_code, &metadata).is_err());
+    }
+    #[test]
+    fn test_anonymous_labels() {
+        use crate::assembler::assemble_source;
+        let metadata = super::Metadata::default();
+        let test_code: &str = ".org $8000\n:\n\tDEX\n\tBEQ :+\n\tBNE :-\n:\n\tJMP :--";
+        let code = assemble_source(test_code, &metadata).unwrap();
+        assert_eq!(
+            code[0x8000..0x8008],
+            [0xCA, 0xF0, 0x02, 0xD0, 0xFB, 0x4C, 0x00, 0x80]
+        );
+        for (test_code, message) in &[
+            (
+                "\tBNE :-\n:",
+                "Assembler error: ':-' points 1 anonymous labels back, but only 0 were defined before it",
+            ),
+            (
+                ":\n\tBNE :++\n:",
+                "Assembler error: A ':+' reference on 0x0000 points to the anonymous label #3, but only 2 were defined",
+            ),
+        ] {
+            let error = assemble_source(test_code, &metadata).err().unwrap();
+            assert_eq!(error.to_string(), *message);
+        }
+    }
+    #[test]
+    fn test_scopes() {
+        use crate::assembler::{assemble_source, Proc};
+        let metadata = super::Metadata::default();
+        let test_code: &str = ".org $8000\nupdate:\n\tRTS\n.scope Player\nspeed = 2\n.proc update\n\tLDA #speed\n\tJSR ::update\nloop:\n\tBNE loop\n\tRTS\n.endproc\n.endscope\n\tJSR Player::update\n\tJMP Player::update::loop";
+        let code = assemble_source(test_code, &metadata).unwrap();
+        assert_eq!(
+            code[0x8000..0x8011],
+            [
+                0x60, 0xA9, 0x02, 0x20, 0x00, 0x80, 0xD0, 0xFE, 0x60, 0x20, 0x01, 0x80, 0x4C, 0x06,
+                0x80, 0x00, 0x00
+            ]
+        );
+        assert_eq!(
+            code.procs,
+            vec![Proc {
+                budget: None,
+                name: "Player::update".to_string(),
+                start: 0x8001,
+                end: 0x8009
+            }]
+        );
+        for test_code in &[
+            ".scope A\n.endproc",
+            ".proc A\n",
+            "\tJMP A::x\n.scope A\nx:\n.endscope\n\tJMP x",
+        ] {
+            assert!(assemble_source(test_code, &metadata).is_err());
+        }
+    }
+    #[test]
+    fn test_65c02() {
+        use crate::assembler::assemble_source;
+        use crate::opcodes::Cpu;
+        let metadata = super::Metadata {
+            cpu: Cpu::Wdc65C02,
+            ..Default::default()
+        };
+        let test_code: &str = ".org $8000\nloop:\n\tSTZ $10\n\tLDA ($10)\n\tINC A\n\tBBR0 $10, loop\n\tBRA loop\n\tJMP (table,X)\ntable:";
+        let code = assemble_source(test_code, &metadata).unwrap();
+        assert_eq!(
+            code[0x8000..0x8010],
+            [
+                0x64, 0x10, 0xB2, 0x10, 0x1A, 0x0F, 0x10, 0xF8, 0x80, 0xF6, 0x7C, 0x0D, 0x80, 0x00,
+                0x00, 0x00
+            ]
+        );
+        assert!(assemble_source(test_code, &super::Metadata::default()).is_err());
+    }
+    #[test]
+    fn test_undocumented() {
+        use crate::assembler::assemble_source;
+        let metadata = super::Metadata::default();
+        let test_code = "\tLAX $10\n\tDCP $1234,X\n\tNOP $10\n\tAXS #$04";
+        assert!(assemble_source(test_code, &metadata).is_err());
+        let code = assemble_source(&format!(".cpu \"6502x\"\n{}", test_code), &metadata).unwrap();
+        assert_eq!(
+            code[0x0000..0x0009],
+            [0xA7, 0x10, 0xDF, 0x34, 0x12, 0x04, 0x10, 0xCB, 0x04]
+        );
+        // The 2A03 of the NES has them too
+        let code = assemble_source(&format!(".cpu \"2a03\"\n{}", test_code), &metadata).unwrap();
+        assert_eq!(code[0x0000..0x0002], [0xA7, 0x10]);
+        assert!(assemble_source(".cpu \"z80\"", &metadata).is_err());
+    }
+    #[test]
+    fn test_65816() {
+        use crate::assembler::assemble_source;
+        let metadata = super::Metadata::default();
+        let test_code = ".cpu \"65816\"\n.org $8000\nstart:\n\tREP #$30\n\tLDA #$1234\n\tLDX #$0010\n\tSEP #$20\n\tLDA #$12\n.i8\n\tLDY #$05\n\tLDA $7E2000,X\n\tJSL far\n\tMVN $7E, $01\n\tBRL start\n\tPEA $10\n.org $018000\nfar:\n\tLDA far\n\tLDA [$10],Y\n\tRTL";
+        let code = assemble_source(test_code, &metadata).unwrap();
+        assert_eq!(
+            code[0x8000..0x801F],
+            [
+                0xC2, 0x30, 0xA9, 0x34, 0x12, 0xA2, 0x10, 0x00, 0xE2, 0x20, 0xA9, 0x12, 0xA0, 0x05,
+                0xBF, 0x00, 0x20, 0x7E, 0x22, 0x00, 0x80, 0x01, 0x54, 0x01, 0x7E, 0x82, 0xE4, 0xFF,
+                0xF4, 0x10, 0x00
+            ]
+        );
+        assert_eq!(
+            code[0x018000..0x018006],
+            [0xAD, 0x00, 0x80, 0xB7, 0x10, 0x6B]
+        );
+        for test_code in &[
+            // Out of the first bank without the 65816
+            ".org $018000",
+            // Label on another bank
+            ".cpu \"65816\"\n\tJMP far\n.org $018000\nfar:",
+            // 8 bit accumulator
+            ".cpu \"65816\"\n\tLDA #$1234",
+        ] {
+            assert!(assemble_source(test_code, &metadata).is_err());
+        }
+    }
+    #[test]
+    fn test_cpu_regions() {
+        use crate::assembler::assemble_source;
+        use crate::opcodes::Cpu;
+        let metadata = super::Metadata {
+            cpu: Cpu::Ricoh2A03,
+            ..Default::default()
+        };
+        let code = assemble_source(
+            ".org $8000\n\tSED\n\tADC #$01\n\tCLD\n\tADC #$01\n.cpu \"65c02\"\n\tSTZ $10\n.cpu \"6502\"\n\tSED\n\tADC #$01",
+            &metadata,
+        )
+        .unwrap();
+        assert_eq!(
+            code[0x8000..0x800B],
+            [0xF8, 0x69, 0x01, 0xD8, 0x69, 0x01, 0x64, 0x10, 0xF8, 0x69, 0x01]
+        );
+        assert_eq!(code.warnings.len(), 2);
+        assert!(code.warnings[0].starts_with("line 2: SED"));
+        assert!(code.warnings[1].starts_with("line 3: ADC"));
+        // 65C02 instructions after going back to the 6502
+        let res = assemble_source(
+            ".cpu \"65c02\"\n\tSTZ $10\n.cpu \"6502\"\n\tSTZ $10",
+            &metadata,
+        );
+        assert!(res.is_err());
+    }
+    #[test]
+    fn test_diagnostics() {
+        use crate::assembler::{assemble_source, Metadata};
+        let metadata = Metadata::default();
+        // Checked with the final addresses, even if they use labels defined after them
+        let source = ".org $80FE\n.assert table_end - table <= 256, \"Too big\"\n.assert >table == >table_end\ntable:\n\t.byte $01\ntable_end:\n.print \"table: \", table, \" \", size\nsize = table_end - table\n.warning \"Check this\"";
+        let code = assemble_source(source, &metadata).unwrap();
+        assert_eq!(code.warnings, ["line 9: Check this"]);
+        assert_eq!(code.prints, ["line 7: table: $80FE $1"]);
+        let error = assemble_source(&source.replace("$80FE", "$80FF"), &metadata)
+            .err()
+            .unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Assembler error: line 3: Assertion failed (>table == >table_end, found $80)"
+        );
+        let error = assemble_source(
+            ".assert * < $8000, \"Out of space\"\n.assert 0\n.assert 1",
+            &metadata,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Assembler error: line 2: Assertion failed (0, found $0)"
+        );
+        let error = assemble_source("\tNOP\n.error \"Not supported\"", &metadata)
+            .err()
+            .unwrap();
+        assert_eq!(error.to_string(), "Assembler error: line 2: Not supported");
+        assert!(assemble_source(".assert missing == 1", &metadata).is_err());
+    }
+    #[test]
+    fn test_debug_symbols() {
+        use crate::assembler::{assemble_source, Metadata};
+        let metadata = Metadata::default();
+        let source = ".org $0010\nptr:\n.org $8000\n; Entry point\nreset:\nmain:\n\tLDA #$00 ; \"not ; this\"\n.proc Player\nupdate:\tRTS ; Moves it\n.endproc\n.org $C000\nnmi:\tRTI";
+        let code = assemble_source(source, &metadata).unwrap();
+        let lists = code.fceux_name_lists(Some(source));
+        assert_eq!(
+            lists,
+            [
+                ("ram".to_string(), "$0010#ptr#\n".to_string()),
+                ("0".to_string(), "$8000#reset#Entry point; Also main\n$8002#Player#Also Player::update; Moves it\n".to_string()),
+                ("1".to_string(), "$C000#nmi#\n".to_string()),
+            ]
+        );
+        assert_eq!(
+            code.mesen_labels(None),
+            "R:0010:ptr\nP:0000:reset:Also main\nP:0002:Player:Also Player::update\nP:4000:nmi\n"
+        );
+        // With an iNES header, the bank is the one the label is on in the PRG-ROM
+        let header =
+            ".db $4E, $45, $53, $1A, $01, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00\n";
+        let code = assemble_source(
+            &format!(
+                "{}.logical $C000\nreset:\tNOP\nnmi:\tRTI\n.endlogical",
+                header
+            ),
+            &metadata,
+        )
+        .unwrap();
+        assert_eq!(
+            code.fceux_name_lists(None),
+            [("0".to_string(), "$C000#reset#\n$C001#nmi#\n".to_string())]
+        );
+        assert_eq!(code.mesen_labels(None), "P:0000:reset\nP:0001:nmi\n");
+        // Banks that run on the same addresses keep their labels apart
+        let fill = format!(".db {}\n", vec!["$EA"; 0x100].join(", ")).repeat(0x40);
+        let banks: String = (0..12)
+            .map(|bank| format!(".logical $8000\nbank{}:\n{}.endlogical\n", bank, fill))
+            .collect();
+        let code = assemble_source(&format!("{}{}", header, banks), &metadata).unwrap();
+        let lists = code.fceux_name_lists(None);
+        assert_eq!(lists.len(), 12);
+        assert_eq!(lists[10], ("A".to_string(), "$8000#bank10#\n".to_string()));
+        assert!(code.mesen_labels(None).ends_with("P:2C000:bank11\n"));
+    }
+    #[test]
+    fn test_elf() {
+        use crate::assembler::{assemble_source, Metadata};
+        let source = ".org $0010\nptr:\n.org $8000\nreset:\n\tLDX #3\n.proc loop\n\tDEX\n\tBNE loop\n\tRTS\n.endproc\ntable:\n.db $01, $02\nnmi:\tRTI";
+        let code = assemble_source(source, &Metadata::default()).unwrap();
+        let elf = code.elf("/src/prog.asm");
+        let u16_at = |at: usize| u16::from_le_bytes([elf[at], elf[at + 1]]) as usize;
+        let u32_at = |at: usize| {
+            u32::from_le_bytes([elf[at], elf[at + 1], elf[at + 2], elf[at + 3]]) as usize
+        };
+        assert_eq!(elf[..7], [0x7F, b'E', b'L', b'F', 1, 1, 1]);
+        assert_eq!(u16_at(18), 6502);
+        // Name, data and size of each section
+        let (headers, count, names) = (u32_at(32), u16_at(48), u16_at(50));
+        let names = u32_at(headers + names * 40 + 16);
+        let text = |at: usize| {
+            let end = elf[at..].iter().position(|&b| b == 0).unwrap();
+            std::str::from_utf8(&elf[at..at + end]).unwrap()
+        };
+        let sections: Vec<(&str, &[u8], usize)> = (0..count)
+            .map(|i| {
+                let header = headers + i * 40;
+                let (offset, size) = (u32_at(header + 16), u32_at(header + 20));
+                let data = if u32_at(header + 4) == 8 {
+                    &[][..]
+                } else {
+                    &elf[offset..offset + size]
+                };
+                (text(names + u32_at(header)), data, size)
+            })
+            .collect();
+        let section = |name: &str| sections.iter().find(|s| s.0 == name).unwrap();
+        // The memory takes no space on the file
+        assert_eq!((section(".text").1.len(), section(".text").2), (0, 0x10000));
+        // Name, address, size and type of the symbols
+        let strtab = headers + 40 * sections.iter().position(|s| s.0 == ".strtab").unwrap();
+        let strtab = u32_at(strtab + 16);
+        let symbols: Vec<(&str, usize, usize, u8)> = section(".symtab")
+            .1
+            .chunks(16)
+            .skip(1)
+            .map(|s| {
+                let field = |at: usize| {
+                    u32::from_le_bytes([s[at], s[at + 1], s[at + 2], s[at + 3]]) as usize
+                };
+                (text(strtab + field(0)), field(4), field(8), s[12] & 0x0F)
+            })
+            .collect();
+        assert_eq!(
+            symbols,
+            [
+                ("ptr", 0x10, 0, 1),
+                ("reset", 0x8000, 0, 2),
+                ("loop", 0x8002, 4, 2),
+                ("table", 0x8006, 0, 1),
+                ("nmi", 0x8008, 0, 2)
+            ]
+        );
+        // The compile unit points to the source. The line table has a sequence that starts on
+        // `$8000` with line 5 and another one for `nmi`, after the data
+        let info = section(".debug_info").1;
+        assert_eq!(info[12..26], b"/src/prog.asm\0"[..]);
+        let line = section(".debug_line").1;
+        let set_address = |address: u16| [0, 5, 2, address as u8, (address >> 8) as u8, 0, 0];
+        let start = [&set_address(0x8000)[..], &[3, 4, 1]].concat();
+        assert!(line.windows(start.len()).any(|w| w == &start[..]));
+        let end = [&set_address(0x8008)[..], &[3, 12, 1, 2, 1, 0, 1, 1]].concat();
+        assert!(line.ends_with(&end));
+    }
 }
 
 #[allow(dead_code)]
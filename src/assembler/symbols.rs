@@ -0,0 +1,78 @@
+use std::collections::HashMap;
+
+/// Values of every label and constant, stored by their full name (Ej.: `Player::update`).
+/// Scopes are written as a prefix that ends with `::` (Ej.: `Player::`), `""` is the global scope
+#[derive(Debug, Default)]
+pub struct Symbols {
+    values: HashMap<String, isize>,
+    /// Treat `Main`, `main` and `MAIN` as the same label
+    ignore_case: bool,
+}
+impl Symbols {
+    pub fn new(ignore_case: bool) -> Self {
+        Self {
+            ignore_case,
+            ..Self::default()
+        }
+    }
+    fn key(&self, scope: &str, name: &str) -> String {
+        let key = format!("{}{}", scope, name);
+        if self.ignore_case {
+            key.to_ascii_lowercase()
+        } else {
+            key
+        }
+    }
+    pub fn define(&mut self, scope: &str, name: &str, value: isize) {
+        let key = self.key(scope, name);
+        self.values.insert(key, value);
+    }
+    /// Find the value of `name` used inside of `scope`,
+    /// looking for it on every scope that encloses it.
+    /// Names that start with `::` are only looked for on the global scope
+    pub fn lookup(&self, scope: &str, name: &str) -> Option<isize> {
+        if let Some(name) = name.strip_prefix("::") {
+            return self.values.get(&self.key("", name)).copied();
+        }
+        let mut scope = scope;
+        loop {
+            if let Some(value) = self.values.get(&self.key(scope, name)) {
+                return Some(*value);
+            }
+            if scope.is_empty() {
+                return None;
+            }
+            scope = parent_scope(scope);
+        }
+    }
+}
+
+/// `A::B::` -> `A::`
+pub fn parent_scope(scope: &str) -> &str {
+    match scope[..scope.len() - 2].rfind("::") {
+        Some(end) => &scope[..end + 2],
+        None => "",
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_lookup() {
+        use super::Symbols;
+        let mut symbols = Symbols::default();
+        symbols.define("", "reset", 0x8000);
+        symbols.define("", "update", 0x8010);
+        symbols.define("Player::", "update", 0x9000);
+        symbols.define("Player::Sprite::", "draw", 0x9100);
+        assert_eq!(symbols.lookup("Player::Sprite::", "update"), Some(0x9000));
+        assert_eq!(symbols.lookup("Player::Sprite::", "::update"), Some(0x8010));
+        assert_eq!(symbols.lookup("Player::Sprite::", "reset"), Some(0x8000));
+        assert_eq!(symbols.lookup("", "Player::Sprite::draw"), Some(0x9100));
+        assert_eq!(symbols.lookup("Player::", "Sprite::draw"), Some(0x9100));
+        assert_eq!(symbols.lookup("", "draw"), None);
+        assert_eq!(symbols.lookup("", "RESET"), None);
+        let mut symbols = Symbols::new(true);
+        symbols.define("Player::", "Update", 0x9000);
+        assert_eq!(symbols.lookup("", "PLAYER::update"), Some(0x9000));
+    }
+}
@@ -1,19 +1,148 @@
+use super::Code;
+use crate::opcodes::Cpu;
+use crate::parser::{Expectation, Expr};
+
 pub struct Metadata {
     /// Where to find include files
     pub search_path: std::path::PathBuf,
+    /// Are `main` and `MAIN` different labels?
+    pub case_sensitive_labels: bool,
+    /// Instruction set to use
+    pub cpu: Cpu,
+    /// Number of the `.test` block to assemble, the others are skipped
+    pub test: Option<usize>,
 }
 impl std::default::Default for Metadata {
     fn default() -> Self {
         Metadata {
             search_path: std::path::PathBuf::from(""),
+            case_sensitive_labels: true,
+            cpu: Cpu::default(),
+            test: None,
         }
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 pub struct LabelUse {
     /// Where was this label used?
     pub location: usize,
+    /// Address `location` has for the CPU (differs from it inside `.logical` blocks)
+    pub logical: usize,
+    /// Value of `*` on the line that used the label
+    pub pc: usize,
+    /// What has to be placed on `location`
+    pub expr: Expr,
+    /// How many bytes does the value take (1, 2 or 3)
+    pub size: usize,
     /// Was this label used for a relative (branch) instruction
     pub is_relative: bool,
+    /// Scope the label was used on, where the search for it starts
+    pub scope: String,
+}
+impl LabelUse {
+    /// A use of `expr` on the current position of `code`.
+    /// `pc` is the value `*` takes on the line
+    pub fn new(
+        code: &Code,
+        pc: usize,
+        scope: &str,
+        expr: Expr,
+        size: usize,
+        is_relative: bool,
+    ) -> Self {
+        Self {
+            location: code.pointer,
+            logical: code.pc(),
+            pc,
+            expr,
+            size,
+            is_relative,
+            scope: scope.to_string(),
+        }
+    }
+}
+
+/// A `.proc`, Ej.: `Player::update` from `$8000` to `$8020`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Proc {
+    /// Name including its scopes
+    pub name: String,
+    pub start: usize,
+    /// Address after its last byte
+    pub end: usize,
+    /// Most cycles a call to it may take, set with `.budget`
+    pub budget: Option<usize>,
+}
+impl Proc {
+    pub fn size(&self) -> usize {
+        self.end - self.start
+    }
+}
+
+/// A label of the code, Ej.: `Player::update` on `$8010`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Label {
+    /// Name including its scopes, and its global label if it's local (Ej.: `main.loop`)
+    pub name: String,
+    pub address: usize,
+    /// Where it is on the output, differs from `address` inside `.logical` blocks
+    pub offset: usize,
+    /// Line where it was defined
+    pub line: usize,
+}
+
+/// Where an instruction of the source was placed, Ej.: line 12 on `$8004`, 2 bytes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SourceLine {
+    pub line: usize,
+    /// Address it has for the CPU
+    pub address: usize,
+    pub size: usize,
+}
+
+/// A `.test "name"` block, Ej.: `adds one` from `$8010` to `$8016`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Test {
+    pub name: String,
+    /// Line of the `.test`
+    pub line: usize,
+    pub start: usize,
+    /// Address after its last byte (same as `start` if it wasn't assembled)
+    pub end: usize,
+    pub expects: Vec<Expect>,
+}
+
+/// An `.expect`, checked when the PC gets to `address`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expect {
+    pub line: usize,
+    pub address: usize,
+    /// With the expressions already evaluated
+    pub expectations: Vec<Expectation>,
+}
+
+/// What `assemble` produces
+pub struct Assembled {
+    /// 64KiB, or more if the 65816 placed code after the first bank
+    pub code: Vec<u8>,
+    pub procs: Vec<Proc>,
+    /// Labels and `.proc`s, in the order they were defined. Anonymous labels aren't on it
+    pub labels: Vec<Label>,
+    /// Each instruction and the line it came from, in the order they were assembled
+    pub instructions: Vec<SourceLine>,
+    /// All the `.test` blocks, only the one chosen with `Metadata::test` has code
+    pub tests: Vec<Test>,
+    /// Things that assembled, but probably don't do what was intended
+    pub warnings: Vec<String>,
+    /// Texts of the `.print` directives, with their line. Ej.: `line 12: size: $1F`
+    pub prints: Vec<String>,
+    /// Instruction set on the last line (`--cpu` or the last `.cpu`)
+    pub cpu: Cpu,
+}
+impl std::ops::Deref for Assembled {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        &self.code
+    }
 }
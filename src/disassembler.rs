@@ -0,0 +1,706 @@
+use crate::addressing_modes::AddressingMode;
+use crate::error::Error;
+use crate::opcodes::{get_code, get_opcode, Cpu, OpcodeData, OpcodeType};
+use crate::parser::{parse_line, LineType};
+use std::collections::BTreeMap;
+
+/// NMI, reset and IRQ vectors, with the name given to the code they point to
+const VECTORS: [(usize, &str); 3] = [(0xFFFA, "nmi"), (0xFFFC, "reset"), (0xFFFE, "irq")];
+/// Bytes on each `.db` line
+const BYTES_PER_LINE: usize = 8;
+
+/// Bytes to disassemble, and the address the CPU sees them at
+pub struct Image {
+    pub bytes: Vec<u8>,
+    /// Address of the first byte
+    pub load: usize,
+    /// Where the bytes came from, Ej.: `PRG-ROM of game.nes`
+    pub origin: String,
+}
+impl Image {
+    /// A raw binary. Without a load address it ends on `$FFFF`, like a ROM with its vectors
+    pub fn raw(bytes: Vec<u8>, load: Option<usize>, origin: &str) -> Result<Image, Error> {
+        if bytes.is_empty() {
+            return Err(Error::Disassembler {
+                cause: format!("{} is empty", origin),
+            });
+        }
+        let load = match load {
+            Some(load) => load,
+            None => 0x10000usize
+                .checked_sub(bytes.len())
+                .ok_or_else(|| Error::Disassembler {
+                    cause: format!(
+                        "{} takes {:#X} bytes, more than 64KiB. Give it a load address",
+                        origin,
+                        bytes.len()
+                    ),
+                })?,
+        };
+        Ok(Image {
+            bytes,
+            load,
+            origin: origin.to_string(),
+        })
+    }
+    fn contains(&self, addr: usize) -> bool {
+        addr >= self.load && addr < self.load + self.bytes.len()
+    }
+    fn byte(&self, addr: usize) -> Option<u8> {
+        if self.contains(addr) {
+            Some(self.bytes[addr - self.load])
+        } else {
+            None
+        }
+    }
+    /// Little endian value of `size` bytes from `addr`
+    fn value(&self, addr: usize, size: usize) -> Option<usize> {
+        (0..size).try_fold(0, |value, i| {
+            self.byte(addr + i)
+                .map(|byte| value | (byte as usize) << (8 * i))
+        })
+    }
+}
+
+/// The parts of an iNES or NES 2.0 file
+pub struct Nes {
+    /// The header, and the trainer if there is one
+    pub header: Vec<u8>,
+    /// The PRG-ROM as the CPU sees it: 16KiB on `$C000`, 32KiB on `$8000`, or with more
+    /// banks each one on `$8000` and the last one (with the vectors) on `$C000`, where most
+    /// mappers keep them
+    pub banks: Vec<Image>,
+    /// The CHR-ROM, and anything after it
+    pub rest: Vec<u8>,
+    /// Ej.: `game.nes (mapper 2, 128 KiB of PRG-ROM)`
+    pub origin: String,
+}
+impl Nes {
+    pub fn new(file: &[u8], origin: &str) -> Result<Nes, Error> {
+        if !Nes::is_nes(file) {
+            return Err(Error::Disassembler {
+                cause: format!("{} doesn't start with an iNES header", origin),
+            });
+        }
+        let header = &file[..16];
+        let nes2 = header[7] & 0x0C == 0x08;
+        let banks = if nes2 {
+            header[4] as usize | (header[9] as usize & 0x0F) << 8
+        } else {
+            header[4] as usize
+        };
+        let mapper = header[6] >> 4 | header[7] & 0xF0;
+        // A 512 byte trainer can go before the PRG-ROM
+        let start = if header[6] & 0x04 != 0 { 16 + 512 } else { 16 };
+        let end = start + banks * 0x4000;
+        if banks == 0 || file.len() < end {
+            return Err(Error::Disassembler {
+                cause: format!(
+                    "{} should have {} PRG-ROM banks, but it's too short",
+                    origin, banks
+                ),
+            });
+        }
+        let image = |first: usize, size: usize, load: usize, bank: &str| Image {
+            bytes: file[first..first + size].to_vec(),
+            load,
+            origin: format!("PRG-ROM {}of {}", bank, origin),
+        };
+        let banks = match banks {
+            1 => vec![image(start, 0x4000, 0xC000, "")],
+            2 => vec![image(start, 0x8000, 0x8000, "")],
+            _ => (0..banks)
+                .map(|bank| {
+                    let load = if bank == banks - 1 { 0xC000 } else { 0x8000 };
+                    image(
+                        start + bank * 0x4000,
+                        0x4000,
+                        load,
+                        &format!("bank {} ", bank),
+                    )
+                })
+                .collect(),
+        };
+        Ok(Nes {
+            header: file[..start].to_vec(),
+            origin: format!(
+                "{} (mapper {}, {} KiB of PRG-ROM)",
+                origin,
+                mapper,
+                (end - start) / 1024
+            ),
+            banks,
+            rest: file[end..].to_vec(),
+        })
+    }
+    pub fn is_nes(file: &[u8]) -> bool {
+        file.len() >= 16 && file.starts_with(b"NES\x1A")
+    }
+}
+
+pub struct Options {
+    pub cpu: Cpu,
+    /// Follow the code from the vectors and `entries`, everything else is data.
+    /// Without it every byte that can be decoded is code
+    pub trace: bool,
+    /// Where code starts, besides the vectors
+    pub entries: Vec<usize>,
+}
+
+/// A decoded instruction
+#[derive(Clone)]
+struct Instruction {
+    opcode: &'static OpcodeData,
+    /// The bytes after the opcode, as a little endian number
+    operand: usize,
+    size: usize,
+    /// Width of the registers on the 65816 when it was decoded
+    wide_accumulator: bool,
+    wide_index: bool,
+}
+impl Instruction {
+    fn decode(image: &Image, addr: usize, cpu: Cpu, wide: (bool, bool)) -> Option<Instruction> {
+        let opcode = get_opcode(image.byte(addr)?, cpu)?;
+        let name = opcode.name();
+        let mut size = opcode.size() as usize;
+        // The immediates of the 65816 follow the width of the registers
+        if cpu == Cpu::Wdc65816
+            && opcode.addr_mode() == AddressingMode::IMM
+            && (name.is_accumulator_sized() && wide.0 || name.is_index_sized() && wide.1)
+        {
+            size += 1;
+        }
+        Some(Instruction {
+            opcode,
+            operand: image.value(addr + 1, size - 1)?,
+            size,
+            wide_accumulator: wide.0,
+            wide_index: wide.1,
+        })
+    }
+    /// Width of the registers after it runs (`REP` and `SEP` change them)
+    fn widths_after(&self) -> (bool, bool) {
+        let bits = self.operand;
+        match self.opcode.name() {
+            OpcodeType::REP => (
+                self.wide_accumulator || bits & 0x20 != 0,
+                self.wide_index || bits & 0x10 != 0,
+            ),
+            OpcodeType::SEP => (
+                self.wide_accumulator && bits & 0x20 == 0,
+                self.wide_index && bits & 0x10 == 0,
+            ),
+            _ => (self.wide_accumulator, self.wide_index),
+        }
+    }
+    /// Where it can jump or branch to, if it's known
+    fn target(&self, addr: usize) -> Option<usize> {
+        let name = self.opcode.name();
+        let signed = |value: usize, bits: u32| {
+            let shift = 64 - bits;
+            ((value as i64) << shift >> shift) as isize
+        };
+        let relative = |offset: isize| {
+            let target = (addr + self.size) as isize + offset;
+            // Branches stay on their bank
+            if target < (addr & !0xFFFF) as isize || target > (addr | 0xFFFF) as isize {
+                None
+            } else {
+                Some(target as usize)
+            }
+        };
+        match self.opcode.addr_mode() {
+            AddressingMode::REL => relative(signed(self.operand, 8)),
+            AddressingMode::RELL if name == OpcodeType::BRL => relative(signed(self.operand, 16)),
+            AddressingMode::ZPGREL if name.is_bit_branch_op() => {
+                relative(signed(self.operand >> 8, 8))
+            }
+            AddressingMode::ABS if name.is_jump_op() || name.is_call_op() => {
+                Some(addr & !0xFFFF | self.operand)
+            }
+            AddressingMode::ABSL if name.is_jump_op() || name.is_call_op() => Some(self.operand),
+            _ => None,
+        }
+    }
+    /// Does the instruction after this one run after it
+    fn falls_through(&self) -> bool {
+        let name = self.opcode.name();
+        !(name.is_jump_op()
+            || name.is_return_op()
+            || name.is_interrupt_op()
+            || name == OpcodeType::BRA
+            || name == OpcodeType::BRL
+            || name == OpcodeType::STP)
+    }
+}
+
+/// What each address holds
+struct Map {
+    /// Instructions by address
+    code: BTreeMap<usize, Instruction>,
+    /// Is the byte part of an instruction
+    is_code: Vec<bool>,
+}
+impl Map {
+    /// Decode every byte that can be decoded, one after another
+    fn linear(image: &Image, cpu: Cpu) -> Map {
+        let mut map = Map::new(image);
+        let mut addr = image.load;
+        let mut wide = (false, false);
+        while image.contains(addr) {
+            match Instruction::decode(image, addr, cpu, wide) {
+                Some(instruction) => {
+                    wide = instruction.widths_after();
+                    addr += instruction.size;
+                    map.insert(image, addr - instruction.size, instruction);
+                }
+                None => addr += 1,
+            }
+        }
+        map
+    }
+    /// Follow the code from `entries`
+    fn traced(image: &Image, cpu: Cpu, entries: &[usize]) -> Map {
+        let mut map = Map::new(image);
+        let mut pending: Vec<(usize, (bool, bool))> = entries
+            .iter()
+            .rev()
+            .map(|&addr| (addr, (false, false)))
+            .collect();
+        while let Some((mut addr, mut wide)) = pending.pop() {
+            while !map.code.contains_key(&addr) {
+                let instruction = match Instruction::decode(image, addr, cpu, wide) {
+                    Some(instruction) => instruction,
+                    None => break,
+                };
+                let bytes = addr - image.load..addr - image.load + instruction.size;
+                if map.is_code[bytes].iter().any(|&b| b) {
+                    // It overlaps another instruction
+                    break;
+                }
+                if let Some(target) = instruction.target(addr) {
+                    if image.contains(target) {
+                        pending.push((target, instruction.widths_after()));
+                    }
+                }
+                let falls_through = instruction.falls_through();
+                wide = instruction.widths_after();
+                let size = instruction.size;
+                map.insert(image, addr, instruction);
+                if !falls_through {
+                    break;
+                }
+                addr += size;
+            }
+        }
+        map
+    }
+    fn new(image: &Image) -> Map {
+        Map {
+            code: BTreeMap::new(),
+            is_code: vec![false; image.bytes.len()],
+        }
+    }
+    fn insert(&mut self, image: &Image, addr: usize, instruction: Instruction) {
+        for i in 0..instruction.size {
+            self.is_code[addr - image.load + i] = true;
+        }
+        self.code.insert(addr, instruction);
+    }
+    /// Can a label be placed on `addr`
+    fn is_line_start(&self, image: &Image, addr: usize) -> bool {
+        image.contains(addr) && (self.code.contains_key(&addr) || !self.is_code[addr - image.load])
+    }
+}
+
+/// `$12`, `$1234` or `$123456`, depending on how many bytes it takes
+fn hex(value: usize, size: usize) -> String {
+    format!("${:0width$X}", value, width = size * 2)
+}
+
+/// Address for label names
+fn address_name(addr: usize) -> String {
+    if addr > 0xFFFF {
+        format!("{:06X}", addr)
+    } else {
+        format!("{:04X}", addr)
+    }
+}
+
+/// The operand of `instruction` as source
+fn operand(
+    instruction: &Instruction,
+    addr: usize,
+    labels: &BTreeMap<usize, String>,
+) -> Option<String> {
+    let value = instruction.operand;
+    let size = instruction.size - 1;
+    let target = || {
+        instruction
+            .target(addr)
+            .and_then(|t| labels.get(&t))
+            .cloned()
+    };
+    Some(match instruction.opcode.addr_mode() {
+        AddressingMode::IMPL => String::new(),
+        AddressingMode::A => "A".to_string(),
+        AddressingMode::IMM => format!("#{}", hex(value, size)),
+        AddressingMode::ZPG => hex(value, 1),
+        AddressingMode::ZPGX => format!("{},X", hex(value, 1)),
+        AddressingMode::ZPGY => format!("{},Y", hex(value, 1)),
+        AddressingMode::ABS | AddressingMode::ABSL => target().unwrap_or_else(|| hex(value, size)),
+        AddressingMode::ABSX => format!("{},X", hex(value, 2)),
+        AddressingMode::ABSY => format!("{},Y", hex(value, 2)),
+        AddressingMode::ABSLX => format!("{},X", hex(value, 3)),
+        AddressingMode::IND => format!("({})", hex(value, 2)),
+        AddressingMode::INDX => format!("({},X)", hex(value, 1)),
+        AddressingMode::INDY => format!("({}),Y", hex(value, 1)),
+        AddressingMode::ZPGIND => format!("({})", hex(value, 1)),
+        AddressingMode::ABSINDX => format!("({},X)", hex(value, 2)),
+        AddressingMode::DPINDL => format!("[{}]", hex(value, 1)),
+        AddressingMode::DPINDLY => format!("[{}],Y", hex(value, 1)),
+        AddressingMode::ABSINDL => format!("[{}]", hex(value, 2)),
+        AddressingMode::SR => format!("{},S", hex(value, 1)),
+        AddressingMode::SRINDY => format!("({},S),Y", hex(value, 1)),
+        AddressingMode::REL => target()?,
+        AddressingMode::RELL => match target() {
+            Some(label) => label,
+            // `PER` pushes an address, it isn't a jump
+            None => {
+                let offset = value as u16 as i16 as isize;
+                hex(((addr + 3) as isize + offset) as usize & 0xFFFF, 2)
+            }
+        },
+        AddressingMode::ZPGREL => format!("{}, {}", hex(value & 0xFF, 1), target()?),
+        // The destination bank goes first
+        AddressingMode::BLK => format!("{}, {}", hex(value >> 8, 1), hex(value & 0xFF, 1)),
+    })
+}
+
+/// Source for `instruction`, if the assembler turns it back into the same bytes
+fn render(
+    instruction: &Instruction,
+    addr: usize,
+    byte: u8,
+    cpu: Cpu,
+    labels: &BTreeMap<usize, String>,
+) -> Option<String> {
+    let name = instruction.opcode.name();
+    let mode = instruction.opcode.addr_mode();
+    // Undocumented opcodes can do the same as others, only one of them has a name
+    if get_code(name, mode, cpu).ok()? != byte {
+        return None;
+    }
+    let operand = operand(instruction, addr, labels)?;
+    let line = if operand.is_empty() {
+        format!("    {:?}", name)
+    } else {
+        format!("    {:?} {}", name, operand)
+    };
+    // The parser picks the addressing mode from how the operand is written
+    match parse_line(line.as_bytes()).ok()?.1.as_slice() {
+        [LineType::Opcode(parsed)] if parsed.name == name && parsed.arg.0 == mode => Some(line),
+        _ => None,
+    }
+}
+
+fn data_line(bytes: &[u8]) -> String {
+    let bytes: Vec<String> = bytes.iter().map(|&b| hex(b as usize, 1)).collect();
+    format!(".db {}", bytes.join(", "))
+}
+
+/// Source that assembles to the bytes of `image`
+pub fn disassemble(image: &Image, options: &Options) -> Result<String, Error> {
+    let mut lines = vec![format!("; Disassembled from {}", image.origin)];
+    if options.cpu != Cpu::default() {
+        lines.push(format!(".cpu \"{}\"", options.cpu));
+    }
+    let load = hex(image.load, if image.load > 0xFFFF { 3 } else { 2 });
+    lines.extend(listing(image, options, format!(".org {}", load)));
+    lines.push(String::new());
+    Ok(lines.join("\n"))
+}
+
+/// Source that assembles to the bytes of the `.nes` file: the header and the CHR-ROM as
+/// `.db`, and each bank of the PRG-ROM on a `.logical` block of its address.
+/// With more than one bank, each one is a `.scope` (`bank0`, `bank1`, ...), so their labels
+/// don't mix
+pub fn disassemble_nes(nes: &Nes, options: &Options) -> Result<String, Error> {
+    let mut lines = vec![format!("; Disassembled from {}", nes.origin)];
+    if options.cpu != Cpu::default() {
+        lines.push(format!(".cpu \"{}\"", options.cpu));
+    }
+    lines.push(".org $0000".to_string());
+    lines.extend(nes.header.chunks(BYTES_PER_LINE).map(data_line));
+    for (bank, image) in nes.banks.iter().enumerate() {
+        lines.push(format!("; {}", image.origin));
+        if nes.banks.len() > 1 {
+            lines.push(format!(".scope bank{}", bank));
+        }
+        let load = format!(".logical {}", hex(image.load, 2));
+        lines.extend(listing(image, options, load));
+        lines.push(".endlogical".to_string());
+        if nes.banks.len() > 1 {
+            lines.push(".endscope".to_string());
+        }
+    }
+    if !nes.rest.is_empty() {
+        lines.push("; CHR-ROM".to_string());
+        lines.extend(nes.rest.chunks(BYTES_PER_LINE).map(data_line));
+    }
+    lines.push(String::new());
+    Ok(lines.join("\n"))
+}
+
+/// The lines for the bytes of `image`, after the constants and the line that places them
+/// (`start`, Ej.: `.org $8000`)
+fn listing(image: &Image, options: &Options, start: String) -> Vec<String> {
+    let cpu = options.cpu;
+    let vectors: Vec<(usize, usize, &str)> = VECTORS
+        .iter()
+        .filter_map(|&(addr, name)| image.value(addr, 2).map(|target| (addr, target, name)))
+        .collect();
+    let map = if options.trace {
+        let mut entries = options.entries.clone();
+        entries.extend(vectors.iter().map(|&(_, target, _)| target));
+        if entries.is_empty() {
+            entries.push(image.load);
+        }
+        Map::traced(image, cpu, &entries)
+    } else {
+        Map::linear(image, cpu)
+    };
+
+    // Names for the vectors, the entries and the targets of jumps and branches
+    let mut labels: BTreeMap<usize, String> = BTreeMap::new();
+    for &(_, target, name) in &vectors {
+        labels.entry(target).or_insert_with(|| name.to_string());
+    }
+    for &entry in &options.entries {
+        labels
+            .entry(entry)
+            .or_insert_with(|| format!("entry_{}", address_name(entry)));
+    }
+    for (&addr, instruction) in &map.code {
+        if let Some(target) = instruction.target(addr) {
+            let prefix = if instruction.opcode.name().is_call_op() {
+                "sub"
+            } else {
+                "L"
+            };
+            labels
+                .entry(target)
+                .or_insert_with(|| format!("{}_{}", prefix, address_name(target)));
+        }
+    }
+
+    let mut lines = Vec::new();
+    // Labels that aren't on the start of a line are constants
+    for (&addr, name) in &labels {
+        if !map.is_line_start(image, addr) {
+            lines.push(format!(
+                "{} = {}",
+                name,
+                hex(addr, if addr > 0xFFFF { 3 } else { 2 })
+            ));
+        }
+    }
+    lines.push(start);
+
+    // The assembler follows `REP`/`SEP` in the order of the source
+    let mut wide = (false, false);
+    let mut data: Vec<u8> = Vec::new();
+    let mut addr = image.load;
+    while image.contains(addr) {
+        let label = labels.get(&addr).filter(|_| map.is_line_start(image, addr));
+        let instruction = map.code.get(&addr);
+        let vector = vectors.iter().find(|&&(vector, _, _)| vector == addr);
+        if !data.is_empty()
+            && (label.is_some()
+                || instruction.is_some()
+                || vector.is_some()
+                || data.len() == BYTES_PER_LINE)
+        {
+            lines.push(data_line(&data));
+            data.clear();
+        }
+        if let Some(label) = label {
+            lines.push(format!("{}:", label));
+        }
+        let byte = image.byte(addr).unwrap_or_default();
+        if let Some(instruction) = instruction {
+            if cpu == Cpu::Wdc65816 {
+                let needed = (instruction.wide_accumulator, instruction.wide_index);
+                if needed.0 != wide.0 {
+                    lines.push(if needed.0 { ".a16" } else { ".a8" }.to_string());
+                }
+                if needed.1 != wide.1 {
+                    lines.push(if needed.1 { ".i16" } else { ".i8" }.to_string());
+                }
+                wide = needed;
+            }
+            let size = instruction.size;
+            match render(instruction, addr, byte, cpu, &labels) {
+                Some(line) => {
+                    lines.push(line);
+                    wide = instruction.widths_after();
+                }
+                None => {
+                    // Written as bytes, with what they mean
+                    let bytes = &image.bytes[addr - image.load..addr - image.load + size];
+                    lines.push(format!(
+                        "{} ; {:?} {:?}",
+                        data_line(bytes),
+                        instruction.opcode.name(),
+                        instruction.opcode.addr_mode()
+                    ));
+                }
+            }
+            addr += size;
+            continue;
+        }
+        let is_data = |addr: usize| image.contains(addr) && !map.is_code[addr - image.load];
+        if let (Some(&(_, target, _)), true) = (vector, is_data(addr + 1)) {
+            if !labels.contains_key(&(addr + 1)) {
+                lines.push(format!(".dw {}", labels[&target]));
+                addr += 2;
+                continue;
+            }
+        }
+        data.push(byte);
+        addr += 1;
+    }
+    if !data.is_empty() {
+        lines.push(data_line(&data));
+    }
+    lines
+}
+
+mod tests {
+    #[test]
+    fn test_disassemble_raw() {
+        use super::{disassemble, Image, Options};
+        use crate::assembler::{assemble_source, Metadata};
+        use crate::opcodes::Cpu;
+        let rom: Vec<u8> = vec![
+            0x78, // SEI
+            0xA2, 0xFF, // LDX #$FF
+            0x9A, // TXS
+            0xAD, 0x12, 0x00, // LDA $0012
+            0xF0, 0x03, // BEQ +3
+            0x20, 0x10, 0x80, // JSR $8010
+            0x4C, 0x00, 0x80, // JMP $8000
+            0xFF, // data
+            0xB5, 0x10, // LDA $10,X
+            0x60, // RTS
+            0x02, 0xA7, 0x80, // data, it would be LAX on the 6502X
+        ];
+        let image = Image::raw(rom.clone(), Some(0x8000), "test").unwrap();
+        // Linear and following the jumps
+        for &trace in &[false, true] {
+            let options = Options {
+                cpu: Cpu::Mos6502,
+                trace,
+                entries: vec![],
+            };
+            let source = disassemble(&image, &options).unwrap();
+            assert!(source.contains("JSR sub_8010"));
+            assert!(source.contains("LDA $0012"));
+            let code = assemble_source(&source, &Metadata::default()).unwrap();
+            assert_eq!(code[0x8000..0x8000 + rom.len()], rom[..]);
+        }
+    }
+    #[test]
+    fn test_disassemble_vectors() {
+        use super::{disassemble, Image, Options};
+        use crate::assembler::{assemble_source, Metadata};
+        use crate::opcodes::Cpu;
+        // Code from the vectors, the rest is data
+        let mut rom = vec![0xEA; 0x10];
+        rom[0] = 0x40; // RTI
+        rom[0x0D..].copy_from_slice(&[0x4C, 0xEB, 0xFF]); // JMP $FFEB
+        rom.extend(&[0xEA, 0xFF, 0xEB, 0xFF, 0xEA, 0xFF]);
+        let image = Image::raw(rom.clone(), None, "test").unwrap();
+        assert_eq!(image.load, 0xFFEA);
+        let options = Options {
+            cpu: Cpu::Mos6502,
+            trace: true,
+            entries: vec![],
+        };
+        let source = disassemble(&image, &options).unwrap();
+        assert!(source.contains("reset:\n    NOP"));
+        assert!(source.contains(".dw nmi"));
+        let code = assemble_source(&source, &Metadata::default()).unwrap();
+        assert_eq!(code[0xFFEA..], rom[..]);
+    }
+    #[test]
+    fn test_disassemble_65816() {
+        use super::{disassemble, Image, Options};
+        use crate::assembler::{assemble_source, Metadata};
+        use crate::opcodes::Cpu;
+        // The width of the immediates follows REP and SEP
+        let rom: Vec<u8> = vec![
+            0xC2, 0x20, // REP #$20
+            0xA9, 0x34, 0x12, // LDA #$1234
+            0x80, 0x03, // BRA +3
+            0xE2, 0x20, // SEP #$20
+            0x6B, // RTL
+            0xA9, 0x01, 0x00, // LDA #$0001, reached with a 16 bit accumulator
+            0x22, 0x00, 0x00, 0x7E, // JSL $7E0000
+            0x60, // RTS
+        ];
+        let image = Image::raw(rom.clone(), Some(0x8000), "test").unwrap();
+        let options = Options {
+            cpu: Cpu::Wdc65816,
+            trace: true,
+            entries: vec![0x8000],
+        };
+        let source = disassemble(&image, &options).unwrap();
+        assert!(source.contains("sub_7E0000 = $7E0000"));
+        let metadata = Metadata {
+            cpu: Cpu::Wdc65816,
+            ..Default::default()
+        };
+        let code = assemble_source(&source, &metadata).unwrap();
+        assert_eq!(code[0x8000..0x8000 + rom.len()], rom[..]);
+    }
+    #[test]
+    fn test_disassemble_nes() {
+        use super::{disassemble_nes, Nes, Options};
+        use crate::assembler::{assemble_source, Metadata};
+        use crate::opcodes::Cpu;
+        let mut nes = b"NES\x1A\x01\x01\x00\x00".to_vec();
+        nes.resize(16 + 0x4000 + 0x2000, 0xEA);
+        let rom = Nes::new(&nes, "test.nes").unwrap();
+        assert_eq!(
+            (rom.banks[0].load, rom.banks[0].bytes.len()),
+            (0xC000, 0x4000)
+        );
+        assert!(Nes::new(&nes[..0x100], "test.nes").is_err());
+        // Every bank, the header and the CHR-ROM come back
+        let mut nes = b"NES\x1A\x04\x01\x20\x00".to_vec();
+        nes.resize(16, 0x00);
+        for bank in 0..4u8 {
+            let mut prg = vec![0xFF; 0x4000];
+            // LDA #bank, JMP $8000 (the start of each bank)
+            prg[..5].copy_from_slice(&[0xA9, bank, 0x4C, 0x00, 0x80]);
+            nes.extend(prg);
+        }
+        // The vectors of the last bank point to its start
+        let vectors = nes.len() - 6;
+        nes[vectors..].copy_from_slice(&[0x00, 0xC0, 0x00, 0xC0, 0x00, 0xC0]);
+        nes.extend((0..0x2000).map(|i| i as u8));
+        let options = Options {
+            cpu: Cpu::Mos6502,
+            trace: true,
+            entries: vec![],
+        };
+        let source = disassemble_nes(&Nes::new(&nes, "test.nes").unwrap(), &options).unwrap();
+        // The jump of the last bank goes to another one
+        assert!(source.contains(
+            ".scope bank3\nL_8000 = $8000\n.logical $C000\nnmi:\n    LDA #$03\n    JMP L_8000\n"
+        ));
+        let code = assemble_source(&source, &Metadata::default()).unwrap();
+        assert_eq!(code[..nes.len()], nes[..]);
+    }
+}
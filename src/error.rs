@@ -5,6 +5,8 @@ use nom::{error::ErrorKind, Err as NErr};
 custom_error! {pub Error
     Parser{cause: String} = "Parser error: {cause}",
     Assembler{cause: String} = "Assembler error: {cause}",
+    Disassembler{cause: String} = "Disassembler error: {cause}",
+    Simulator{cause: String} = "Simulator error: {cause}",
     UnkownOpcode{name: String} = "Unkown opcode {name} (Maybe the addressing mode is not valid?)",
     UndefLabel{labels: String} = "These labels were used, but a definition couldn't be found: {labels}",
     ParseTo{source: std::num::ParseIntError} = "Couldn't parse text to a number: {source}",
@@ -1,6 +1,6 @@
 macro_rules! import_formats {
-    ($($name:ident->$ext:literal),*) => {
-        $(pub mod $name;)*
+    ($($module:ident: $name:ident->$ext:literal),*) => {
+        $(pub mod $module;)*
         #[derive(Copy, Clone, PartialEq, Debug)]
         pub(crate) enum Format {
             $($name),*
@@ -30,4 +30,4 @@ macro_rules! import_formats {
     };
 }
 
-import_formats!(Hex->"hex", Nes2->"nes");
+import_formats!(hex: Hex->"hex", nes2: Nes2->"nes");
@@ -1,5 +1,8 @@
+// Not reachable from `--format` yet
+#![allow(dead_code)]
+
 mod rom_data {
-    pub static HEADER_MAGIC_NUMBER: &'static [u8; 4] = b"NES\x1A";
+    pub static HEADER_MAGIC_NUMBER: &[u8; 4] = b"NES\x1A";
     pub static HEADER_NES2_IDENT: u8 = 0b0000_1000;
     pub static HEADER_CONSOLE_TYPE: u8 = 0b0000_0000;
     pub static BANK_SIZE: usize = 0x2000; // 8KiB
@@ -33,7 +36,7 @@ impl Cartridge {
         header[4] = (prg_rom_size & 0x0FF) as u8;
         header[5] = (chr_rom_size & 0x0FF) as u8;
         header[6] = 0b0000_0001;
-        header[7] = 0x00 | rom_data::HEADER_NES2_IDENT | rom_data::HEADER_CONSOLE_TYPE;
+        header[7] = rom_data::HEADER_NES2_IDENT | rom_data::HEADER_CONSOLE_TYPE;
         header[8] = 0x00; // Mapper MSB / Submapper
         header[9] |= ((prg_rom_size & 0xF00) >> 4) as u8;
         header[9] |= ((chr_rom_size & 0xF00) >> 8) as u8;
@@ -82,7 +85,7 @@ impl Cartridge {
             }
         }
 
-        let mut chr_rom_real_size = if chr_rom_size & 0x800 == 0 {
+        let chr_rom_real_size = if chr_rom_size & 0x800 == 0 {
             chr_rom_size as usize * 8 * 1024
         } else {
             // Exponent notation
@@ -91,10 +94,8 @@ impl Cartridge {
         let mut chr_rom_area = vec![0; chr_rom_real_size];
         println!("Created CHR-ROM with {} bytes", chr_rom_area.len());
         let bank_addr = banks[2];
-        for i in 0..0x2000 {
-            // 8KiB
-            chr_rom_area[i] = code[bank_addr + i];
-        }
+        // 8KiB
+        chr_rom_area[..0x2000].copy_from_slice(&code[bank_addr..bank_addr + 0x2000]);
 
         Self {
             header,
@@ -103,12 +104,12 @@ impl Cartridge {
         }
     }
 }
-impl std::convert::Into<Vec<u8>> for Cartridge {
-    fn into(self) -> Vec<u8> {
-        let mut bytes = Vec::with_capacity(self.prg_rom.len() + self.chr_rom.len() + 16);
-        bytes.extend(&self.header.to_vec());
-        bytes.extend(&self.prg_rom);
-        bytes.extend(&self.chr_rom);
+impl From<Cartridge> for Vec<u8> {
+    fn from(cartridge: Cartridge) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(cartridge.prg_rom.len() + cartridge.chr_rom.len() + 16);
+        bytes.extend(&cartridge.header.to_vec());
+        bytes.extend(&cartridge.prg_rom);
+        bytes.extend(&cartridge.chr_rom);
         println!("{}", bytes.len());
         bytes
     }
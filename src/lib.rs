@@ -0,0 +1,14 @@
+//! Instruction tables of the 6502 family and the parser of its assembly language,
+//! for editor plugins and analyzers. Ej.: `OpcodeType::LDA.opcode(AddressingMode::IMM, Cpu::Mos6502)`.
+//! The assembler and the simulator are here too, to run code from other programs with
+//! `Machine::builder(cpu).image(&code).device(0xF001, CharOut::new(stdout())).build()`
+
+#[macro_use]
+extern crate nom;
+
+pub mod addressing_modes;
+pub mod assembler;
+pub mod error;
+pub mod opcodes;
+pub mod parser;
+pub mod simulator;
@@ -1,11 +1,9 @@
-#[macro_use]
-extern crate nom;
-
-use assembler::assemble;
+use assembler::assemble_lines;
+use crustacean_6502_assembler::{addressing_modes, assembler, error, opcodes, parser, simulator};
 use error::Error;
 use formats::Format;
 use parser::LineType;
-use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::io::{BufWriter, Read, Write};
 use std::path::PathBuf;
 use structopt::StructOpt;
 
@@ -18,112 +16,542 @@ struct Args {
     output: Option<PathBuf>,
     #[structopt(parse(from_str), long, default_value = "Hex")]
     format: formats::Format,
+    /// Treat labels that only differ in case (`main`, `Main`) as the same one
+    #[structopt(long)]
+    case_insensitive_labels: bool,
+    /// Instruction set: 6502, 6502x (with the undocumented opcodes), 2a03 (NES, with them too), 65c02 or 65816.
+    /// `.cpu` changes it from that line on
+    #[structopt(long, default_value = "6502")]
+    cpu: opcodes::Cpu,
+    /// Also write the labels as FCEUX name lists, one for the RAM and one for each PRG bank
+    /// (`<output>.ram.nl`, `<output>.0.nl`, ...)
+    #[structopt(long)]
+    fceux: bool,
+    /// Also write the labels as a Mesen label file (the output with the `.mlb` extension)
+    #[structopt(long)]
+    mesen: bool,
+    /// Also write the labels and the line of each instruction as an ELF file with DWARF debug
+    /// info (the output with the `.elf` extension), that GDB loads with `file`
+    #[structopt(long)]
+    elf: bool,
+    /// Add the comments of the source to the labels of `--fceux` and `--mesen`
+    #[structopt(long)]
+    comments: bool,
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(StructOpt)]
+enum Command {
+    /// Turn a binary or a .nes file back into source that assembles to the same bytes
+    Disasm {
+        /// Address of the first byte of a binary ($8000, 0x8000 or 32768).
+        /// By default it ends on $FFFF. .nes files use the address of their PRG-ROM
+        #[structopt(long, parse(try_from_str = parse_address))]
+        load: Option<usize>,
+        /// Only bytes reachable from the reset, NMI and IRQ vectors (and --entry) are code
+        #[structopt(long)]
+        trace: bool,
+        /// Other places where code starts, with --trace
+        #[structopt(long, parse(try_from_str = parse_address))]
+        entry: Vec<usize>,
+    },
+    /// Assemble and run the code on a simulated CPU with 64KiB of RAM
+    Run(RunArgs),
+    /// Assemble the code and debug it with GDB's remote protocol
+    Gdb {
+        /// Port of localhost to wait for GDB on
+        #[structopt(long, default_value = "6502")]
+        port: u16,
+        /// Where to start, instead of the address on the reset vector
+        #[structopt(long, parse(try_from_str = parse_address))]
+        start: Option<usize>,
+        #[structopt(flatten)]
+        devices: DeviceArgs,
+    },
+    /// Assemble and run each `.test` block, checking its `.expect`s
+    Test {
+        /// Only run the tests whose name contains this
+        filter: Option<String>,
+        /// Fail a test that runs for more cycles than this
+        #[structopt(long, default_value = "1000000")]
+        max_cycles: u64,
+        #[structopt(flatten)]
+        coverage: CoverageArgs,
+        #[structopt(flatten)]
+        devices: DeviceArgs,
+    },
+}
+
+#[derive(StructOpt)]
+struct RunArgs {
+    /// Where to start, instead of the address on the reset vector
+    #[structopt(long, parse(try_from_str = parse_address))]
+    start: Option<usize>,
+    /// Stop after this many cycles
+    #[structopt(long, default_value = "100000000")]
+    max_cycles: u64,
+    /// Stop when the PC gets to this address
+    #[structopt(long, parse(try_from_str = parse_address))]
+    trap: Vec<usize>,
+    /// Memory to print when it stops, Ej.: $0200 or $0200-$02FF
+    #[structopt(long, parse(try_from_str = parse_range))]
+    dump: Vec<(usize, usize)>,
+    /// Write each instruction and the registers before it to this file, like nestest.log
+    #[structopt(long, parse(from_os_str))]
+    trace: Option<PathBuf>,
+    /// Print the cycles each routine and line took
+    #[structopt(long)]
+    profile: bool,
+    /// Enter an NMI after each this many cycles, Ej.: 29781 for the frames of the NES
+    #[structopt(long)]
+    nmi_every: Option<u64>,
+    #[structopt(flatten)]
+    coverage: CoverageArgs,
+    #[structopt(flatten)]
+    devices: DeviceArgs,
+}
+
+#[derive(StructOpt)]
+struct CoverageArgs {
+    /// Write an LCOV file with the times each line ran
+    #[structopt(long, parse(from_os_str))]
+    lcov: Option<PathBuf>,
+    /// Write the source with the times each line ran in front, like gcov
+    #[structopt(long, parse(from_os_str))]
+    listing: Option<PathBuf>,
+}
+
+#[derive(StructOpt)]
+struct DeviceArgs {
+    /// Device on the bus: charout[=$F001] prints what is stored on it, timer[=$F008] counts cycles,
+    /// fifo[=$F004]:file (- for stdin) gives bytes to read and controller[=$4016]:movie.fm2 plays
+    /// the input of an FCEUX movie
+    #[structopt(long = "device", parse(try_from_str = parse_device))]
+    devices: Vec<DeviceSpec>,
+}
+
+/// Ej.: `charout`, `fifo=$F004:input.txt` or `controller:movie.fm2`
+struct DeviceSpec {
+    kind: String,
+    address: Option<usize>,
+    file: Option<PathBuf>,
+}
+fn parse_device(s: &str) -> Result<DeviceSpec, String> {
+    let (device, file) = match s.find(':') {
+        Some(i) => (&s[..i], Some(PathBuf::from(&s[i + 1..]))),
+        None => (s, None),
+    };
+    let (kind, address) = match device.find('=') {
+        Some(i) => {
+            let address = parse_address(&device[i + 1..]).map_err(|e| e.to_string())?;
+            (&device[..i], Some(address))
+        }
+        None => (device, None),
+    };
+    match (kind, &file) {
+        ("charout", None) | ("timer", None) | ("fifo", Some(_)) | ("controller", Some(_)) => {
+            Ok(DeviceSpec {
+                kind: kind.to_string(),
+                address,
+                file,
+            })
+        }
+        ("fifo", None) | ("controller", None) => Err(format!("'{}' needs a file", kind)),
+        ("charout", Some(_)) | ("timer", Some(_)) => Err(format!("'{}' doesn't take a file", kind)),
+        _ => Err(format!("There is no device called '{}'", kind)),
+    }
+}
+
+/// Ej.: `$8000`, `0x8000` or `32768`
+fn parse_address(s: &str) -> Result<usize, std::num::ParseIntError> {
+    if let Some(hex) = s.strip_prefix('$').or_else(|| s.strip_prefix("0x")) {
+        usize::from_str_radix(hex, 16)
+    } else {
+        s.parse()
+    }
+}
+/// Ej.: `$0200` or `$0200-$02FF`, both ends included
+fn parse_range(s: &str) -> Result<(usize, usize), std::num::ParseIntError> {
+    match s.find('-') {
+        Some(i) => Ok((parse_address(&s[..i])?, parse_address(&s[i + 1..])?)),
+        None => parse_address(s).map(|addr| (addr, addr)),
+    }
 }
 // #endregion
 
-mod addressing_modes;
-mod assembler;
-mod error;
+mod disassembler;
 mod formats;
-mod opcodes;
-mod parser;
 
 fn main() -> Result<(), error::Error> {
     let args: Args = Args::from_args();
-    let input_buf = BufReader::new(
-        std::fs::OpenOptions::new()
-            .read(true)
-            .open(&args.input)
-            .unwrap_or_else(|e| panic!("Could not open input file. Error: {:?}", e)),
-    );
+    match &args.command {
+        Some(Command::Disasm { load, trace, entry }) => {
+            return disasm(&args, *load, *trace, entry.clone())
+        }
+        Some(Command::Run(run_args)) => return run(&args, run_args),
+        Some(Command::Gdb {
+            port,
+            start,
+            devices,
+        }) => return gdb(&args, *port, *start, devices),
+        Some(Command::Test {
+            filter,
+            max_cycles,
+            coverage,
+            devices,
+        }) => return test(&args, filter.as_deref(), *max_cycles, coverage, devices),
+        None => {}
+    }
+    let code = assemble_input(&args)?;
+    let output = match args.output.clone() {
+        Some(v) => v,
+        None => {
+            let mut out = args.input.clone();
+            out.set_extension(args.format.get_ext());
+            out
+        }
+    };
     let mut output_buf = BufWriter::new(
         std::fs::OpenOptions::new()
             .write(true)
             .create(true)
             .truncate(true)
-            .open(match args.output {
-                Some(v) => v,
-                None => {
-                    let mut out = args.input.clone();
-                    let fmt: Format = args.format.into();
-                    out.set_extension(fmt.get_ext());
-                    out
-                }
-            })
+            .open(&output)
             .unwrap_or_else(|e| panic!("Could not open output file. Error: {:?}", e)),
     );
 
-    let code: Vec<_> = input_buf
-        .lines()
-        .map(|line: Result<String, std::io::Error>| {
-            let mut escape = false;
-            let mut in_string = false;
-            let mut in_comment = false;
-            let line: String = line?
-                .chars()
-                .filter_map(|c: char| {
-                    if in_comment {
-                        None
-                    } else {
-                        match c {
-                            '\\' => {
-                                escape = true;
-                                None
-                            }
-                            '"' if !escape => {
-                                in_string = !in_string;
-                                Some('"')
-                            }
-                            '"' if escape => Some(c),
-                            'n' if escape => Some(0x10 as char),
-                            ';' => {
-                                in_comment = true;
-                                None
-                            }
-                            _ => {
-                                if escape {
-                                    panic!("\\{} doesn't mean anything", c)
-                                } else {
-                                    Some(c)
-                                }
-                            }
-                        }
-                    }
-                })
-                .collect();
-            /* let line: String = line?
-            .split(';')
-            .next()
-            .unwrap_or_default()
-            .trim()
-            .to_owned(); */
-            println!("Line {}", line);
-            Ok(line)
-        })
-        .filter(|line: &Result<_, _>| match line {
-            Ok(line) => !line.is_empty(),
-            Err(_) => true,
-        })
-        .map(|line: Result<String, Error>| {
-            line.and_then(|line| {
-                parser::parse_line(line.as_bytes())
-                    .map(|(_, line)| line)
-                    .map_err(Into::into)
-            })
-        })
-        .collect::<Result<_, Error>>()?;
+    match args.format {
+        Format::Hex => output_buf.write_all(&formats::hex::save(&code[..]))?,
+        _ => unimplemented!("This format hasn't been implemented yet"),
+    };
+    write_debug_symbols(&args, &code, &output)
+}
 
-    let metadata = {
-        let mut search_path = PathBuf::from(args.input);
-        search_path.pop();
-        assembler::Metadata { search_path }
+/// The label files of `--fceux`, `--mesen` and `--elf`, next to `output`
+fn write_debug_symbols(
+    args: &Args,
+    code: &assembler::Assembled,
+    output: &std::path::Path,
+) -> Result<(), Error> {
+    let source = if args.comments {
+        Some(std::fs::read_to_string(&args.input)?)
+    } else {
+        None
     };
+    if args.fceux {
+        for (bank, list) in code.fceux_name_lists(source.as_deref()) {
+            let mut path = output.as_os_str().to_owned();
+            path.push(format!(".{}.nl", bank));
+            std::fs::write(path, list)?;
+        }
+    }
+    if args.mesen {
+        std::fs::write(
+            output.with_extension("mlb"),
+            code.mesen_labels(source.as_deref()),
+        )?;
+    }
+    if args.elf {
+        std::fs::write(
+            output.with_extension("elf"),
+            code.elf(&full_path(&args.input)),
+        )?;
+    }
+    Ok(())
+}
 
-    let code = assemble(code, &metadata)?;
-    match args.format {
-        Format::Hex => output_buf.write_all(&code)?,
-        _ => unimplemented!("This format hasn't been implemented yet"),
+/// Parse the input file, keeping the number of the line each `LineType` came from
+fn parse_input(args: &Args) -> Result<Vec<(usize, LineType)>, Error> {
+    let source = std::fs::read_to_string(&args.input)
+        .unwrap_or_else(|e| panic!("Could not open input file. Error: {:?}", e));
+    parser::parse_source(&source)
+}
+
+fn metadata(args: &Args, test: Option<usize>) -> assembler::Metadata {
+    let mut search_path = args.input.clone();
+    search_path.pop();
+    assembler::Metadata {
+        search_path,
+        case_sensitive_labels: !args.case_insensitive_labels,
+        cpu: args.cpu,
+        test,
+    }
+}
+
+/// Parse and assemble the input file
+fn assemble_input(args: &Args) -> Result<assembler::Assembled, Error> {
+    let code = assemble_lines(parse_input(args)?, &metadata(args, None))?;
+    show_messages(&code);
+    Ok(code)
+}
+
+/// Print the warnings and the `.print` texts on stderr, away from the output of the program
+fn show_messages(code: &assembler::Assembled) {
+    for warning in &code.warnings {
+        eprintln!("Warning: {}", warning);
+    }
+    for text in &code.prints {
+        eprintln!("{}", text);
+    }
+}
+
+fn disasm(args: &Args, load: Option<usize>, trace: bool, entries: Vec<usize>) -> Result<(), Error> {
+    use disassembler::{disassemble, disassemble_nes, Image, Nes, Options};
+    let file = std::fs::read(&args.input)?;
+    let origin = args.input.display().to_string();
+    let options = Options {
+        cpu: args.cpu,
+        trace,
+        entries,
+    };
+    let source = if Nes::is_nes(&file) {
+        if load.is_some() {
+            return Err(Error::Disassembler {
+                cause: format!("{} is a .nes file, it can't take a load address", origin),
+            });
+        }
+        disassemble_nes(&Nes::new(&file, &origin)?, &options)?
+    } else {
+        disassemble(&Image::raw(file, load, &origin)?, &options)?
+    };
+    let output = match &args.output {
+        Some(v) => v.clone(),
+        None => args.input.with_extension("asm"),
     };
+    std::fs::write(output, source)?;
+    Ok(())
+}
 
+/// A machine with `code` and the devices of `--device`, after a reset
+fn build_machine(
+    code: &assembler::Assembled,
+    devices: &DeviceArgs,
+    start: Option<usize>,
+) -> Result<simulator::Machine, Error> {
+    use simulator::{CharOut, Controller, InputFifo, Timer};
+    let mut builder = simulator::Machine::builder(code.cpu).image(code);
+    if let Some(start) = start {
+        builder = builder.start(start as u16);
+    }
+    for spec in &devices.devices {
+        let read = |default| spec.address.unwrap_or(default) as u16;
+        builder = match (&spec.kind[..], &spec.file) {
+            ("charout", _) => builder.device(read(0xF001), CharOut::new(std::io::stdout())),
+            ("timer", _) => builder.device(read(0xF008), Timer::new()),
+            ("fifo", Some(file)) => {
+                let mut bytes = Vec::new();
+                if file.as_os_str() == "-" {
+                    std::io::stdin().read_to_end(&mut bytes)?;
+                } else {
+                    bytes = std::fs::read(file)?;
+                }
+                builder.device(read(0xF004), InputFifo::new(bytes))
+            }
+            ("controller", Some(file)) => {
+                let movie = std::fs::read_to_string(file)?;
+                builder.device(read(0x4016), Controller::from_fm2(&movie)?)
+            }
+            _ => unreachable!("Checked by parse_device"),
+        };
+    }
+    builder.build()
+}
+
+fn run(args: &Args, run_args: &RunArgs) -> Result<(), Error> {
+    let code = assemble_input(args)?;
+    let mut machine = build_machine(&code, &run_args.devices, run_args.start)?;
+    machine.nmi_every(run_args.nmi_every);
+    let traps: Vec<u16> = run_args.trap.iter().map(|&trap| trap as u16).collect();
+    let mut trace = match &run_args.trace {
+        Some(path) => Some(BufWriter::new(std::fs::File::create(path)?)),
+        None => None,
+    };
+    let mut executed = simulator::Executed::new();
+    let mut profiler = simulator::Profiler::new(&code);
+    let mut result = Ok(());
+    let stop = machine.run_with(run_args.max_cycles, &traps, &mut |machine| {
+        executed.record(machine);
+        profiler.record(machine);
+        match &mut trace {
+            Some(output) if result.is_ok() => {
+                result = writeln!(output, "{}", machine.trace_line());
+            }
+            _ => {}
+        }
+    });
+    result?;
+    if let Some(mut output) = trace {
+        output.flush()?;
+    }
+    profiler.finish(&machine);
+    let mut coverage = simulator::Coverage::new();
+    coverage.add(&code, &executed);
+    write_coverage(args, &run_args.coverage, &coverage)?;
+    println!("Stopped: {}", stop);
+    println!("{}", machine.registers);
+    println!("Cycles: {}", machine.cycles);
+    for &(first, last) in &run_args.dump {
+        let memory = &machine.memory()[first.min(0xFFFF)..=last.min(0xFFFF)];
+        for (i, line) in memory.chunks(16).enumerate() {
+            let bytes: Vec<String> = line.iter().map(|b| format!("{:02X}", b)).collect();
+            println!("${:04X}: {}", first + i * 16, bytes.join(" "));
+        }
+    }
+    if run_args.profile {
+        print_profile(args, &profiler)?;
+    }
+    let over_budget = profiler.over_budget();
+    if !over_budget.is_empty() {
+        return Err(Error::Simulator {
+            cause: over_budget.join("\n"),
+        });
+    }
     Ok(())
 }
+
+/// Routines with the most cycles first, and the hottest lines
+fn print_profile(args: &Args, profiler: &simulator::Profiler) -> Result<(), Error> {
+    println!(
+        "\n{:<32} {:>8} {:>12} {:>12} {:>10}",
+        "Routine", "Calls", "Inclusive", "Exclusive", "Worst"
+    );
+    for routine in profiler.routines() {
+        let budget = match routine.budget {
+            Some(budget) => format!(" (budget {})", budget),
+            None => String::new(),
+        };
+        println!(
+            "{:<32} {:>8} {:>12} {:>12} {:>10}{}",
+            routine.name,
+            routine.calls,
+            routine.inclusive,
+            routine.exclusive,
+            routine.worst,
+            budget
+        );
+    }
+    let source = std::fs::read_to_string(&args.input)?;
+    let source: Vec<&str> = source.lines().collect();
+    println!("\nHottest lines");
+    for (line, cycles) in profiler.hottest_lines(10) {
+        let text = source.get(line - 1).map_or("", |text| text.trim());
+        let location = format!("{}:{}", args.input.display(), line);
+        println!("{:<32} {:>12}  {}", location, cycles, text);
+    }
+    Ok(())
+}
+
+/// Run the tests and print the results like `cargo test`
+fn test(
+    args: &Args,
+    filter: Option<&str>,
+    max_cycles: u64,
+    coverage_args: &CoverageArgs,
+    devices: &DeviceArgs,
+) -> Result<(), Error> {
+    let lines = parse_input(args)?;
+    let code = assemble_lines(lines.clone(), &metadata(args, None))?;
+    show_messages(&code);
+    let tests = code.tests;
+    let selected: Vec<usize> = (0..tests.len())
+        .filter(|&i| filter.map_or(true, |filter| tests[i].name.contains(filter)))
+        .collect();
+    let file = args.input.display();
+    println!("\nrunning {} tests", selected.len());
+    let mut failed: Vec<(String, Vec<simulator::Failure>)> = Vec::new();
+    let mut coverage = simulator::Coverage::new();
+    for &i in &selected {
+        let code = assemble_lines(lines.clone(), &metadata(args, Some(i)))?;
+        let test = &code.tests[i];
+        let mut executed = simulator::Executed::new();
+        let mut profiler = simulator::Profiler::new(&code);
+        let machine = build_machine(&code, devices, None)?;
+        let (mut failures, machine) =
+            simulator::run_test_with(machine, test, max_cycles, &mut |machine| {
+                executed.record(machine);
+                profiler.record(machine);
+            });
+        profiler.finish(&machine);
+        coverage.add(&code, &executed);
+        failures.extend(
+            profiler
+                .over_budget()
+                .into_iter()
+                .map(|message| simulator::Failure {
+                    line: test.line,
+                    message,
+                }),
+        );
+        if failures.is_empty() {
+            println!("test {} ... ok", test.name);
+        } else {
+            println!("test {} ... FAILED", test.name);
+            failed.push((test.name.clone(), failures));
+        }
+    }
+    if !failed.is_empty() {
+        println!("\nfailures:");
+        for (name, failures) in &failed {
+            println!("\n---- {} ----", name);
+            for failure in failures {
+                println!("{}:{}: {}", file, failure.line, failure.message);
+            }
+        }
+        println!("\nfailures:");
+        for (name, _) in &failed {
+            println!("    {}", name);
+        }
+    }
+    write_coverage(args, coverage_args, &coverage)?;
+    let result = if failed.is_empty() { "ok" } else { "FAILED" };
+    println!(
+        "\ntest result: {}. {} passed; {} failed; {} filtered out\n",
+        result,
+        selected.len() - failed.len(),
+        failed.len(),
+        tests.len() - selected.len()
+    );
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::Simulator {
+            cause: format!("{} of {} tests failed", failed.len(), selected.len()),
+        })
+    }
+}
+
+/// Write the files asked for with `--lcov` and `--listing`
+fn write_coverage(
+    args: &Args,
+    coverage_args: &CoverageArgs,
+    coverage: &simulator::Coverage,
+) -> Result<(), Error> {
+    if let Some(path) = &coverage_args.lcov {
+        std::fs::write(path, coverage.lcov(&args.input.display().to_string()))?;
+    }
+    if let Some(path) = &coverage_args.listing {
+        let source = std::fs::read_to_string(&args.input)?;
+        std::fs::write(path, coverage.listing(&source))?;
+    }
+    Ok(())
+}
+
+/// Ej.: `/home/user/game/main.asm` for `main.asm`, for the debug info
+fn full_path(path: &std::path::Path) -> String {
+    std::fs::canonicalize(path)
+        .unwrap_or_else(|_| path.to_path_buf())
+        .display()
+        .to_string()
+}
+
+fn gdb(args: &Args, port: u16, start: Option<usize>, devices: &DeviceArgs) -> Result<(), Error> {
+    let code = assemble_input(args)?;
+    // GDB asks for it when it connects, and loads the symbols and the lines from it
+    let elf = args.input.with_extension("elf");
+    std::fs::write(&elf, code.elf(&full_path(&args.input)))?;
+    let machine = build_machine(&code, devices, start)?;
+    let mut stub = simulator::Stub::new(machine, &code, &args.input.display().to_string());
+    stub.exec_file = Some(full_path(&elf));
+    simulator::serve(stub, port)
+}
@@ -1,881 +1,515 @@
-use super::addressing_modes::AddressingMode;
+use super::addressing_modes::{AddressingMode, MODES};
 use crate::error::Error;
 
-pub fn get_code(name: OpcodeType, addr_mode: AddressingMode) -> Result<u8, Error> {
-    for (i, opcode) in OPCODES.iter().enumerate() {
-        match opcode {
-            None => continue,
-            Some(ref opcode) => {
-                if opcode.name == name && opcode.addr_mode == addr_mode {
-                    return Ok((i & 0xFF) as u8);
-                }
-            }
+/// Instruction set the code is assembled for
+#[derive(Debug, PartialEq, Copy, Clone, Default)]
+pub enum Cpu {
+    /// The original NMOS 6502
+    #[default]
+    Mos6502,
+    /// The NMOS 6502 with its stable undocumented opcodes (`LAX`, `DCP`, ...)
+    Mos6502X,
+    /// The 6502 of the NES, without decimal mode
+    Ricoh2A03,
+    /// The CMOS 65C02 from WDC, with the Rockwell bit instructions
+    Wdc65C02,
+    /// The 16 bit 65816 from WDC (native mode)
+    Wdc65816,
+}
+impl Cpu {
+    pub const ALL: [Cpu; 5] = [
+        Cpu::Mos6502,
+        Cpu::Ricoh2A03,
+        Cpu::Mos6502X,
+        Cpu::Wdc65C02,
+        Cpu::Wdc65816,
+    ];
+    /// Highest address the code can be placed on
+    pub fn max_address(self) -> usize {
+        match self {
+            Cpu::Wdc65816 => 0xFF_FFFF,
+            _ => 0xFFFF,
+        }
+    }
+    /// Opcodes of this CPU, by code
+    fn opcodes(self) -> &'static [Option<OpcodeData>; 256] {
+        match self {
+            Cpu::Mos6502 => &OPCODES_6502,
+            Cpu::Mos6502X => &OPCODES_6502X,
+            Cpu::Ricoh2A03 => &OPCODES_2A03,
+            Cpu::Wdc65C02 => &OPCODES_65C02,
+            Cpu::Wdc65816 => &OPCODES_65816,
+        }
+    }
+    /// Code of each instruction and addressing mode of this CPU
+    fn codes(self) -> &'static [[Option<u8>; MODES]; OpcodeType::COUNT] {
+        match self {
+            Cpu::Mos6502 => &CODES_6502,
+            Cpu::Mos6502X => &CODES_6502X,
+            Cpu::Ricoh2A03 => &CODES_2A03,
+            Cpu::Wdc65C02 => &CODES_65C02,
+            Cpu::Wdc65816 => &CODES_65816,
         }
     }
-    Err(Error::UnkownOpcode { name: name.into() })
+}
+impl std::str::FromStr for Cpu {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Cpu::ALL
+            .iter()
+            .find(|cpu| cpu.to_string().eq_ignore_ascii_case(s))
+            .copied()
+            .ok_or_else(|| {
+                format!(
+                    "Unkown CPU {} (Expected 6502, 6502x, 2a03, 65c02 or 65816)",
+                    s
+                )
+            })
+    }
+}
+impl std::fmt::Display for Cpu {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            Cpu::Mos6502 => "6502",
+            Cpu::Mos6502X => "6502x",
+            Cpu::Ricoh2A03 => "2a03",
+            Cpu::Wdc65C02 => "65c02",
+            Cpu::Wdc65816 => "65816",
+        })
+    }
+}
+
+pub fn get_code(name: OpcodeType, addr_mode: AddressingMode, cpu: Cpu) -> Result<u8, Error> {
+    let code_on = |cpu: Cpu| cpu.codes()[name as usize][addr_mode as usize];
+    if let Some(code) = code_on(cpu) {
+        return Ok(code);
+    }
+    // Tell which CPU it belongs to, if any. The 2A03 has nothing the 6502X doesn't
+    let other = match Cpu::ALL
+        .iter()
+        .filter(|other| **other != Cpu::Ricoh2A03)
+        .find(|other| code_on(**other).is_some())
+    {
+        Some(other) => other,
+        None => return Err(Error::UnkownOpcode { name: name.into() }),
+    };
+    let instruction = if cpu.codes()[name as usize].iter().any(Option::is_some) {
+        format!("{:?} with {:?} addressing", name, addr_mode)
+    } else {
+        format!("{:?}", name)
+    };
+    Err(Error::Assembler {
+        cause: format!(
+            "{} is a {} instruction, but the code is for the {}. Use '--cpu {}' or '.cpu \"{}\"'",
+            instruction, other, cpu, other, other
+        ),
+    })
 }
 
-#[derive(Debug, PartialEq, Copy, Clone)]
-pub enum OpcodeType {
-    ADC,
-    AND,
-    ASL,
-    BCC, // Branch ops
-    BCS, // Branch ops
-    BEQ, // Branch ops
-    BIT,
-    BMI, // Branch ops
-    BNE, // Branch ops
-    BPL, // Branch ops
-    BRK,
-    BVC, // Branch ops
-    BVS, // Branch ops
-    CLC,
-    CLD,
-    CLI,
-    CLV,
-    CMP,
-    CPX,
-    CPY,
-    DEC,
-    DEX,
-    DEY,
-    EOR,
-    INC,
-    INX,
-    INY,
-    JMP,
-    JSR,
-    LDA,
-    LDX,
-    LDY,
-    LSR,
-    NOP,
-    ORA,
-    PHA,
-    PHP,
-    PLA,
-    PLP,
-    ROL,
-    ROR,
-    RTI,
-    RTS,
-    SBC,
-    SEC,
-    SED,
-    SEI,
-    STA,
-    STX,
-    STY,
-    TAX,
-    TAY,
-    TSX,
-    TXA,
-    TXS,
-    TYA,
+/// What `code` means for `cpu`
+pub fn get_opcode(code: u8, cpu: Cpu) -> Option<&'static OpcodeData> {
+    cpu.opcodes()[code as usize].as_ref()
 }
+
+// `OpcodeType` and the `OPCODES_*`/`CODES_*` tables of every CPU
+include!(concat!(env!("OUT_DIR"), "/opcodes_table.rs"));
+
 impl OpcodeType {
-    pub fn identify<'s, S: std::ops::Deref<Target = &'s str>>(
-        string: &S,
-    ) -> Result<OpcodeType, ()> {
-        match **string {
-            "ADC" => Ok(OpcodeType::ADC),
-            "AND" => Ok(OpcodeType::AND),
-            "ASL" => Ok(OpcodeType::ASL),
-            "BCC" => Ok(OpcodeType::BCC),
-            "BCS" => Ok(OpcodeType::BCS),
-            "BEQ" => Ok(OpcodeType::BEQ),
-            "BIT" => Ok(OpcodeType::BIT),
-            "BMI" => Ok(OpcodeType::BMI),
-            "BNE" => Ok(OpcodeType::BNE),
-            "BPL" => Ok(OpcodeType::BPL),
-            "BRK" => Ok(OpcodeType::BRK),
-            "BVC" => Ok(OpcodeType::BVC),
-            "BVS" => Ok(OpcodeType::BVS),
-            "CLC" => Ok(OpcodeType::CLC),
-            "CLD" => Ok(OpcodeType::CLD),
-            "CLI" => Ok(OpcodeType::CLI),
-            "CLV" => Ok(OpcodeType::CLV),
-            "CMP" => Ok(OpcodeType::CMP),
-            "CPX" => Ok(OpcodeType::CPX),
-            "CPY" => Ok(OpcodeType::CPY),
-            "DEC" => Ok(OpcodeType::DEC),
-            "DEX" => Ok(OpcodeType::DEX),
-            "DEY" => Ok(OpcodeType::DEY),
-            "EOR" => Ok(OpcodeType::EOR),
-            "INC" => Ok(OpcodeType::INC),
-            "INX" => Ok(OpcodeType::INX),
-            "INY" => Ok(OpcodeType::INY),
-            "JMP" => Ok(OpcodeType::JMP),
-            "JSR" => Ok(OpcodeType::JSR),
-            "LDA" => Ok(OpcodeType::LDA),
-            "LDX" => Ok(OpcodeType::LDX),
-            "LDY" => Ok(OpcodeType::LDY),
-            "LSR" => Ok(OpcodeType::LSR),
-            "NOP" => Ok(OpcodeType::NOP),
-            "ORA" => Ok(OpcodeType::ORA),
-            "PHA" => Ok(OpcodeType::PHA),
-            "PHP" => Ok(OpcodeType::PHP),
-            "PLA" => Ok(OpcodeType::PLA),
-            "PLP" => Ok(OpcodeType::PLP),
-            "ROL" => Ok(OpcodeType::ROL),
-            "ROR" => Ok(OpcodeType::ROR),
-            "RTI" => Ok(OpcodeType::RTI),
-            "RTS" => Ok(OpcodeType::RTS),
-            "SBC" => Ok(OpcodeType::SBC),
-            "SEC" => Ok(OpcodeType::SEC),
-            "SED" => Ok(OpcodeType::SED),
-            "SEI" => Ok(OpcodeType::SEI),
-            "STA" => Ok(OpcodeType::STA),
-            "STX" => Ok(OpcodeType::STX),
-            "STY" => Ok(OpcodeType::STY),
-            "TAX" => Ok(OpcodeType::TAX),
-            "TAY" => Ok(OpcodeType::TAY),
-            "TSX" => Ok(OpcodeType::TSX),
-            "TXA" => Ok(OpcodeType::TXA),
-            "TXS" => Ok(OpcodeType::TXS),
-            "TYA" => Ok(OpcodeType::TYA),
-            _ => Err(()),
+    pub fn identify<'s, S: std::ops::Deref<Target = &'s str>>(string: &S) -> Option<OpcodeType> {
+        // Mnemonics aren't case sensitive
+        let upper = string.to_ascii_uppercase();
+        let name = match &*upper {
+            // Aliases
+            "BLT" => "BCC",
+            "BGE" => "BCS",
+            // Other names of undocumented opcodes
+            "ASR" => "ALR",
+            "SBX" => "AXS",
+            "ISB" => "ISC",
+            name => name,
+        };
+        OpcodeType::from_mnemonic(name)
+    }
+    /// Identify the `ASLA`-style spellings of the accumulator shifts
+    pub fn identify_accumulator(string: &str) -> Option<OpcodeType> {
+        let upper = string.to_ascii_uppercase();
+        let name = upper.strip_suffix('A')?;
+        match OpcodeType::identify(&&name) {
+            Some(op) if op.is_accumulator_op() => Some(op),
+            _ => None,
         }
     }
+    /// Opcodes that work on the accumulator when they don't have an operand
+    pub fn is_accumulator_op(self) -> bool {
+        use OpcodeType::*;
+        let accumulator_ops = [ASL, LSR, ROL, ROR];
+        accumulator_ops.contains(&self)
+    }
+    /// Rockwell bit branches (`BBR0`-`BBS7`), they take a zero page address and a label
+    pub fn is_bit_branch_op(self) -> bool {
+        use OpcodeType::*;
+        let bit_branch_ops = [
+            BBR0, BBR1, BBR2, BBR3, BBR4, BBR5, BBR6, BBR7, BBS0, BBS1, BBS2, BBS3, BBS4, BBS5,
+            BBS6, BBS7,
+        ];
+        bit_branch_ops.contains(&self)
+    }
+    /// Opcodes whose immediate takes 2 bytes with a 16 bit accumulator (65816)
+    pub fn is_accumulator_sized(self) -> bool {
+        use OpcodeType::*;
+        let accumulator_ops = [ADC, AND, BIT, CMP, EOR, LDA, ORA, SBC];
+        accumulator_ops.contains(&self)
+    }
+    /// Opcodes whose immediate takes 2 bytes with 16 bit index registers (65816)
+    pub fn is_index_sized(self) -> bool {
+        use OpcodeType::*;
+        let index_ops = [CPX, CPY, LDX, LDY];
+        index_ops.contains(&self)
+    }
+    /// Opcodes that take a 16 bit relative address (65816)
+    pub fn is_long_relative_op(self) -> bool {
+        self == OpcodeType::BRL || self == OpcodeType::PER
+    }
+    /// Conditional branches and `BRA`, they take an 8 bit relative address.
+    /// `BRL` and the bit branches are covered by `is_long_relative_op` and `is_bit_branch_op`
     pub fn is_branch_op(self) -> bool {
         use OpcodeType::*;
-        let branch_ops = [BCC, BCS, BEQ, BMI, BNE, BPL, BVC, BVS];
+        let branch_ops = [BCC, BCS, BEQ, BMI, BNE, BPL, BVC, BVS, BRA];
         branch_ops.contains(&self)
     }
+    /// Unconditional jumps that don't come back (`JMP`, `JML`)
+    pub fn is_jump_op(self) -> bool {
+        self == OpcodeType::JMP || self == OpcodeType::JML
+    }
+    /// Subroutine calls (`JSR`, `JSL`)
+    pub fn is_call_op(self) -> bool {
+        self == OpcodeType::JSR || self == OpcodeType::JSL
+    }
+    /// Returns from a subroutine or an interrupt (`RTS`, `RTL`, `RTI`)
+    pub fn is_return_op(self) -> bool {
+        use OpcodeType::*;
+        let return_ops = [RTS, RTL, RTI];
+        return_ops.contains(&self)
+    }
+    /// Software interrupts (`BRK`, `COP`)
+    pub fn is_interrupt_op(self) -> bool {
+        self == OpcodeType::BRK || self == OpcodeType::COP
+    }
+    /// Whether the next instruction isn't always the one that follows this one
+    pub fn changes_flow(self) -> bool {
+        self.is_branch_op()
+            || self.is_bit_branch_op()
+            || self == OpcodeType::BRL
+            || self.is_jump_op()
+            || self.is_call_op()
+            || self.is_return_op()
+            || self.is_interrupt_op()
+    }
+    /// Flags of the status register the result depends on, as a mask of `flags`.
+    /// Ej.: `ADC` reads `flags::C | flags::D`, `BEQ` reads `flags::Z`
+    pub fn flags_read(self) -> u8 {
+        use flags::*;
+        use OpcodeType::*;
+        match self {
+            ADC | SBC | ARR | ISC | RRA => C | D,
+            ROL | ROR | RLA | BCC | BCS | XCE => C,
+            BEQ | BNE => Z,
+            BMI | BPL => N,
+            BVC | BVS => V,
+            PHP | BRK | COP => ALL,
+            _ => 0,
+        }
+    }
+    /// Flags of the status register it can change, as a mask of `flags`.
+    /// Ej.: `CMP` writes `flags::N | flags::Z | flags::C`
+    pub fn flags_written(self) -> u8 {
+        use flags::*;
+        use OpcodeType::*;
+        match self {
+            LDA | LDX | LDY | LAX | TAX | TAY | TSX | TXA | TYA | TXY | TYX | TCD | TDC | TSC
+            | XBA | PLA | PLX | PLY | PLB | PLD | INC | INX | INY | DEC | DEX | DEY | AND | ORA
+            | EOR => N | Z,
+            ASL | LSR | ROL | ROR | CMP | CPX | CPY | ALR | ANC | AXS | DCP | RLA | SLO | SRE => {
+                N | Z | C
+            }
+            ADC | SBC | ARR | ISC | RRA => N | V | Z | C,
+            // `BIT #` only changes Z
+            BIT => N | V | Z,
+            TRB | TSB => Z,
+            CLC | SEC => C,
+            CLD | SED => D,
+            CLI | SEI => I,
+            CLV => V,
+            // The 65C02 and the 65816 also clear D
+            BRK | COP => I | D,
+            XCE => C | M | X,
+            PLP | RTI | REP | SEP => ALL,
+            _ => 0,
+        }
+    }
+    /// The opcode for `mode` on `cpu`, with its size and cycles
+    pub fn opcode(self, mode: AddressingMode, cpu: Cpu) -> Option<&'static OpcodeData> {
+        cpu.codes()[self as usize][mode as usize].and_then(|code| get_opcode(code, cpu))
+    }
+    /// Every addressing mode it has on `cpu`
+    pub fn addressing_modes(self, cpu: Cpu) -> impl Iterator<Item = AddressingMode> {
+        cpu.codes()[self as usize]
+            .iter()
+            .filter_map(move |code| code.and_then(|code| get_opcode(code, cpu)))
+            .map(|opcode| opcode.addr_mode())
+    }
+}
+
+/// Bits of the status register, for `OpcodeType::flags_read` and `OpcodeType::flags_written`
+pub mod flags {
+    /// Carry
+    pub const C: u8 = 0x01;
+    /// Zero
+    pub const Z: u8 = 0x02;
+    /// Interrupt disable
+    pub const I: u8 = 0x04;
+    /// Decimal mode
+    pub const D: u8 = 0x08;
+    /// Index registers width on the 65816, break on the pushed copy of the others
+    pub const X: u8 = 0x10;
+    /// Accumulator width on the 65816, unused on the others
+    pub const M: u8 = 0x20;
+    /// Overflow
+    pub const V: u8 = 0x40;
+    /// Negative
+    pub const N: u8 = 0x80;
+    pub const ALL: u8 = 0xFF;
 }
-impl std::convert::Into<String> for OpcodeType {
-    fn into(self) -> String {
-        format!("{:?}", self)
+impl From<OpcodeType> for String {
+    fn from(opcode: OpcodeType) -> String {
+        format!("{:?}", opcode)
     }
 }
+/// An opcode of a CPU: instruction, addressing mode, size and timing
 #[derive(Debug)]
 pub struct OpcodeData {
     name: OpcodeType,
     addr_mode: AddressingMode,
+    /// Bytes taken by the opcode and its operand
+    size: u8,
+    /// Without the extra cycles of crossing a page or taking a branch
+    cycles: u8,
+    /// Extra cycles when the address crosses a page, or when the branch is taken
+    page_penalty: u8,
+}
+impl OpcodeData {
+    pub fn name(&self) -> OpcodeType {
+        self.name
+    }
+    pub fn addr_mode(&self) -> AddressingMode {
+        self.addr_mode
+    }
+    /// Bytes of the opcode and its operand
+    pub fn size(&self) -> u8 {
+        self.size
+    }
+    /// Cycles it takes, without the page crossing or branch penalty
+    pub fn cycles(&self) -> u8 {
+        self.cycles
+    }
+    /// Extra cycles when an indexed address crosses a page. Branches take
+    /// that many more when taken, and again when the target is on another page
+    pub fn page_penalty(&self) -> u8 {
+        self.page_penalty
+    }
 }
-
-pub const OPCODES: [Option<OpcodeData>; 256] = [
-    Some(OpcodeData {
-        name: OpcodeType::BRK,
-        addr_mode: AddressingMode::IMPL,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::ORA,
-        addr_mode: AddressingMode::INDX,
-    }),
-    None,
-    None,
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::ORA,
-        addr_mode: AddressingMode::ZPG,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::ASL,
-        addr_mode: AddressingMode::ZPG,
-    }),
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::PHP,
-        addr_mode: AddressingMode::IMPL,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::ORA,
-        addr_mode: AddressingMode::IMM,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::ASL,
-        addr_mode: AddressingMode::A,
-    }),
-    None,
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::ORA,
-        addr_mode: AddressingMode::ABS,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::ASL,
-        addr_mode: AddressingMode::ABS,
-    }),
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::BPL,
-        addr_mode: AddressingMode::REL,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::ORA,
-        addr_mode: AddressingMode::INDY,
-    }),
-    None,
-    None,
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::ORA,
-        addr_mode: AddressingMode::ZPGX,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::ASL,
-        addr_mode: AddressingMode::ZPGX,
-    }),
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::CLC,
-        addr_mode: AddressingMode::IMPL,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::ORA,
-        addr_mode: AddressingMode::ABSY,
-    }),
-    None,
-    None,
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::ORA,
-        addr_mode: AddressingMode::ABSX,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::ASL,
-        addr_mode: AddressingMode::ABSX,
-    }),
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::JSR,
-        addr_mode: AddressingMode::ABS,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::AND,
-        addr_mode: AddressingMode::INDX,
-    }),
-    None,
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::BIT,
-        addr_mode: AddressingMode::ZPG,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::AND,
-        addr_mode: AddressingMode::ZPG,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::ROL,
-        addr_mode: AddressingMode::ZPG,
-    }),
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::PLP,
-        addr_mode: AddressingMode::IMPL,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::AND,
-        addr_mode: AddressingMode::IMM,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::ROL,
-        addr_mode: AddressingMode::A,
-    }),
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::BIT,
-        addr_mode: AddressingMode::ABS,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::AND,
-        addr_mode: AddressingMode::ABS,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::ROL,
-        addr_mode: AddressingMode::ABS,
-    }),
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::BMI,
-        addr_mode: AddressingMode::REL,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::AND,
-        addr_mode: AddressingMode::INDY,
-    }),
-    None,
-    None,
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::AND,
-        addr_mode: AddressingMode::ZPGX,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::ROL,
-        addr_mode: AddressingMode::ZPGX,
-    }),
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::SEC,
-        addr_mode: AddressingMode::IMPL,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::AND,
-        addr_mode: AddressingMode::ABSY,
-    }),
-    None,
-    None,
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::AND,
-        addr_mode: AddressingMode::ABSX,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::ROL,
-        addr_mode: AddressingMode::ABSX,
-    }),
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::RTI,
-        addr_mode: AddressingMode::IMPL,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::EOR,
-        addr_mode: AddressingMode::INDX,
-    }),
-    None,
-    None,
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::EOR,
-        addr_mode: AddressingMode::ZPG,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::LSR,
-        addr_mode: AddressingMode::ZPG,
-    }),
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::PHA,
-        addr_mode: AddressingMode::IMPL,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::EOR,
-        addr_mode: AddressingMode::IMM,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::LSR,
-        addr_mode: AddressingMode::A,
-    }),
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::JMP,
-        addr_mode: AddressingMode::ABS,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::EOR,
-        addr_mode: AddressingMode::ABS,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::LSR,
-        addr_mode: AddressingMode::ABS,
-    }),
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::BVC,
-        addr_mode: AddressingMode::REL,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::EOR,
-        addr_mode: AddressingMode::INDY,
-    }),
-    None,
-    None,
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::EOR,
-        addr_mode: AddressingMode::ZPGX,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::LSR,
-        addr_mode: AddressingMode::ZPGX,
-    }),
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::CLI,
-        addr_mode: AddressingMode::IMPL,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::EOR,
-        addr_mode: AddressingMode::ABSY,
-    }),
-    None,
-    None,
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::EOR,
-        addr_mode: AddressingMode::ABSX,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::LSR,
-        addr_mode: AddressingMode::ABSX,
-    }),
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::RTS,
-        addr_mode: AddressingMode::IMPL,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::ADC,
-        addr_mode: AddressingMode::INDX,
-    }),
-    None,
-    None,
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::ADC,
-        addr_mode: AddressingMode::ZPG,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::ROR,
-        addr_mode: AddressingMode::ZPG,
-    }),
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::PLA,
-        addr_mode: AddressingMode::IMPL,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::ADC,
-        addr_mode: AddressingMode::IMM,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::ROR,
-        addr_mode: AddressingMode::A,
-    }),
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::JMP,
-        addr_mode: AddressingMode::IND,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::ADC,
-        addr_mode: AddressingMode::ABS,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::ROR,
-        addr_mode: AddressingMode::ABS,
-    }),
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::BVS,
-        addr_mode: AddressingMode::REL,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::ADC,
-        addr_mode: AddressingMode::INDY,
-    }),
-    None,
-    None,
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::ADC,
-        addr_mode: AddressingMode::ZPGX,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::ROR,
-        addr_mode: AddressingMode::ZPGX,
-    }),
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::SEI,
-        addr_mode: AddressingMode::IMPL,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::ADC,
-        addr_mode: AddressingMode::ABSY,
-    }),
-    None,
-    None,
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::ADC,
-        addr_mode: AddressingMode::ABSX,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::ROR,
-        addr_mode: AddressingMode::ABSX,
-    }),
-    None,
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::STA,
-        addr_mode: AddressingMode::INDX,
-    }),
-    None,
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::STY,
-        addr_mode: AddressingMode::ZPG,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::STA,
-        addr_mode: AddressingMode::ZPG,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::STX,
-        addr_mode: AddressingMode::ZPG,
-    }),
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::DEY,
-        addr_mode: AddressingMode::IMPL,
-    }),
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::TXA,
-        addr_mode: AddressingMode::IMPL,
-    }),
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::STY,
-        addr_mode: AddressingMode::ABS,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::STA,
-        addr_mode: AddressingMode::ABS,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::STX,
-        addr_mode: AddressingMode::ABS,
-    }),
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::BCC,
-        addr_mode: AddressingMode::REL,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::STA,
-        addr_mode: AddressingMode::INDY,
-    }),
-    None,
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::STY,
-        addr_mode: AddressingMode::ZPGX,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::STA,
-        addr_mode: AddressingMode::ZPGX,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::STX,
-        addr_mode: AddressingMode::ZPGY,
-    }),
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::TYA,
-        addr_mode: AddressingMode::IMPL,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::STA,
-        addr_mode: AddressingMode::ABSY,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::TXS,
-        addr_mode: AddressingMode::IMPL,
-    }),
-    None,
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::STA,
-        addr_mode: AddressingMode::ABSX,
-    }),
-    None,
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::LDY,
-        addr_mode: AddressingMode::IMM,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::LDA,
-        addr_mode: AddressingMode::INDX,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::LDX,
-        addr_mode: AddressingMode::IMM,
-    }),
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::LDY,
-        addr_mode: AddressingMode::ZPG,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::LDA,
-        addr_mode: AddressingMode::ZPG,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::LDX,
-        addr_mode: AddressingMode::ZPG,
-    }),
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::TAY,
-        addr_mode: AddressingMode::IMPL,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::LDA,
-        addr_mode: AddressingMode::IMM,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::TAX,
-        addr_mode: AddressingMode::IMPL,
-    }),
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::LDY,
-        addr_mode: AddressingMode::ABS,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::LDA,
-        addr_mode: AddressingMode::ABS,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::LDX,
-        addr_mode: AddressingMode::ABS,
-    }),
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::BCS,
-        addr_mode: AddressingMode::REL,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::LDA,
-        addr_mode: AddressingMode::INDY,
-    }),
-    None,
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::LDY,
-        addr_mode: AddressingMode::ZPGX,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::LDA,
-        addr_mode: AddressingMode::ZPGX,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::LDX,
-        addr_mode: AddressingMode::ZPGY,
-    }),
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::CLV,
-        addr_mode: AddressingMode::IMPL,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::LDA,
-        addr_mode: AddressingMode::ABSY,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::TSX,
-        addr_mode: AddressingMode::IMPL,
-    }),
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::LDY,
-        addr_mode: AddressingMode::ABSX,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::LDA,
-        addr_mode: AddressingMode::ABSX,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::LDX,
-        addr_mode: AddressingMode::ABSY,
-    }),
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::CPY,
-        addr_mode: AddressingMode::IMM,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::CMP,
-        addr_mode: AddressingMode::INDX,
-    }),
-    None,
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::CPY,
-        addr_mode: AddressingMode::ZPG,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::CMP,
-        addr_mode: AddressingMode::ZPG,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::DEC,
-        addr_mode: AddressingMode::ZPG,
-    }),
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::INY,
-        addr_mode: AddressingMode::IMPL,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::CMP,
-        addr_mode: AddressingMode::IMM,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::DEX,
-        addr_mode: AddressingMode::IMPL,
-    }),
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::CPY,
-        addr_mode: AddressingMode::ABS,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::CMP,
-        addr_mode: AddressingMode::ABS,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::DEC,
-        addr_mode: AddressingMode::ABS,
-    }),
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::BNE,
-        addr_mode: AddressingMode::REL,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::CMP,
-        addr_mode: AddressingMode::INDY,
-    }),
-    None,
-    None,
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::CMP,
-        addr_mode: AddressingMode::ZPGX,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::DEC,
-        addr_mode: AddressingMode::ZPGX,
-    }),
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::CLD,
-        addr_mode: AddressingMode::IMPL,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::CMP,
-        addr_mode: AddressingMode::ABSY,
-    }),
-    None,
-    None,
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::CMP,
-        addr_mode: AddressingMode::ABSX,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::DEC,
-        addr_mode: AddressingMode::ABSX,
-    }),
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::CPX,
-        addr_mode: AddressingMode::IMM,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::SBC,
-        addr_mode: AddressingMode::INDX,
-    }),
-    None,
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::CPX,
-        addr_mode: AddressingMode::ZPG,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::SBC,
-        addr_mode: AddressingMode::ZPG,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::INC,
-        addr_mode: AddressingMode::ZPG,
-    }),
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::INX,
-        addr_mode: AddressingMode::IMPL,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::SBC,
-        addr_mode: AddressingMode::IMM,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::NOP,
-        addr_mode: AddressingMode::IMPL,
-    }),
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::CPX,
-        addr_mode: AddressingMode::ABS,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::SBC,
-        addr_mode: AddressingMode::ABS,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::INC,
-        addr_mode: AddressingMode::ABS,
-    }),
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::BEQ,
-        addr_mode: AddressingMode::REL,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::SBC,
-        addr_mode: AddressingMode::INDY,
-    }),
-    None,
-    None,
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::SBC,
-        addr_mode: AddressingMode::ZPGX,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::INC,
-        addr_mode: AddressingMode::ZPGX,
-    }),
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::SED,
-        addr_mode: AddressingMode::IMPL,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::SBC,
-        addr_mode: AddressingMode::ABSY,
-    }),
-    None,
-    None,
-    None,
-    Some(OpcodeData {
-        name: OpcodeType::SBC,
-        addr_mode: AddressingMode::ABSX,
-    }),
-    Some(OpcodeData {
-        name: OpcodeType::INC,
-        addr_mode: AddressingMode::ABSX,
-    }),
-    None,
-];
 
 mod test {
     #[test]
     fn test_opcode_name() {
         use super::OpcodeType;
-        let strings = vec![("LDA", true), ("STA", true), ("JMP", true), ("xd", false)];
+        let strings = [
+            ("LDA", true),
+            ("STA", true),
+            ("JMP", true),
+            ("lda", true),
+            ("Sta", true),
+            ("xd", false),
+            ("BLT", true),
+            ("bge", true),
+        ];
         for (string, is_ok) in strings.iter() {
             let res = OpcodeType::identify(&string);
             println!("{} -> {:?}", string, res);
-            assert_eq!(res.is_ok(), *is_ok);
+            assert_eq!(res.is_some(), *is_ok);
+        }
+        assert_eq!(OpcodeType::identify(&&"BLT"), Some(OpcodeType::BCC));
+        assert_eq!(OpcodeType::identify(&&"BGE"), Some(OpcodeType::BCS));
+        assert_eq!(
+            OpcodeType::identify_accumulator("asla"),
+            Some(OpcodeType::ASL)
+        );
+        assert_eq!(OpcodeType::identify_accumulator("LDAA"), None);
+        assert_eq!(OpcodeType::identify_accumulator("ROL"), None);
+    }
+    #[test]
+    fn test_get_code() {
+        use super::{get_code, get_opcode, Cpu, OpcodeType, OPCODES_6502};
+        use crate::addressing_modes::get_size;
+        use crate::addressing_modes::AddressingMode;
+        let tests = [
+            (
+                OpcodeType::LDA,
+                AddressingMode::IMM,
+                Cpu::Mos6502,
+                Some(0xA9),
+            ),
+            (
+                OpcodeType::LDA,
+                AddressingMode::IMM,
+                Cpu::Wdc65C02,
+                Some(0xA9),
+            ),
+            (OpcodeType::STZ, AddressingMode::ZPG, Cpu::Mos6502, None),
+            (
+                OpcodeType::STZ,
+                AddressingMode::ZPG,
+                Cpu::Wdc65C02,
+                Some(0x64),
+            ),
+            (
+                OpcodeType::LDA,
+                AddressingMode::ZPGIND,
+                Cpu::Wdc65C02,
+                Some(0xB2),
+            ),
+            (
+                OpcodeType::JMP,
+                AddressingMode::ABSINDX,
+                Cpu::Wdc65C02,
+                Some(0x7C),
+            ),
+            (
+                OpcodeType::INC,
+                AddressingMode::A,
+                Cpu::Wdc65C02,
+                Some(0x1A),
+            ),
+            (
+                OpcodeType::BBS7,
+                AddressingMode::ZPGREL,
+                Cpu::Wdc65C02,
+                Some(0xFF),
+            ),
+            (
+                OpcodeType::RMB2,
+                AddressingMode::ZPG,
+                Cpu::Wdc65C02,
+                Some(0x27),
+            ),
+        ];
+        for (name, mode, cpu, exp) in tests.iter() {
+            assert_eq!(get_code(*name, *mode, *cpu).ok(), *exp);
+        }
+        assert_eq!(
+            get_code(OpcodeType::LAX, AddressingMode::ZPG, Cpu::Mos6502X).ok(),
+            Some(0xA7)
+        );
+        match get_code(OpcodeType::LAX, AddressingMode::ZPG, Cpu::Mos6502) {
+            Err(e) => assert!(format!("{}", e).contains("6502x")),
+            Ok(_) => panic!("LAX shouldn't be available on the 6502"),
+        }
+        // The other CPUs only use slots the 6502 left empty
+        for (code, opcode) in OPCODES_6502.iter().enumerate() {
+            if let Some(opcode) = opcode {
+                for cpu in Cpu::ALL.iter() {
+                    let other = get_opcode(code as u8, *cpu).unwrap();
+                    assert_eq!(other.name(), opcode.name(), "{:#04X} on {}", code, cpu);
+                    assert_eq!(other.addr_mode(), opcode.addr_mode());
+                }
+            }
+        }
+        // The table agrees with itself and with the size of each addressing mode
+        for cpu in Cpu::ALL.iter() {
+            for code in 0..=0xFF {
+                if let Some(opcode) = get_opcode(code, *cpu) {
+                    assert_eq!(opcode.size() as usize, get_size(opcode.addr_mode()));
+                    let same = get_opcode(
+                        get_code(opcode.name(), opcode.addr_mode(), *cpu).unwrap(),
+                        *cpu,
+                    )
+                    .unwrap();
+                    assert_eq!(same.name(), opcode.name());
+                    assert_eq!(same.addr_mode(), opcode.addr_mode());
+                }
+            }
         }
+        assert_eq!(
+            get_opcode(0xBD, Cpu::Mos6502).map(|o| o.page_penalty()),
+            Some(1)
+        );
+        assert_eq!(
+            get_opcode(0x9D, Cpu::Mos6502).map(|o| o.page_penalty()),
+            Some(0)
+        );
+        assert_eq!(get_opcode(0xA7, Cpu::Mos6502X).map(|o| o.cycles()), Some(3));
+        assert_eq!(get_opcode(0xA7, Cpu::Mos6502).map(|o| o.cycles()), None);
+        // `$EB` runs as `SBC #imm`, but `SBC #imm` is still assembled as `$E9`
+        assert_eq!(
+            get_opcode(0xEB, Cpu::Mos6502X).map(|o| o.name()),
+            Some(OpcodeType::SBC)
+        );
+        assert_eq!(
+            get_code(OpcodeType::SBC, AddressingMode::IMM, Cpu::Mos6502X).ok(),
+            Some(0xE9)
+        );
+        assert_eq!(
+            get_opcode(0xEA, Cpu::Mos6502).map(|o| o.name()),
+            Some(OpcodeType::NOP)
+        );
+        // Every opcode of the 65816 means something
+        for code in 0..=0xFF {
+            assert!(get_opcode(code, Cpu::Wdc65816).is_some(), "{:#04X}", code);
+        }
+        assert_eq!(
+            get_code(OpcodeType::LDA, AddressingMode::DPINDLY, Cpu::Wdc65816).ok(),
+            Some(0xB7)
+        );
+        assert_eq!(
+            get_code(OpcodeType::BBR0, AddressingMode::ZPGREL, Cpu::Wdc65816).ok(),
+            None
+        );
+        assert_eq!("6502X".parse(), Ok(Cpu::Mos6502X));
+        match get_code(OpcodeType::STZ, AddressingMode::ZPG, Cpu::Ricoh2A03) {
+            Err(e) => assert!(format!("{}", e).contains("STZ is a 65c02 instruction")),
+            Ok(_) => panic!("STZ shouldn't be available on the 2A03"),
+        }
+        assert_eq!("65C02".parse(), Ok(Cpu::Wdc65C02));
+        assert!("z80".parse::<Cpu>().is_err());
+    }
+    #[test]
+    fn test_metadata() {
+        use super::{flags, Cpu, OpcodeType};
+        use crate::addressing_modes::AddressingMode;
+        let modes: Vec<_> = OpcodeType::JMP.addressing_modes(Cpu::Mos6502).collect();
+        assert_eq!(modes, vec![AddressingMode::ABS, AddressingMode::IND]);
+        assert_eq!(OpcodeType::JMP.addressing_modes(Cpu::Wdc65C02).count(), 3);
+        assert_eq!(OpcodeType::STZ.addressing_modes(Cpu::Mos6502).count(), 0);
+        let lda = OpcodeType::LDA
+            .opcode(AddressingMode::ABSX, Cpu::Mos6502)
+            .unwrap();
+        assert_eq!((lda.size(), lda.cycles(), lda.page_penalty()), (3, 4, 1));
+        assert!(OpcodeType::LAX
+            .opcode(AddressingMode::ZPG, Cpu::Mos6502)
+            .is_none());
+        assert_eq!(OpcodeType::ADC.flags_read(), flags::C | flags::D);
+        assert_eq!(
+            OpcodeType::CMP.flags_written(),
+            flags::N | flags::Z | flags::C
+        );
+        assert_eq!(OpcodeType::STA.flags_written(), 0);
+        assert_eq!(OpcodeType::BEQ.flags_read(), flags::Z);
+        assert!(OpcodeType::BEQ.is_branch_op() && OpcodeType::BEQ.changes_flow());
+        assert!(OpcodeType::JML.is_jump_op());
+        assert!(OpcodeType::JSR.is_call_op());
+        assert!(OpcodeType::RTI.is_return_op());
+        assert!(OpcodeType::BRK.is_interrupt_op());
+        assert!(OpcodeType::BBR3.changes_flow());
+        assert!(!OpcodeType::LDA.changes_flow());
     }
 }
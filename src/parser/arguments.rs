@@ -1,26 +1,30 @@
-use super::{bin_to_hex, eof, is_symbol, u8_to_hex};
-use super::{AddressingMode, ArgumentType, Value};
+use super::expressions::expression;
+use super::{bin_to_hex, comma, is_symbol, label_identifier, line_end, u8_to_hex};
+use super::{
+    AddressingMode, ArgumentType, Assertion, Comparison, Expectation, Expr, Target, Value,
+};
 use crate::nom;
 use nom::{bytes::complete as bytes, character, combinator, sequence, IResult};
 use std::convert::TryFrom;
 
 // #region Parsers
 fn a(input: &[u8]) -> IResult<&[u8], ArgumentType> {
-    let (input, _) = character::complete::char('A')(input)?;
+    let (input, _) = character::complete::one_of("Aa")(input)?;
+    let (input, _) = line_end(input)?;
     Ok((input, (AddressingMode::A, Value::None)))
 }
 
 fn hex_addr_short(input: &[u8]) -> IResult<&[u8], ArgumentType> {
     let (input, _) = character::complete::char('$')(input)?;
     let (input, value) = combinator::map_res(bytes::take(2usize), u8_to_hex)(input)?;
-    let (input, _) = eof(input)?;
+    let (input, _) = line_end(input)?;
     Ok((input, (AddressingMode::ZPG, Value::Short(value as u8))))
 }
 
 fn hex_addr_long(input: &[u8]) -> IResult<&[u8], ArgumentType> {
     let (input, _) = character::complete::char('$')(input)?;
     let (input, value) = combinator::map_res(bytes::take(4usize), u8_to_hex)(input)?;
-    let (input, _) = eof(input)?;
+    let (input, _) = line_end(input)?;
     Ok((input, (AddressingMode::ABS, Value::Long(value as u16))))
 }
 
@@ -30,11 +34,12 @@ named!(
     do_parse!(
         char!('$')
             >> addr: map_res!(take!(4usize),u8_to_hex)
-            >> char!(',')
+            >> call!(comma)
             >> reg: alt!(
-                char!('X')  => { |_| AddressingMode::ABSX } |
-                char!('Y')  => { |_| AddressingMode::ABSY }
+                one_of!("Xx")  => { |_| AddressingMode::ABSX } |
+                one_of!("Yy")  => { |_| AddressingMode::ABSY }
             )
+            >> call!(line_end)
             >> ( (reg, Value::Long(addr as u16)) )
     )
 );
@@ -43,7 +48,7 @@ fn hex_value(input: &[u8]) -> IResult<&[u8], ArgumentType> {
     let (input, _) = character::complete::char('#')(input)?;
     let (input, _) = character::complete::char('$')(input)?;
     let (input, value) = combinator::map_res(bytes::take(2usize), u8_to_hex)(input)?;
-    let (input, _) = eof(input)?;
+    let (input, _) = line_end(input)?;
     Ok((input, (AddressingMode::IMM, Value::Short(value as u8))))
 }
 
@@ -51,56 +56,217 @@ fn bin_value(input: &[u8]) -> IResult<&[u8], ArgumentType> {
     let (input, _) = character::complete::char('#')(input)?;
     let (input, _) = character::complete::char('%')(input)?;
     let (input, value) = combinator::map_res(bytes::take(8usize), bin_to_hex)(input)?;
-    let (input, _) = eof(input)?;
+    let (input, _) = line_end(input)?;
     Ok((input, (AddressingMode::IMM, Value::Short(value as u8))))
 }
 
 fn indexed_indirect(input: &[u8]) -> IResult<&[u8], ArgumentType> {
     let (input, _) = character::complete::char('(')(input)?;
+    let (input, _) = character::complete::space0(input)?;
     let (input, _) = character::complete::char('$')(input)?;
     let (input, value) = combinator::map_res(bytes::take(2usize), u8_to_hex)(input)?;
-    let (input, _) = bytes::tag(",X")(input)?;
+    let (input, _) = comma(input)?;
+    let (input, _) = character::complete::one_of("Xx")(input)?;
+    let (input, _) = character::complete::space0(input)?;
     let (input, _) = character::complete::char(')')(input)?;
+    let (input, _) = line_end(input)?;
     Ok((input, (AddressingMode::INDX, Value::Short(value as u8))))
 }
 
-// TODO: Improve label recognition
 fn label_name(input: &[u8]) -> IResult<&[u8], ArgumentType> {
-    let (input, value) = combinator::map_res(character::complete::alphanumeric1, |s: &[u8]| {
-        String::from_utf8(s.to_vec())
-    })(input)?;
-    let (input, _) = eof(input)?;
+    let (input, value) = label_identifier(input)?;
+    let (input, _) = line_end(input)?;
     Ok((input, (AddressingMode::ABS, Value::Label(value))))
 }
 
+/// Ej.: `"Hello world"`, without the quotes
+fn string(input: &[u8]) -> IResult<&[u8], &[u8]> {
+    sequence::delimited(
+        character::complete::char('"'),
+        bytes::take_while(|c: u8| {
+            c != b'"' && (c.is_ascii_alphanumeric() || is_symbol(c) || c == b' ')
+        }),
+        character::complete::char('"'),
+    )(input)
+}
+
 // text
 named!(
     text<&[u8], ArgumentType>,
     do_parse!(
-        text: delimited!(char!('\"'), take_while!(|c: u8| c!=b'"'&&(c.is_ascii_alphanumeric()||is_symbol(c))) , char!('\"'))
-        >> eof!()
+        text: call!(string)
+        >> call!(line_end)
         >> ((AddressingMode::ABS, Value::Text(Box::from(text))))
     )
 );
 
 fn array(input: &[u8]) -> IResult<&[u8], ArgumentType> {
-    let values: Vec<&[u8]> = input
-        .split(|c| c == &b',')
-        .map(|c: &[u8]| match c[0] {
-            b'$' => Ok(&c[1..]),
-            b' ' => Ok(&c[2..]),
-            _ => Err(nom::Err::Error((c, nom::error::ErrorKind::Char))),
-        })
-        .collect::<Result<Vec<&[u8]>, _>>()?;
-    if let Some(last) = values.last() {
-        if last.len() > 2 {
-            return Err(nom::Err::Error((last, nom::error::ErrorKind::Eof)));
-        } else if last.len() < 2 {
-            return Err(nom::Err::Error((last, nom::error::ErrorKind::Complete)));
+    let (input, values) = nom::multi::separated_nonempty_list(
+        comma,
+        sequence::preceded(
+            character::complete::char('$'),
+            bytes::take_while_m_n(2, 2, nom::character::is_hex_digit),
+        ),
+    )(input)?;
+    let (input, _) = line_end(input)?;
+    Ok((
+        input,
+        (AddressingMode::ABS, Value::try_from(values).unwrap()),
+    ))
+}
+
+// #region Expressions
+/// Turn an expression into a `Value`, folding it if it's a constant
+fn expr_to_value(expr: Expr, short: bool) -> Value {
+    match expr.constant() {
+        Some(value) if short => Value::Short((value & 0xFF) as u8),
+        Some(value) => Value::Long((value & 0xFFFF) as u16),
+        None => Value::Expr(expr),
+    }
+}
+
+/// Constant expressions that fit in a byte use the zero page modes
+fn is_zero_page(expr: &Expr) -> bool {
+    match expr.constant() {
+        Some(value) => (0..=0xFF).contains(&value),
+        None => false,
+    }
+}
+
+fn is_in_range(expr: &Expr, min: isize, max: isize) -> bool {
+    match expr.constant() {
+        Some(value) => value >= min && value <= max,
+        None => true,
+    }
+}
+
+fn immediate_expr(input: &[u8]) -> IResult<&[u8], ArgumentType> {
+    let (input, _) = character::complete::char('#')(input)?;
+    let (input, _) = character::complete::space0(input)?;
+    // 16 bit registers of the 65816 take 2 bytes, so the assembler checks the size
+    let (input, expr) = combinator::verify(expression, |e| is_in_range(e, -0x8000, 0xFFFF))(input)?;
+    let (input, _) = line_end(input)?;
+    let value = if is_zero_page(&expr) {
+        expr_to_value(expr, true)
+    } else {
+        Value::Expr(expr)
+    };
+    Ok((input, (AddressingMode::IMM, value)))
+}
+
+fn indirect_expr(input: &[u8]) -> IResult<&[u8], ArgumentType> {
+    let (input, _) = character::complete::char('(')(input)?;
+    let (input, _) = character::complete::space0(input)?;
+    let (input, expr) = combinator::verify(expression, |e| is_in_range(e, 0, 0xFFFF))(input)?;
+    let (input, _) = character::complete::space0(input)?;
+    let (input, mode) = nom::branch::alt((
+        combinator::map(
+            sequence::tuple((
+                comma,
+                character::complete::one_of("Ss"),
+                character::complete::space0,
+                character::complete::char(')'),
+                comma,
+                character::complete::one_of("Yy"),
+            )),
+            |_| AddressingMode::SRINDY,
+        ),
+        combinator::map(
+            sequence::tuple((
+                comma,
+                character::complete::one_of("Xx"),
+                character::complete::space0,
+                character::complete::char(')'),
+            )),
+            |_| AddressingMode::INDX,
+        ),
+        combinator::map(
+            sequence::tuple((
+                character::complete::char(')'),
+                comma,
+                character::complete::one_of("Yy"),
+            )),
+            |_| AddressingMode::INDY,
+        ),
+        combinator::map(character::complete::char(')'), |_| AddressingMode::IND),
+    ))(input)?;
+    let (input, _) = line_end(input)?;
+    // `JMP ($1234,X)` is the only one that can take a long address
+    let mode = match mode {
+        AddressingMode::INDX if !is_in_range(&expr, 0, 0xFF) => AddressingMode::ABSINDX,
+        AddressingMode::INDY | AddressingMode::SRINDY if !is_in_range(&expr, 0, 0xFF) => {
+            return Err(nom::Err::Error((input, nom::error::ErrorKind::Verify)))
         }
+        mode => mode,
+    };
+    let short = mode != AddressingMode::IND && mode != AddressingMode::ABSINDX;
+    Ok((input, (mode, expr_to_value(expr, short))))
+}
+
+fn direct_expr(input: &[u8]) -> IResult<&[u8], ArgumentType> {
+    let (input, expr) = combinator::verify(expression, |e| is_in_range(e, 0, 0xFF_FFFF))(input)?;
+    let zero_page = is_zero_page(&expr);
+    // Addresses after the first bank are only reachable with the long modes of the 65816
+    let far = !is_in_range(&expr, 0, 0xFFFF);
+    let (input, index) = combinator::opt(sequence::preceded(
+        comma,
+        character::complete::one_of("XYSxys"),
+    ))(input)?;
+    let (input, _) = line_end(input)?;
+    let index = index.map(|c| c.to_ascii_uppercase());
+    let mode = match (index, zero_page, far) {
+        (None, _, true) => AddressingMode::ABSL,
+        (Some('X'), _, true) => AddressingMode::ABSLX,
+        (None, true, _) => AddressingMode::ZPG,
+        (None, false, _) => AddressingMode::ABS,
+        (Some('X'), true, _) => AddressingMode::ZPGX,
+        (Some('X'), false, _) => AddressingMode::ABSX,
+        (Some('S'), _, false) => AddressingMode::SR,
+        (Some('Y'), true, _) => AddressingMode::ZPGY,
+        (Some('Y'), false, false) => AddressingMode::ABSY,
+        _ => return Err(nom::Err::Error((input, nom::error::ErrorKind::Verify))),
+    };
+    let value = match expr.constant() {
+        Some(value) if far => Value::Far(value as u32),
+        _ => expr_to_value(expr, zero_page || mode == AddressingMode::SR),
+    };
+    Ok((input, (mode, value)))
+}
+
+/// Ej.: `[$10]` or `[$10],Y`
+fn indirect_long_expr(input: &[u8]) -> IResult<&[u8], ArgumentType> {
+    let (input, _) = character::complete::char('[')(input)?;
+    let (input, _) = character::complete::space0(input)?;
+    let (input, expr) = combinator::verify(expression, |e| is_in_range(e, 0, 0xFFFF))(input)?;
+    let (input, _) = character::complete::space0(input)?;
+    let (input, _) = character::complete::char(']')(input)?;
+    let (input, index) =
+        combinator::opt(sequence::preceded(comma, character::complete::one_of("Yy")))(input)?;
+    let (input, _) = line_end(input)?;
+    // `JML [$1234]` is the only one that can take a long address
+    let mode = match index {
+        Some(_) => AddressingMode::DPINDLY,
+        None if is_in_range(&expr, 0, 0xFF) => AddressingMode::DPINDL,
+        None => AddressingMode::ABSINDL,
+    };
+    if mode == AddressingMode::DPINDLY && !is_in_range(&expr, 0, 0xFF) {
+        return Err(nom::Err::Error((input, nom::error::ErrorKind::Verify)));
     }
-    Ok((&[], (AddressingMode::ABS, Value::try_from(values).unwrap())))
+    Ok((
+        input,
+        (mode, expr_to_value(expr, mode != AddressingMode::ABSINDL)),
+    ))
+}
+
+/// Ej.: `$10, label`
+fn expr_pair(input: &[u8]) -> IResult<&[u8], ArgumentType> {
+    let (input, first) = expression(input)?;
+    let (input, _) = comma(input)?;
+    let (input, second) = expression(input)?;
+    let (input, _) = line_end(input)?;
+    Ok((input, (AddressingMode::ZPGREL, Value::Pair(first, second))))
 }
+// #endregion
 //#endregion
 /// Parse argument
 fn argument(input: &[u8]) -> IResult<&[u8], ArgumentType> {
@@ -115,17 +281,133 @@ fn argument(input: &[u8]) -> IResult<&[u8], ArgumentType> {
         array,
         label_name,
         text,
+        immediate_expr,
+        indirect_expr,
+        indirect_long_expr,
+        direct_expr,
+        expr_pair,
     ))(input)
 }
 
-pub fn parse_argument(input: &[u8]) -> IResult<&[u8], ArgumentType> {
-    if input.is_empty() {
-        return Ok((input, (AddressingMode::IMPL, Value::None)));
+/// Ej.: `A`, `PC`, `C` or `mem[$0200]`
+fn expectation_target(input: &[u8]) -> IResult<&[u8], Target> {
+    use crate::opcodes::flags;
+    let memory = sequence::delimited(
+        sequence::pair(bytes::tag_no_case("mem"), character::complete::space0),
+        sequence::delimited(
+            sequence::pair(character::complete::char('['), character::complete::space0),
+            expression,
+            sequence::pair(character::complete::space0, character::complete::char(']')),
+        ),
+        character::complete::space0,
+    );
+    if let Ok((input, addr)) = memory(input) {
+        return Ok((input, Target::Memory(addr)));
     }
-    let (input, _) = character::complete::char(' ')(input)?;
-    if input.is_empty() {
+    let (input, name) = character::complete::alpha1(input)?;
+    let target = match &*name.to_ascii_uppercase() {
+        b"A" => Target::A,
+        b"X" => Target::X,
+        b"Y" => Target::Y,
+        b"S" => Target::S,
+        b"P" => Target::P,
+        b"PC" => Target::PC,
+        b"C" => Target::Flag(flags::C),
+        b"Z" => Target::Flag(flags::Z),
+        b"I" => Target::Flag(flags::I),
+        b"D" => Target::Flag(flags::D),
+        b"V" => Target::Flag(flags::V),
+        b"N" => Target::Flag(flags::N),
+        _ => return Err(nom::Err::Error((input, nom::error::ErrorKind::Tag))),
+    };
+    Ok((input, target))
+}
+
+/// Ej.: `==` or `<=`
+fn comparison(input: &[u8]) -> IResult<&[u8], Comparison> {
+    nom::branch::alt((
+        combinator::map(bytes::tag("=="), |_| Comparison::Equal),
+        combinator::map(bytes::tag("!="), |_| Comparison::NotEqual),
+        combinator::map(bytes::tag("<="), |_| Comparison::LessOrEqual),
+        combinator::map(bytes::tag(">="), |_| Comparison::GreaterOrEqual),
+        combinator::map(bytes::tag("<"), |_| Comparison::Less),
+        combinator::map(bytes::tag(">"), |_| Comparison::Greater),
+    ))(input)
+}
+
+/// Ej.: `A == $05`
+fn expectation(input: &[u8]) -> IResult<&[u8], Expectation> {
+    let start = input;
+    let (input, target) = expectation_target(input)?;
+    let (input, _) = character::complete::space0(input)?;
+    let (input, comparison) = comparison(input)?;
+    let (input, _) = character::complete::space0(input)?;
+    let (input, value) = expression(input)?;
+    let text = String::from_utf8_lossy(&start[..start.len() - input.len()]).into_owned();
+    Ok((
+        input,
+        Expectation {
+            target,
+            comparison,
+            value,
+            text,
+        },
+    ))
+}
+
+/// The argument of `.expect`, Ej.: ` A == $05, mem[$0200] == $FF`
+pub fn parse_expectations(input: &[u8]) -> IResult<&[u8], Value> {
+    let (input, _) = character::complete::space1(input)?;
+    let (input, expectations) = nom::multi::separated_nonempty_list(comma, expectation)(input)?;
+    let (input, _) = line_end(input)?;
+    Ok((input, Value::Expectations(expectations)))
+}
+
+/// The argument of `.assert`, Ej.: ` end - table <= 256, "The table doesn't fit in a page"`
+pub fn parse_assertion(input: &[u8]) -> IResult<&[u8], Value> {
+    let (input, _) = character::complete::space1(input)?;
+    let start = input;
+    let (input, lhs) = expression(input)?;
+    let (input, check) = combinator::opt(sequence::pair(
+        sequence::delimited(
+            character::complete::space0,
+            comparison,
+            character::complete::space0,
+        ),
+        expression,
+    ))(input)?;
+    let text = String::from_utf8_lossy(&start[..start.len() - input.len()]).into_owned();
+    let (input, message) = combinator::opt(sequence::preceded(comma, string))(input)?;
+    let (input, _) = line_end(input)?;
+    let message = message.map(|m| String::from_utf8_lossy(m).into_owned());
+    Ok((
+        input,
+        Value::Assertion(Assertion {
+            lhs,
+            check,
+            text,
+            message,
+        }),
+    ))
+}
+
+/// The argument of `.print`: texts and expressions, Ej.: ` "size: ", end - start`
+pub fn parse_print(input: &[u8]) -> IResult<&[u8], Value> {
+    let (input, _) = character::complete::space1(input)?;
+    let item = nom::branch::alt((
+        combinator::map(string, |text| Value::Text(Box::from(text))),
+        combinator::map(expression, Value::Expr),
+    ));
+    let (input, items) = nom::multi::separated_nonempty_list(comma, item)(input)?;
+    let (input, _) = line_end(input)?;
+    Ok((input, Value::Array(items)))
+}
+
+pub fn parse_argument(input: &[u8]) -> IResult<&[u8], ArgumentType> {
+    if let Ok((input, _)) = line_end(input) {
         return Ok((input, (AddressingMode::IMPL, Value::None)));
     }
+    let (input, _) = character::complete::space1(input)?;
     let (input, arg) = argument(input)?;
     if !input.is_empty() {
         eprintln!("This should've be the end, but found {:X?}", input);
@@ -176,9 +458,8 @@ mod tests {
             NErr::Error((&b"0"[..], ErrorKind::Eof)),
         ];
         for (test, error) in tests_error.iter().zip(errors_exp.iter()) {
-            let res: NErr<(&[u8], ErrorKind)> = hex_addr_short(test)
-                .err()
-                .expect("This should have errored");
+            let res: NErr<(&[u8], ErrorKind)> =
+                hex_addr_short(test).expect_err("This should have errored");
             println!("{:?} -> {:?} / {:?}", test, res, error);
             assert_eq!(&res, error);
         }
@@ -201,7 +482,7 @@ mod tests {
         ];
         for (test, error) in tests_error.iter().zip(errors_exp.iter()) {
             let res: NErr<(&[u8], ErrorKind)> =
-                hex_addr_long(test).err().expect("This should have errored");
+                hex_addr_long(test).expect_err("This should have errored");
             println!("{:?} -> {:?} / {:?}", test, res, error);
             assert_eq!(&res, error);
         }
@@ -226,8 +507,10 @@ mod tests {
         let test = b"($02,X)";
         let res = indexed_indirect(test);
         println!("{:#?}", res);
-        res.expect(":(");
-        //assert_eq!(res, (AddressingMode::INDX, Value::Short(0x02)));
+        assert_eq!(
+            res.expect(":(").1,
+            (AddressingMode::INDX, Value::Short(0x02))
+        );
     }
 
     #[test]
@@ -243,7 +526,7 @@ mod tests {
 
     #[test]
     fn test_argument() {
-        use super::super::{types::Value, AddressingMode};
+        use super::super::{types::Value, AddressingMode, Expr};
         use super::argument;
         let tests = [
             &b"#$AD"[..],
@@ -251,6 +534,12 @@ mod tests {
             &b"$AD"[..],
             &b"Hello"[..],
             &b"($FE,X)"[..],
+            &b"($1234,X)"[..],
+            &b"$10, loop"[..],
+            &b"$7E2000,X"[..],
+            &b"[$10],Y"[..],
+            &b"($03,S),Y"[..],
+            &b"$03,S"[..],
         ];
         let tests_results = [
             (AddressingMode::IMM, Value::Short(0xAD)),
@@ -258,6 +547,15 @@ mod tests {
             (AddressingMode::ZPG, Value::Short(0xAD)),
             (AddressingMode::ABS, Value::Label("Hello".to_string())),
             (AddressingMode::INDX, Value::Short(0xFE)),
+            (AddressingMode::ABSINDX, Value::Long(0x1234)),
+            (
+                AddressingMode::ZPGREL,
+                Value::Pair(Expr::Number(0x10), Expr::Label("loop".to_string())),
+            ),
+            (AddressingMode::ABSLX, Value::Far(0x7E2000)),
+            (AddressingMode::DPINDLY, Value::Short(0x10)),
+            (AddressingMode::SRINDY, Value::Short(0x03)),
+            (AddressingMode::SR, Value::Short(0x03)),
         ];
         for (test, exp) in tests.iter().zip(tests_results.iter()) {
             let (_, res) = argument(test).expect("This shouldn't haver errored");
@@ -266,4 +564,56 @@ mod tests {
         }
     }
     // #endregion
+    #[test]
+    fn test_expectations() {
+        use super::parse_expectations;
+        use crate::opcodes::flags;
+        use crate::parser::{Comparison, Expr, Target, Value};
+        let (_, value) = parse_expectations(b" A == $05, mem[ $0200 ]!=$FF ,c==1").unwrap();
+        let expectations = match value {
+            Value::Expectations(expectations) => expectations,
+            _ => panic!("Expected a list of expectations"),
+        };
+        assert_eq!(expectations.len(), 3);
+        assert_eq!(expectations[0].target, Target::A);
+        assert_eq!(expectations[0].value, Expr::Number(5));
+        assert_eq!(expectations[0].text, "A == $05");
+        assert_eq!(expectations[1].target, Target::Memory(Expr::Number(0x200)));
+        assert_eq!(expectations[1].comparison, Comparison::NotEqual);
+        assert_eq!(expectations[2].target, Target::Flag(flags::C));
+        assert!(parse_expectations(b" Q == 1").is_err());
+        assert!(parse_expectations(b" A = 1").is_err());
+        assert!(parse_expectations(b"").is_err());
+    }
+    #[test]
+    fn test_assertion() {
+        use super::{parse_assertion, parse_print};
+        use crate::parser::{Assertion, Comparison, Expr, Value};
+        let (_, value) = parse_assertion(b" * <= $FFFA , \"Too long\"").unwrap();
+        assert_eq!(
+            value,
+            Value::Assertion(Assertion {
+                lhs: Expr::Pc,
+                check: Some((Comparison::LessOrEqual, Expr::Number(0xFFFA))),
+                text: "* <= $FFFA".to_string(),
+                message: Some("Too long".to_string()),
+            })
+        );
+        let (_, value) = parse_assertion(b" size").unwrap();
+        match value {
+            Value::Assertion(assertion) => {
+                assert_eq!(assertion.check, None);
+                assert_eq!(assertion.message, None);
+            }
+            _ => panic!("Expected an assertion"),
+        }
+        let (_, value) = parse_print(b" \"size: \", size").unwrap();
+        assert_eq!(
+            value,
+            Value::Array(vec![
+                Value::Text(Box::from(&b"size: "[..])),
+                Value::Expr(Expr::Label("size".to_string())),
+            ])
+        );
+    }
 }
@@ -0,0 +1,308 @@
+use super::{bin_to_hex, label_identifier, u8_to_hex};
+use crate::error::Error;
+use crate::nom;
+use nom::{
+    branch, bytes::complete as bytes, character::complete as character, combinator, sequence,
+    IResult,
+};
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    And,
+    Or,
+    Xor,
+    Shl,
+    Shr,
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum UnOp {
+    /// Ej.: `-1`
+    Neg,
+    /// Ej.: `~$0F`
+    Not,
+    /// Ej.: `<label` (low byte)
+    Low,
+    /// Ej.: `>label` (high byte)
+    High,
+    /// Ej.: `^label` (bank byte, 65816)
+    Bank,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Expr {
+    Number(isize),
+    Label(String),
+    /// The program counter, written as `*` or `$`
+    Pc,
+    Unary(UnOp, Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    /// Evaluate the expression.
+    /// `pc` is the value of `*`, `lookup` returns the value of a label (if it is known).
+    /// If a label can't be found, an `Error::UndefLabel` is returned with its name
+    pub fn eval(
+        &self,
+        pc: Option<isize>,
+        lookup: &dyn Fn(&str) -> Option<isize>,
+    ) -> Result<isize, Error> {
+        Ok(match self {
+            Expr::Number(n) => *n,
+            Expr::Label(name) => lookup(name).ok_or_else(|| Error::UndefLabel {
+                labels: name.clone(),
+            })?,
+            Expr::Pc => pc.ok_or_else(|| Error::Assembler {
+                cause: "The program counter (*) can't be used here".to_string(),
+            })?,
+            Expr::Unary(op, expr) => {
+                let value = expr.eval(pc, lookup)?;
+                match op {
+                    UnOp::Neg => value.wrapping_neg(),
+                    UnOp::Not => !value,
+                    UnOp::Low => value & 0xFF,
+                    UnOp::High => (value >> 8) & 0xFF,
+                    UnOp::Bank => (value >> 16) & 0xFF,
+                }
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = lhs.eval(pc, lookup)?;
+                let rhs = rhs.eval(pc, lookup)?;
+                match op {
+                    BinOp::Add => lhs.wrapping_add(rhs),
+                    BinOp::Sub => lhs.wrapping_sub(rhs),
+                    BinOp::Mul => lhs.wrapping_mul(rhs),
+                    BinOp::Div | BinOp::Mod if rhs == 0 => {
+                        return Err(Error::Assembler {
+                            cause: format!("Division by zero in {:?}", self),
+                        })
+                    }
+                    BinOp::Div => lhs.wrapping_div(rhs),
+                    BinOp::Mod => lhs.wrapping_rem(rhs),
+                    BinOp::And => lhs & rhs,
+                    BinOp::Or => lhs | rhs,
+                    BinOp::Xor => lhs ^ rhs,
+                    BinOp::Shl => lhs.wrapping_shl(rhs as u32),
+                    BinOp::Shr => lhs.wrapping_shr(rhs as u32),
+                }
+            }
+        })
+    }
+    /// Rename every label used on the expression
+    pub fn map_labels(self, f: &dyn Fn(String) -> Result<String, Error>) -> Result<Expr, Error> {
+        Ok(match self {
+            Expr::Label(name) => Expr::Label(f(name)?),
+            Expr::Unary(op, expr) => Expr::Unary(op, Box::new(expr.map_labels(f)?)),
+            Expr::Binary(op, lhs, rhs) => Expr::Binary(
+                op,
+                Box::new(lhs.map_labels(f)?),
+                Box::new(rhs.map_labels(f)?),
+            ),
+            expr => expr,
+        })
+    }
+    /// Value of the expression if it doesn't depend on labels nor on `*`
+    pub fn constant(&self) -> Option<isize> {
+        self.eval(None, &|_| None).ok()
+    }
+}
+
+// #region Parsers
+fn number(input: &[u8]) -> IResult<&[u8], Expr> {
+    let hex = sequence::preceded(
+        character::char('$'),
+        combinator::map_res(character::hex_digit1, u8_to_hex),
+    );
+    let bin = sequence::preceded(
+        character::char('%'),
+        combinator::map_res(bytes::take_while1(|c| c == b'0' || c == b'1'), bin_to_hex),
+    );
+    let dec = combinator::map_res(character::digit1, |v: &[u8]| {
+        std::str::from_utf8(v)
+            .map_err(|_| ())?
+            .parse::<usize>()
+            .map_err(|_| ())
+    });
+    let (input, value) = branch::alt((hex, bin, dec))(input)?;
+    Ok((input, Expr::Number(value as isize)))
+}
+
+fn pc(input: &[u8]) -> IResult<&[u8], Expr> {
+    let (input, _) = branch::alt((character::char('*'), character::char('$')))(input)?;
+    Ok((input, Expr::Pc))
+}
+
+fn label(input: &[u8]) -> IResult<&[u8], Expr> {
+    let (input, name) = label_identifier(input)?;
+    Ok((input, Expr::Label(name)))
+}
+
+/// Reference to an anonymous label: `:+`, `:++`, ... for the following ones
+/// and `:-`, `:--`, ... for the previous ones
+fn anonymous_label(input: &[u8]) -> IResult<&[u8], Expr> {
+    let (input, name) = combinator::map_res(
+        combinator::recognize(sequence::pair(
+            character::char(':'),
+            branch::alt((
+                bytes::take_while1(|c| c == b'+'),
+                bytes::take_while1(|c| c == b'-'),
+            )),
+        )),
+        |name: &[u8]| String::from_utf8(name.to_vec()),
+    )(input)?;
+    Ok((input, Expr::Label(name)))
+}
+
+fn parenthesized(input: &[u8]) -> IResult<&[u8], Expr> {
+    sequence::delimited(
+        sequence::pair(character::char('('), character::space0),
+        expression,
+        sequence::pair(character::space0, character::char(')')),
+    )(input)
+}
+
+fn unary(input: &[u8]) -> IResult<&[u8], Expr> {
+    let op: IResult<&[u8], UnOp> = branch::alt((
+        combinator::map(character::char('-'), |_| UnOp::Neg),
+        combinator::map(character::char('~'), |_| UnOp::Not),
+        combinator::map(character::char('<'), |_| UnOp::Low),
+        combinator::map(character::char('>'), |_| UnOp::High),
+        combinator::map(character::char('^'), |_| UnOp::Bank),
+    ))(input);
+    if let Ok((input, op)) = op {
+        let (input, _) = character::space0(input)?;
+        let (input, expr) = unary(input)?;
+        return Ok((input, Expr::Unary(op, Box::new(expr))));
+    }
+    // `number` goes before `pc` so `$10` isn't read as `$` followed by garbage
+    branch::alt((parenthesized, number, pc, label, anonymous_label))(input)
+}
+
+/// Parse a left associative chain of `operand (operator operand)*`
+fn binary<'i>(
+    input: &'i [u8],
+    operand: fn(&'i [u8]) -> IResult<&'i [u8], Expr>,
+    operators: &[(&'static str, BinOp)],
+) -> IResult<&'i [u8], Expr> {
+    let (mut input, mut lhs) = operand(input)?;
+    'chain: loop {
+        let (rest, _) = character::space0(input)?;
+        for (symbol, op) in operators {
+            let tag: IResult<&[u8], &[u8]> = bytes::tag(*symbol)(rest);
+            if let Ok((rest, _)) = tag {
+                let (rest, _) = character::space0(rest)?;
+                let (rest, rhs) = operand(rest)?;
+                lhs = Expr::Binary(*op, Box::new(lhs), Box::new(rhs));
+                input = rest;
+                continue 'chain;
+            }
+        }
+        return Ok((input, lhs));
+    }
+}
+
+fn product(input: &[u8]) -> IResult<&[u8], Expr> {
+    binary(
+        input,
+        unary,
+        &[("*", BinOp::Mul), ("/", BinOp::Div), ("%", BinOp::Mod)],
+    )
+}
+fn sum(input: &[u8]) -> IResult<&[u8], Expr> {
+    binary(input, product, &[("+", BinOp::Add), ("-", BinOp::Sub)])
+}
+fn shift(input: &[u8]) -> IResult<&[u8], Expr> {
+    binary(input, sum, &[("<<", BinOp::Shl), (">>", BinOp::Shr)])
+}
+fn bit_and(input: &[u8]) -> IResult<&[u8], Expr> {
+    binary(input, shift, &[("&", BinOp::And)])
+}
+fn bit_xor(input: &[u8]) -> IResult<&[u8], Expr> {
+    binary(input, bit_and, &[("^", BinOp::Xor)])
+}
+fn bit_or(input: &[u8]) -> IResult<&[u8], Expr> {
+    binary(input, bit_xor, &[("|", BinOp::Or)])
+}
+// #endregion
+
+/// Parse an expression. Operators, from lowest to highest precedence:
+/// `|`, `^`, `&`, `<< >>`, `+ -`, `* / %` and the unary `- ~ < > ^`
+pub fn expression(input: &[u8]) -> IResult<&[u8], Expr> {
+    bit_or(input)
+}
+
+mod tests {
+    #[test]
+    fn test_expression() {
+        use super::{expression, BinOp, Expr};
+        let tests = [
+            (
+                &b"*-2"[..],
+                Expr::Binary(BinOp::Sub, Box::new(Expr::Pc), Box::new(Expr::Number(2))),
+            ),
+            (
+                &b"* + $100"[..],
+                Expr::Binary(
+                    BinOp::Add,
+                    Box::new(Expr::Pc),
+                    Box::new(Expr::Number(0x100)),
+                ),
+            ),
+            (
+                &b"$ - start"[..],
+                Expr::Binary(
+                    BinOp::Sub,
+                    Box::new(Expr::Pc),
+                    Box::new(Expr::Label("start".to_string())),
+                ),
+            ),
+            (
+                &b":++ + 1"[..],
+                Expr::Binary(
+                    BinOp::Add,
+                    Box::new(Expr::Label(":++".to_string())),
+                    Box::new(Expr::Number(1)),
+                ),
+            ),
+            (
+                &b"**2"[..],
+                Expr::Binary(BinOp::Mul, Box::new(Expr::Pc), Box::new(Expr::Number(2))),
+            ),
+        ];
+        for (test, exp) in tests.iter() {
+            let (rest, res) = expression(test).expect("This shouldn't have errored");
+            println!("{:?} -> {:?}", std::str::from_utf8(test).unwrap(), res);
+            assert_eq!(rest, &[][..]);
+            assert_eq!(&res, exp);
+        }
+    }
+    #[test]
+    fn test_eval() {
+        use super::expression;
+        let lookup = |name: &str| if name == "start" { Some(0x8000) } else { None };
+        let tests = [
+            (&b"1+2*3"[..], 7),
+            (&b"(1+2)*3"[..], 9),
+            (&b"%1010 | $0F & 3"[..], 0b1011),
+            (&b"<$1234"[..], 0x34),
+            (&b">$1234"[..], 0x12),
+            (&b"^$7E1234"[..], 0x7E),
+            (&b"$12 ^ $FF"[..], 0xED),
+            (&b"* - start"[..], 0x10),
+            (&b"-1 + 1 << 4"[..], 0),
+        ];
+        for (test, exp) in tests.iter() {
+            let (_, expr) = expression(test).expect("This shouldn't have errored");
+            assert_eq!(expr.eval(Some(0x8010), &lookup).unwrap(), *exp);
+        }
+        let (_, expr) = expression(b"missing+1").unwrap();
+        assert!(expr.eval(Some(0), &lookup).is_err());
+        assert_eq!(expr.constant(), None);
+    }
+}
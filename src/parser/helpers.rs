@@ -1,4 +1,4 @@
-use nom::character;
+use nom::{bytes::complete as bytes, character, combinator, multi, sequence, IResult};
 use std::str::from_utf8;
 
 pub fn u8_to_hex(v: &[u8]) -> Result<usize, ()> {
@@ -19,9 +19,89 @@ pub fn bin_to_hex(v: &[u8]) -> Result<usize, ()> {
 }
 
 named!(pub eof, eof!());
-// TODO: Improve margin recognition
+// Any mix of spaces and tabs
 named!(pub margin<&[u8]>, take_while!(character::is_space));
 
+/// End of a line: trailing spaces and tabs, and the line ending (`\n`, `\r\n` or the `\r` left
+/// after splitting on `\n`) if it's still there
+pub fn line_end(input: &[u8]) -> IResult<&[u8], ()> {
+    let (input, _) = character::complete::space0(input)?;
+    let (input, _) = combinator::opt(nom::branch::alt((
+        character::complete::line_ending,
+        bytes::tag("\r"),
+    )))(input)?;
+    let (input, _) = eof(input)?;
+    Ok((input, ()))
+}
+
+/// A `,` with any whitespace around it
+pub fn comma(input: &[u8]) -> IResult<&[u8], char> {
+    sequence::delimited(
+        character::complete::space0,
+        character::complete::char(','),
+        character::complete::space0,
+    )(input)
+}
+
 pub fn is_symbol(c: u8) -> bool {
     c.is_ascii_graphic() || c.is_ascii_punctuation()
 }
+
+/// A name: a letter or `_` followed by letters, digits or `_`. Ej.: `_start`, `ppu_ctrl`
+fn identifier(input: &[u8]) -> IResult<&[u8], &[u8]> {
+    combinator::recognize(sequence::pair(
+        bytes::take_while_m_n(1, 1, |c: u8| c.is_ascii_alphabetic() || c == b'_'),
+        bytes::take_while(|c: u8| c.is_ascii_alphanumeric() || c == b'_'),
+    ))(input)
+}
+
+/// Name of a label. Local labels start with `@` or `.`,
+/// and can be reached from anywhere as `global.local`.
+/// Names inside scopes are reached as `Scope::name`, and `::name` is always the global one
+pub fn label_identifier(input: &[u8]) -> IResult<&[u8], String> {
+    combinator::map_res(
+        combinator::recognize(sequence::tuple((
+            combinator::opt(bytes::tag("::")),
+            combinator::opt(character::complete::one_of("@.")),
+            identifier,
+            multi::many0(sequence::pair(
+                nom::branch::alt((bytes::tag("::"), bytes::tag("."))),
+                identifier,
+            )),
+        ))),
+        |name: &[u8]| String::from_utf8(name.to_vec()),
+    )(input)
+}
+
+mod tests {
+    #[test]
+    fn test_label_identifier() {
+        use super::label_identifier;
+        let tests_ok = [
+            "main",
+            "_start",
+            "ppu_ctrl",
+            "Reset_Handler",
+            "@loop",
+            ".loop",
+            "Player.update",
+            "Player::update",
+            "::reset",
+            "Game::Player::update.loop",
+            "x2",
+        ];
+        for test in tests_ok.iter() {
+            let (rest, name) =
+                label_identifier(test.as_bytes()).expect("This should have been an Ok");
+            assert_eq!(rest, &[][..]);
+            assert_eq!(&name, test);
+        }
+        for test in ["2x", "$10", "@", ".", "-a", ":+", "::"].iter() {
+            assert!(
+                label_identifier(test.as_bytes()).is_err(),
+                "{} should have errored",
+                test
+            );
+        }
+    }
+}
@@ -1,59 +1,235 @@
-use super::arguments::parse_argument;
-use super::helpers::{eof, margin};
+use super::arguments::{parse_argument, parse_assertion, parse_expectations, parse_print};
+use super::expressions::expression;
+use super::helpers::{label_identifier, line_end, margin};
 use super::nom;
 use super::types::{LineType, Opcode};
 use super::OpcodeType;
-use super::{AddressingMode, ArgumentType, Value};
-use nom::{bytes::complete as bytes, character, combinator, IResult};
+use super::{AddressingMode, ArgumentType, Expr, Value};
+use crate::error::Error;
+use nom::{bytes::complete as bytes, character, combinator, sequence, IResult};
 use std::str::from_utf8;
 
 // #region Parsers
 fn parse_opcode_line(input: &[u8]) -> IResult<&[u8], Opcode> {
     let (input, _) = margin(input)?;
-    let (input, name) = bytes::take_while_m_n(3, 3, character::is_alphabetic)(input)?;
-    let name: OpcodeType =
-        match OpcodeType::identify(&&from_utf8(name).expect("Couldn't convert [u8] to str")) {
-            Ok(v) => v,
-            Err(_) => return Err(nom::Err::Failure((input, nom::error::ErrorKind::MapRes))),
-        };
+    let (input, name) = bytes::take_while_m_n(3, 4, character::is_alphanumeric)(input)?;
+    let name = from_utf8(name).expect("Couldn't convert [u8] to str");
+    // `ASLA` is the same as `ASL A`
+    let (name, accumulator): (OpcodeType, bool) = match OpcodeType::identify(&&name) {
+        Some(v) => (v, false),
+        _ => match OpcodeType::identify_accumulator(name) {
+            Some(v) => (v, true),
+            None => return Err(nom::Err::Failure((input, nom::error::ErrorKind::MapRes))),
+        },
+    };
     let (input, mut arg): (_, ArgumentType) = parse_argument(input)?;
+    // A shift without operand works on the accumulator
+    if name.is_accumulator_op() && arg.0 == AddressingMode::IMPL {
+        arg = (AddressingMode::A, arg.1);
+    } else if accumulator {
+        return Err(nom::Err::Failure((input, nom::error::ErrorKind::Verify)));
+    }
+    // `$7E, $01` is read as an array of bytes
+    let takes_pair = name.is_bit_branch_op() || name == OpcodeType::MVN || name == OpcodeType::MVP;
+    if let (AddressingMode::ABS, Value::Array(values)) = &arg {
+        if let (true, [Value::Short(first), Value::Short(second)]) = (takes_pair, &values[..]) {
+            let pair = Value::Pair(
+                Expr::Number(*first as isize),
+                Expr::Number(*second as isize),
+            );
+            arg = (AddressingMode::ZPGREL, pair);
+        }
+    }
+    let is_jump = name == OpcodeType::JMP || name == OpcodeType::JML;
+    let is_long_jump = name == OpcodeType::JML || name == OpcodeType::JSL;
+    // `(zp)` and `(abs)` are written the same way, but only jumps take an absolute address
+    arg = match (name, arg) {
+        (OpcodeType::JMP, (AddressingMode::INDX, Value::Short(addr)))
+        | (OpcodeType::JSR, (AddressingMode::INDX, Value::Short(addr))) => {
+            (AddressingMode::ABSINDX, Value::Long(addr.into()))
+        }
+        (OpcodeType::JMP, (AddressingMode::INDX, value))
+        | (OpcodeType::JSR, (AddressingMode::INDX, value)) => (AddressingMode::ABSINDX, value),
+        (_, (AddressingMode::DPINDL, Value::Short(addr))) if is_jump => {
+            (AddressingMode::ABSINDL, Value::Long(addr.into()))
+        }
+        (_, (AddressingMode::DPINDL, value)) if is_jump => (AddressingMode::ABSINDL, value),
+        // `JML` and `JSL` always take a long address
+        (_, (AddressingMode::ZPG, Value::Short(addr))) if is_long_jump => {
+            (AddressingMode::ABSL, Value::Far(addr.into()))
+        }
+        (_, (AddressingMode::ABS, Value::Long(addr))) if is_long_jump => {
+            (AddressingMode::ABSL, Value::Far(addr.into()))
+        }
+        (_, (AddressingMode::ABS, value)) if is_long_jump => (AddressingMode::ABSL, value),
+        (OpcodeType::MVN, (AddressingMode::ZPGREL, value))
+        | (OpcodeType::MVP, (AddressingMode::ZPGREL, value)) => (AddressingMode::BLK, value),
+        (OpcodeType::JMP, arg) => arg,
+        (_, (AddressingMode::IND, Value::Long(addr))) if addr <= 0xFF => {
+            (AddressingMode::ZPGIND, Value::Short(addr as u8))
+        }
+        (_, (AddressingMode::IND, Value::Long(_))) => {
+            return Err(nom::Err::Failure((input, nom::error::ErrorKind::Verify)))
+        }
+        (_, (AddressingMode::IND, value)) => (AddressingMode::ZPGIND, value),
+        (_, arg) => arg,
+    };
     // If the OPCODE is any kind of branch, then we DO NOT USE ABS as addressing mode,
     // even if its argument is a label, so we manually patch this
     if name.is_branch_op() {
         arg = (AddressingMode::REL, arg.1);
+    } else if name.is_long_relative_op() {
+        arg = (AddressingMode::RELL, arg.1);
     }
     Ok((input, Opcode { name, arg }))
 }
 
 fn label_def(input: &[u8]) -> IResult<&[u8], String> {
-    let (input, value) = combinator::map_res(character::complete::alphanumeric1, |v: &[u8]| {
-        String::from_utf8(v.to_vec())
-    })(input)?;
+    let (input, _) = margin(input)?;
+    let (input, value) = label_identifier(input)?;
     let (input, _) = character::complete::char(':')(input)?;
-    let (input, _) = eof(input)?;
+    let (input, _) = line_end(input)?;
     Ok((input, value))
 }
 
+fn anon_label_def(input: &[u8]) -> IResult<&[u8], ()> {
+    let (input, _) = margin(input)?;
+    let (input, _) = character::complete::char(':')(input)?;
+    let (input, _) = line_end(input)?;
+    Ok((input, ()))
+}
+
+fn const_def(input: &[u8]) -> IResult<&[u8], (String, Expr)> {
+    let (input, _) = margin(input)?;
+    let (input, name) = label_identifier(input)?;
+    let (input, _) = character::complete::space0(input)?;
+    let (input, _) = character::complete::char('=')(input)?;
+    let (input, _) = character::complete::space0(input)?;
+    let (input, value) = expression(input)?;
+    let (input, _) = line_end(input)?;
+    Ok((input, (name, value)))
+}
+
+/// Parser of the argument of a directive
+type MacroArgument = fn(&[u8]) -> IResult<&[u8], Value>;
+
 fn parse_macro(input: &[u8]) -> IResult<&[u8], (String, Value)> {
+    let (input, _) = margin(input)?;
     let (input, _) = character::complete::char('.')(input)?;
-    let (input, name) = character::complete::alpha1(input)?;
-    let name = String::from_utf8(name.to_vec())
+    let (input, name) = combinator::recognize(sequence::pair(
+        character::complete::alpha1,
+        character::complete::alphanumeric0,
+    ))(input)?;
+    // Directives aren't case sensitive
+    let name = String::from_utf8(name.to_ascii_lowercase())
         .map_err(|_| nom::Err::Error((input, nom::error::ErrorKind::MapRes)))?;
-    println!("Macro {}", name);
+    // These take their own kind of argument
+    let special: Option<MacroArgument> = match &name[..] {
+        "expect" => Some(parse_expectations),
+        "assert" => Some(parse_assertion),
+        "print" => Some(parse_print),
+        _ => None,
+    };
+    if let Some(parser) = special {
+        let (input, arg) = parser(input)?;
+        return Ok((input, (name, arg)));
+    }
     let (input, (_, arg)) = parse_argument(input)?;
     Ok((input, (name, arg)))
 }
 // #endregion
 
+/// `label: instruction` (or `: instruction`) on the same line
+fn labeled_line(input: &[u8]) -> IResult<&[u8], Vec<LineType>> {
+    let (input, _) = margin(input)?;
+    let (input, label) = combinator::opt(label_identifier)(input)?;
+    let (input, _) = character::complete::char(':')(input)?;
+    let (input, _) = character::complete::space1(input)?;
+    let (input, line) = nom::branch::alt((
+        combinator::map(parse_opcode_line, LineType::Opcode),
+        combinator::map(parse_macro, |(n, a)| LineType::Macro(n, a)),
+    ))(input)?;
+    let label = match label {
+        Some(name) => LineType::LabelDef(name),
+        None => LineType::AnonLabelDef,
+    };
+    Ok((input, vec![label, line]))
+}
+
 named!(
-    pub parse_line<LineType>,
+    single_line<LineType>,
     alt!(
-        label_def => { |r|LineType::LabelDef(r) }|
-        parse_opcode_line => { |r|LineType::Opcode(r) }|
+        label_def => { LineType::LabelDef }|
+        anon_label_def => { |_|LineType::AnonLabelDef }|
+        const_def => { |(n,v)|LineType::ConstDef(n,v) }|
+        parse_opcode_line => { LineType::Opcode }|
         parse_macro => { |(n,a)|LineType::Macro(n,a) }
     )
 );
 
+/// Parse a line of code (without its comment). Blank lines don't have any `LineType`
+pub fn parse_line(input: &[u8]) -> IResult<&[u8], Vec<LineType>> {
+    if let Ok((input, _)) = line_end(input) {
+        return Ok((input, vec![]));
+    }
+    nom::branch::alt((labeled_line, combinator::map(single_line, |r| vec![r])))(input)
+}
+
+/// Parse a whole source file, keeping the number of the line each `LineType` came from.
+/// Comments are taken out and `\"`, `\n` are escaped before parsing each line
+pub fn parse_source(source: &str) -> Result<Vec<(usize, LineType)>, Error> {
+    let code: Vec<(usize, LineType)> = source
+        .lines()
+        .map(|line: &str| {
+            let mut escape = false;
+            let mut in_string = false;
+            let mut in_comment = false;
+            line.chars()
+                .filter_map(|c: char| {
+                    if in_comment {
+                        None
+                    } else {
+                        match c {
+                            '\\' => {
+                                escape = true;
+                                None
+                            }
+                            '"' if !escape => {
+                                in_string = !in_string;
+                                Some('"')
+                            }
+                            '"' if escape => Some(c),
+                            'n' if escape => Some(0x10 as char),
+                            ';' => {
+                                in_comment = true;
+                                None
+                            }
+                            _ => {
+                                if escape {
+                                    panic!("\\{} doesn't mean anything", c)
+                                } else {
+                                    Some(c)
+                                }
+                            }
+                        }
+                    }
+                })
+                .collect::<String>()
+        })
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(number, line): (usize, String)| {
+            parse_line(line.as_bytes())
+                .map(|(_, lines)| lines.into_iter().map(|line| (number + 1, line)).collect())
+                .map_err(Into::into)
+        })
+        .collect::<Result<Vec<Vec<(usize, LineType)>>, Error>>()?
+        .into_iter()
+        .flatten()
+        .collect();
+    Ok(code)
+}
+
 mod tests {
     #[test]
     fn test_macro() {
@@ -62,6 +238,49 @@ mod tests {
         println!("{:?}", res);
     }
     #[test]
+    fn test_case_insensitive() {
+        use super::parse_line;
+        let tests = [
+            &b"  lda #$00"[..],
+            &b"  sta $0200,x"[..],
+            &b"  Lda ($10),y"[..],
+            &b"  lsr a"[..],
+            &b".ORG $8000"[..],
+        ];
+        for test in tests.iter() {
+            let upper = test.to_ascii_uppercase();
+            let (_, lower) = parse_line(test).expect("This shouldn't have errored");
+            let (_, upper) = parse_line(&upper).expect("This shouldn't have errored");
+            assert_eq!(format!("{:?}", lower), format!("{:?}", upper));
+        }
+    }
+    #[test]
+    fn test_whitespace() {
+        use super::parse_line;
+        let tests = [
+            (&b"\tLDA\t#$00"[..], &b"  LDA #$00"[..]),
+            (&b"  STA $0200 , X  "[..], &b"  STA $0200,X"[..]),
+            (&b"  LDA ( $10 , X )"[..], &b"  LDA ($10,X)"[..]),
+            (&b"  LDA ( ptr ) , Y\t"[..], &b"  LDA (ptr),Y"[..]),
+            (&b"  JMP ( vector )"[..], &b"  JMP (vector)"[..]),
+            (&b"  LDA #(1 + 2) * 3"[..], &b"  LDA #(1+2)*3"[..]),
+            (&b".db $DE ,\t$AD,$BE  "[..], &b".db $DE,$AD,$BE"[..]),
+            (&b"  main:  \r\n"[..], &b"main:"[..]),
+            (&b"  .org\t$8000\r"[..], &b".org $8000"[..]),
+            (&b"size  =  2\t"[..], &b"size = 2"[..]),
+            (&b"  CLC \r\n"[..], &b"  CLC"[..]),
+        ];
+        for (test, exp) in tests.iter() {
+            let (rest, res) = parse_line(test).unwrap_or_else(|e| {
+                panic!("{:?} failed: {:?}", std::str::from_utf8(test).unwrap(), e)
+            });
+            let (_, exp) = parse_line(exp).expect("This shouldn't have errored");
+            assert_eq!(rest, &[][..]);
+            assert_eq!(format!("{:?}", res), format!("{:?}", exp));
+        }
+        assert_eq!(parse_line(&b" \t \r\n"[..]).unwrap().1.len(), 0);
+    }
+    #[test]
     fn test_opcode() {
         use super::parse_opcode_line;
         let tests = [
@@ -79,6 +298,168 @@ mod tests {
         }
     }
     #[test]
+    fn test_accumulator() {
+        use super::parse_line;
+        let tests = [
+            (&b"  ASL"[..], &b"  ASL A"[..]),
+            (&b"  lsr"[..], &b"  LSR A"[..]),
+            (&b"  ROLA"[..], &b"  ROL A"[..]),
+            (&b"  rora "[..], &b"  ROR A"[..]),
+            (&b"  BLT loop"[..], &b"  BCC loop"[..]),
+            (&b"  BGE loop"[..], &b"  BCS loop"[..]),
+        ];
+        for (test, exp) in tests.iter() {
+            let (_, res) = parse_line(test).expect("This shouldn't have errored");
+            let (_, exp) = parse_line(exp).expect("This shouldn't have errored");
+            assert_eq!(format!("{:?}", res), format!("{:?}", exp));
+        }
+        assert!(parse_line(&b"  ASLA $10"[..]).is_err());
+        assert!(parse_line(&b"  LDAA"[..]).is_err());
+    }
+    #[test]
+    fn test_65c02() {
+        use super::parse_opcode_line;
+        use crate::parser::{AddressingMode, Expr, OpcodeType, Value};
+        let tests = [
+            (
+                &b"  LDA ($10)"[..],
+                AddressingMode::ZPGIND,
+                Value::Short(0x10),
+            ),
+            (
+                &b"  JMP ($1234)"[..],
+                AddressingMode::IND,
+                Value::Long(0x1234),
+            ),
+            (
+                &b"  JMP ($10,X)"[..],
+                AddressingMode::ABSINDX,
+                Value::Long(0x10),
+            ),
+            (
+                &b"  JMP ($1234,X)"[..],
+                AddressingMode::ABSINDX,
+                Value::Long(0x1234),
+            ),
+            (&b"  stz $10"[..], AddressingMode::ZPG, Value::Short(0x10)),
+            (&b"  INC A"[..], AddressingMode::A, Value::None),
+            (
+                &b"  BBR3 $10, loop"[..],
+                AddressingMode::ZPGREL,
+                Value::Pair(Expr::Number(0x10), Expr::Label("loop".to_string())),
+            ),
+        ];
+        for (test, mode, value) in tests.iter() {
+            let (_, opcode) = parse_opcode_line(test).expect("This shouldn't have errored");
+            assert_eq!(&opcode.arg.0, mode);
+            assert_eq!(&opcode.arg.1, value);
+        }
+        let (_, opcode) = parse_opcode_line(&b"  rmb7 $10"[..]).unwrap();
+        assert_eq!(opcode.name, OpcodeType::RMB7);
+        assert!(parse_opcode_line(&b"  LDA ($1234)"[..]).is_err());
+    }
+    #[test]
+    fn test_65816() {
+        use super::{parse_line, parse_opcode_line};
+        use crate::parser::{AddressingMode, Expr, LineType, Value};
+        let tests = [
+            (
+                &b"  LDA $7E2000"[..],
+                AddressingMode::ABSL,
+                Value::Far(0x7E2000),
+            ),
+            (
+                &b"  JSL $8000"[..],
+                AddressingMode::ABSL,
+                Value::Far(0x8000),
+            ),
+            (
+                &b"  JML [$FFFC]"[..],
+                AddressingMode::ABSINDL,
+                Value::Long(0xFFFC),
+            ),
+            (
+                &b"  LDA [$10]"[..],
+                AddressingMode::DPINDL,
+                Value::Short(0x10),
+            ),
+            (
+                &b"  LDA ($03,S),Y"[..],
+                AddressingMode::SRINDY,
+                Value::Short(0x03),
+            ),
+            (
+                &b"  JSR ($1234,X)"[..],
+                AddressingMode::ABSINDX,
+                Value::Long(0x1234),
+            ),
+            (
+                &b"  PEI ($10)"[..],
+                AddressingMode::ZPGIND,
+                Value::Short(0x10),
+            ),
+            (
+                &b"  MVN $7E, $01"[..],
+                AddressingMode::BLK,
+                Value::Pair(Expr::Number(0x7E), Expr::Number(0x01)),
+            ),
+            (
+                &b"  BRL far"[..],
+                AddressingMode::RELL,
+                Value::Label("far".to_string()),
+            ),
+        ];
+        for (test, mode, value) in tests.iter() {
+            let (_, opcode) = parse_opcode_line(test).expect("This shouldn't have errored");
+            assert_eq!(&opcode.arg.0, mode);
+            assert_eq!(&opcode.arg.1, value);
+        }
+        let (_, lines) = parse_line(&b".A16"[..]).expect("This shouldn't have errored");
+        assert!(match &lines[0] {
+            LineType::Macro(name, Value::None) => name == "a16",
+            _ => false,
+        });
+    }
+    #[test]
+    fn test_label_def() {
+        use super::label_def;
+        let tests = [
+            (&b"main:"[..], "main"),
+            (&b"@loop:"[..], "@loop"),
+            (&b".loop:"[..], ".loop"),
+            (&b"main.loop:"[..], "main.loop"),
+        ];
+        for (test, exp) in tests.iter() {
+            let (_, name) = label_def(test).expect("This shouldn't have errored");
+            assert_eq!(&name, exp);
+        }
+        assert!(label_def(&b".org $8000"[..]).is_err());
+    }
+    #[test]
+    fn test_labeled_line() {
+        use super::parse_line;
+        use crate::parser::LineType;
+        let tests = [
+            &b"loop: DEX"[..],
+            &b"_start:\tLDA #$00"[..],
+            &b": BNE :-"[..],
+        ];
+        for test in tests.iter() {
+            let (_, lines) = parse_line(test).expect("This shouldn't have errored");
+            assert_eq!(lines.len(), 2);
+            assert!(matches!(lines[1], LineType::Opcode(_)));
+        }
+    }
+    #[test]
+    fn test_const_def() {
+        use super::const_def;
+        use crate::parser::Expr;
+        let (_, (name, value)) = const_def(&b"size = * - start"[..]).expect(":(");
+        assert_eq!(name, "size");
+        assert!(matches!(value, Expr::Binary(..)));
+        assert!(const_def(&b"  LDA #$00"[..]).is_err());
+    }
+    #[test]
     fn test_line() {
         use crate::parser::parse_line;
         let code: &str = include_str!("../../assembly/general/custom.asm");
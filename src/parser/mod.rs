@@ -3,14 +3,235 @@ use crate::nom;
 use crate::opcodes::OpcodeType;
 
 mod arguments;
+mod expressions;
 mod helpers;
 mod lines;
 mod types;
 
 // Private (for submodules)
-use helpers::{bin_to_hex, eof, is_symbol, u8_to_hex};
+use helpers::{bin_to_hex, comma, is_symbol, label_identifier, line_end, u8_to_hex};
 use types::ArgumentType;
 
 // Public exports
-pub use lines::parse_line;
-pub use types::{LineType, NomError, Value};
+pub use expressions::Expr;
+pub use lines::{parse_line, parse_source};
+pub use types::{Assertion, Comparison, Expectation, LineType, NomError, Target, Value};
+
+mod tests {
+    #[test]
+    fn test_grammar_in_sync() {
+        use super::parse_line;
+        // Source lines that go through each rule of the grammar (accepted, rejected).
+        // Every rule of the EBNF file must be here and nothing else,
+        // so changing one of them without the other makes this test fail
+        let examples: &[(&str, &[&str], &[&str])] = &[
+            ("space", &["  LDA\t#$00", "  LDA #$00"], &["  LDA#$00"]),
+            (
+                "margin",
+                &["NOP", "\tNOP", "    NOP", " \t NOP", "  main:"],
+                &[],
+            ),
+            (
+                "comma",
+                &["  LDA $10 , X", "  LDA $10,\tX"],
+                &["  LDA $10 X"],
+            ),
+            (
+                "line_end",
+                &["  NOP  ", "  NOP\r\n", "main: \r"],
+                &["  NOP\r\r"],
+            ),
+            ("bin_digit", &["  LDA #%01010101"], &["  LDA #%01210101"]),
+            ("digit", &["  LDA #10", "  LDA $10"], &[]),
+            ("hex_digit", &["  LDA $fA", "  LDA $1234"], &["  LDA $G0"]),
+            ("letter", &["lbl:", "LBL:"], &[]),
+            ("symbol", &[".incbin \"../data.bin\""], &[]),
+            (
+                "characters",
+                &[".incbin \"data.bin\"", "NOP ; A comment"],
+                &[],
+            ),
+            ("a", &["  ASL A", "  asl a"], &[]),
+            ("hex_addr_short", &["  LDA $10"], &["  LDA $1"]),
+            ("hex_addr_long", &["  LDA $1000"], &[]),
+            ("hex_value", &["  LDA #$10"], &[]),
+            ("bin_value", &["  LDA #%00001111"], &[]),
+            ("indexed_indirect", &["  LDA ($10,X)"], &[]),
+            (
+                "absolute_indexed",
+                &["  LDA $1000,X", "  LDA $1000,Y", "  lda $1000,x"],
+                &[],
+            ),
+            (
+                "identifier",
+                &["_start:", "ppu_ctrl:", "Reset_Handler:", "x2:"],
+                &["2x:", "a-b:"],
+            ),
+            (
+                "label_name",
+                &["@loop:", ".loop:", "  JMP Player.update"],
+                &["@:", "a..b:"],
+            ),
+            (
+                "text",
+                &[".incbin \"data.bin\"", ".test \"adds one\""],
+                &[".incbin \"data.bin"],
+            ),
+            ("array", &[".db $DE,$AD", ".db $DE, $AD, $BE"], &[]),
+            ("number", &["  LDA #$1+%10+3"], &[]),
+            ("pc", &["  BNE *-2", "  JMP $+3"], &[]),
+            ("anonymous_ref", &["  BNE :-", "  BEQ :++"], &["  BNE :"]),
+            ("primary", &["  LDA #(1+2)"], &["  LDA #(1+2"]),
+            (
+                "unary",
+                &[
+                    "  LDA #<label",
+                    "  LDX #>label",
+                    "  LDY #-1",
+                    "  LDA #~$0F",
+                    "  LDA #^label",
+                ],
+                &[],
+            ),
+            ("product", &["  LDA #2*3", "  LDA #6/3", "  LDA #7%2"], &[]),
+            ("sum", &["  LDA #2+3", "  LDA #3 - 2"], &[]),
+            ("shift", &["  LDA #1<<2", "  LDA #8 >> 1"], &[]),
+            ("bit_and", &["  LDA #3&1"], &[]),
+            ("bit_xor", &["  LDA #3^1"], &[]),
+            (
+                "expression",
+                &["  LDA #3|4", "  LDA label+1"],
+                &["  LDA label+"],
+            ),
+            ("immediate_expr", &["  LDA #label"], &[]),
+            (
+                "indirect_expr",
+                &[
+                    "  JMP (vector)",
+                    "  LDA (ptr),Y",
+                    "  LDA (ptr,X)",
+                    "  LDA (ptr)",
+                    "  JMP (table,X)",
+                    "  LDA (3,S),Y",
+                ],
+                &[],
+            ),
+            (
+                "indirect_long_expr",
+                &["  LDA [ptr]", "  LDA [ptr],Y"],
+                &["  LDA [ptr"],
+            ),
+            (
+                "direct_expr",
+                &[
+                    "  LDA table,X",
+                    "  LDX table,Y",
+                    "  LDA 3,S",
+                    "  LDA $7E0000",
+                ],
+                &[],
+            ),
+            ("expr_pair", &["  BBR0 $10, loop"], &["  BBR0 $10,"]),
+            (
+                "opcode",
+                &["  NOP", "  ASL", "  ASLA", "  BLT *", "  SMB7 $10"],
+                &["  NO", "  XYZ", "  LDAA"],
+            ),
+            ("arg", &["  LDA label"], &[]),
+            (
+                "expectation_target",
+                &["  .expect pc == $8000", "  .expect mem[ptr + 1] == 0"],
+                &["  .expect Q == 1", "  .expect mem[ptr == 1"],
+            ),
+            (
+                "comparison",
+                &["  .expect A != 1", "  .expect X <= 2"],
+                &["  .expect A = 1"],
+            ),
+            ("expectation", &["  .expect c==1"], &["  .expect A =="]),
+            (
+                "expectations",
+                &["  .expect A == 1, Y > 2"],
+                &["  .expect A == 1,"],
+            ),
+            (
+                "assertion",
+                &[
+                    ".assert * <= $FFFA, \"The code runs into the vectors\"",
+                    ".assert >table==>end",
+                    ".assert size",
+                ],
+                &[".assert size <", ".assert size,"],
+            ),
+            (
+                "print_items",
+                &[".print \"size: \", end - start", ".print size"],
+                &[".print \"size: \",", ".print \"size"],
+            ),
+            ("label_def", &["main:"], &["main"]),
+            ("anon_label_def", &[":"], &[]),
+            ("const_def", &["size = end - start", "size=2"], &["size = "]),
+            ("instruction", &["  CLC", "  LDA #$00"], &[]),
+            ("macro", &[".org $8000", ".endlogical", ".a16"], &[".16"]),
+            (
+                "labeled_line",
+                &["loop: DEX", ": BNE :-", "table: .byte $00"],
+                &["loop:DEX"],
+            ),
+            ("line", &["main:", "", " \t "], &[]),
+            ("comment", &["NOP ;"], &[]),
+        ];
+
+        let grammar = include_str!("../../assembly_language_def.ebnf");
+        let mut rules: Vec<String> = Vec::new();
+        let mut rule = String::new();
+        for line in grammar
+            .lines()
+            .filter(|l| !l.trim_start().starts_with("(*"))
+        {
+            rule.push_str(line);
+            if line.trim_end().ends_with(';') {
+                let name = rule.split('=').next().unwrap().trim().to_string();
+                assert!(!rules.contains(&name), "The rule {} is defined twice", name);
+                rules.push(name);
+                rule.clear();
+            }
+        }
+        let documented: Vec<&str> = examples.iter().map(|(name, _, _)| *name).collect();
+        for name in &rules {
+            assert!(
+                documented.contains(&&name[..]),
+                "The rule {} has no examples",
+                name
+            );
+        }
+        for name in &documented {
+            assert!(
+                rules.iter().any(|r| r == name),
+                "{} isn't on the grammar",
+                name
+            );
+        }
+
+        for (name, accepted, rejected) in examples {
+            for line in accepted.iter() {
+                // Comments are removed before parsing
+                let line = line.split(';').next().unwrap();
+                match parse_line(line.as_bytes()) {
+                    Ok((rest, _)) => {
+                        assert!(rest.is_empty(), "{}: {:?} left {:?}", name, line, rest)
+                    }
+                    Err(e) => panic!("{}: {:?} should have been accepted. {:?}", name, line, e),
+                }
+            }
+            for line in rejected.iter() {
+                if let Ok(([], res)) = parse_line(line.as_bytes()) {
+                    panic!(
+                        "{}: {:?} should have been rejected, got {:?}",
+                        name, line, res
+                    )
+                }
+            }
+        }
+    }
+}
@@ -1,57 +1,117 @@
-use super::{AddressingMode, OpcodeType};
+use super::{AddressingMode, Expr, OpcodeType};
 
 pub type NomError<'i> = nom::Err<(&'i [u8], nom::error::ErrorKind)>;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum Value {
     Short(u8),
     Long(u16),
+    /// Ej.: `$7E2000` (65816 long address)
+    Far(u32),
     Label(String),
     Array(Vec<Value>),
     Text(Box<[u8]>),
+    Expr(Expr),
+    /// Ej.: `$10,label` (a zero page address and a branch target)
+    Pair(Expr, Expr),
+    /// Ej.: `A == $05, mem[$0200] == $FF` (on `.expect`)
+    Expectations(Vec<Expectation>),
+    /// Ej.: `* <= $FFFA, "The code runs into the vectors"` (on `.assert`)
+    Assertion(Assertion),
     None,
 }
 
 impl Value {
     pub fn is_short(&self) -> bool {
-        match self {
-            Value::Short(_) => true,
-            _ => false,
-        }
+        matches!(self, Value::Short(_))
     }
     pub fn is_long(&self) -> bool {
-        match self {
-            Value::Long(_) => true,
-            _ => false,
-        }
+        matches!(self, Value::Long(_))
+    }
+    pub fn is_far(&self) -> bool {
+        matches!(self, Value::Far(_))
     }
     pub fn is_label(&self) -> bool {
-        match self {
-            Value::Label(_) => true,
-            _ => false,
-        }
+        matches!(self, Value::Label(_))
     }
     pub fn is_array(&self) -> bool {
-        match self {
-            Value::Array(_) => true,
-            _ => false,
-        }
+        matches!(self, Value::Array(_))
     }
     pub fn is_text(&self) -> bool {
-        match self {
-            Value::Text(_) => true,
-            _ => false,
-        }
+        matches!(self, Value::Text(_))
+    }
+    pub fn is_expr(&self) -> bool {
+        matches!(self, Value::Expr(_))
+    }
+    pub fn is_pair(&self) -> bool {
+        matches!(self, Value::Pair(..))
+    }
+    pub fn is_expectations(&self) -> bool {
+        matches!(self, Value::Expectations(_))
+    }
+    pub fn is_assertion(&self) -> bool {
+        matches!(self, Value::Assertion(_))
     }
     pub fn is_none(&self) -> bool {
+        matches!(self, Value::None)
+    }
+}
+/// A condition of `.expect`, Ej.: `mem[$0200] == $FF`
+#[derive(Debug, PartialEq, Clone)]
+pub struct Expectation {
+    pub target: Target,
+    pub comparison: Comparison,
+    pub value: Expr,
+    /// As it was written
+    pub text: String,
+}
+/// The condition of `.assert`, it holds if `lhs` isn't 0 when there is no comparison
+#[derive(Debug, PartialEq, Clone)]
+pub struct Assertion {
+    pub lhs: Expr,
+    pub check: Option<(Comparison, Expr)>,
+    /// The condition as it was written
+    pub text: String,
+    pub message: Option<String>,
+}
+/// What an `Expectation` looks at
+#[derive(Debug, PartialEq, Clone)]
+pub enum Target {
+    A,
+    X,
+    Y,
+    S,
+    P,
+    PC,
+    /// A flag of P (0 or 1), with the bit from `opcodes::flags`
+    Flag(u8),
+    /// Ej.: `mem[$0200]`
+    Memory(Expr),
+}
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+impl Comparison {
+    pub fn holds(self, found: isize, expected: isize) -> bool {
         match self {
-            Value::None => true,
-            _ => false,
+            Comparison::Equal => found == expected,
+            Comparison::NotEqual => found != expected,
+            Comparison::Less => found < expected,
+            Comparison::LessOrEqual => found <= expected,
+            Comparison::Greater => found > expected,
+            Comparison::GreaterOrEqual => found >= expected,
         }
     }
 }
+
 impl std::convert::TryFrom<Vec<&[u8]>> for Value {
-    type Error = crate::Error;
+    type Error = crate::error::Error;
     fn try_from(v: Vec<&[u8]>) -> Result<Self, Self::Error> {
         Ok(Value::Array(
             v.into_iter()
@@ -76,14 +136,18 @@ mod tests {
 }
 
 pub type ArgumentType = (AddressingMode, Value);
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Opcode {
     pub name: OpcodeType,
     pub arg: ArgumentType,
 }
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum LineType {
     Opcode(Opcode),
     LabelDef(String),
+    /// A bare `:`, referenced with `:-` or `:+`
+    AnonLabelDef,
+    /// Ej.: `size = * - start`
+    ConstDef(String, Expr),
     Macro(String, Value),
 }
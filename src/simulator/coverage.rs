@@ -0,0 +1,103 @@
+use super::Machine;
+use crate::assembler::Assembled;
+use std::collections::BTreeMap;
+
+/// How many times each address was run
+pub struct Executed {
+    counts: Vec<u64>,
+}
+impl Default for Executed {
+    fn default() -> Self {
+        Executed {
+            counts: vec![0; 0x10000],
+        }
+    }
+}
+impl Executed {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Count the instruction on the PC, to use as the `before_step` of `Machine::run_with`
+    pub fn record(&mut self, machine: &Machine) {
+        self.counts[machine.registers.pc as usize] += 1;
+    }
+}
+
+/// How many times the instructions of each source line were run,
+/// added up over all the runs (the tests assemble the code in different ways)
+#[derive(Default)]
+pub struct Coverage {
+    /// Only lines with instructions are on it
+    hits: BTreeMap<usize, u64>,
+}
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Add a run of `code`
+    pub fn add(&mut self, code: &Assembled, executed: &Executed) {
+        for instruction in &code.instructions {
+            let count = executed
+                .counts
+                .get(instruction.address)
+                .copied()
+                .unwrap_or_default();
+            *self.hits.entry(instruction.line).or_default() += count;
+        }
+    }
+    /// `source` with the times each line ran in front, like `gcov`.
+    /// Ej.: `    #####:   12:    JSR update` for an instruction that never ran,
+    /// or `        -:   13:; comment` for a line without instructions
+    pub fn listing(&self, source: &str) -> String {
+        let mut listing = String::new();
+        for (i, text) in source.lines().enumerate() {
+            let count = match self.hits.get(&(i + 1)) {
+                Some(0) => "#####".to_string(),
+                Some(count) => count.to_string(),
+                None => "-".to_string(),
+            };
+            listing.push_str(&format!("{:>9}:{:>5}:{}\n", count, i + 1, text));
+        }
+        listing
+    }
+    /// LCOV tracefile of `file`, the source that was assembled
+    pub fn lcov(&self, file: &str) -> String {
+        let mut lcov = format!("TN:\nSF:{}\n", file);
+        for (line, count) in &self.hits {
+            lcov.push_str(&format!("DA:{},{}\n", line, count));
+        }
+        let hit = self.hits.values().filter(|&&count| count > 0).count();
+        lcov.push_str(&format!(
+            "LH:{}\nLF:{}\nend_of_record\n",
+            hit,
+            self.hits.len()
+        ));
+        lcov
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_coverage() {
+        use super::{Coverage, Executed};
+        use crate::assembler::{assemble_source, Metadata};
+        use crate::simulator::Machine;
+        let source = ".org $8000\n\tLDX #3\nloop:\n\tDEX\n\tBNE loop\n\tBRK\n\tNOP ; never runs\n.org $FFFC\n.dw $8000";
+        let code = assemble_source(source, &Metadata::default()).unwrap();
+        let mut machine = Machine::new(code.cpu, &code).unwrap();
+        machine.reset(None);
+        let mut executed = Executed::new();
+        machine.run_with(1000, &[], &mut |m| executed.record(m));
+        let mut coverage = Coverage::new();
+        coverage.add(&code, &executed);
+        let listing = coverage.listing(source);
+        let listing: Vec<&str> = listing.lines().collect();
+        assert_eq!(listing[1], "        1:    2:\tLDX #3");
+        assert_eq!(listing[3], "        3:    4:\tDEX");
+        assert_eq!(listing[6], "    #####:    7:\tNOP ; never runs");
+        assert_eq!(listing[7], "        -:    8:.org $FFFC");
+        let lcov = coverage.lcov("loop.asm");
+        assert!(lcov.starts_with("TN:\nSF:loop.asm\nDA:2,1\nDA:4,3\nDA:5,3\nDA:6,1\nDA:7,0\n"));
+        assert!(lcov.ends_with("LH:4\nLF:5\nend_of_record\n"));
+    }
+}
@@ -0,0 +1,237 @@
+use crate::error::Error;
+use std::collections::VecDeque;
+use std::io::Write;
+
+/// CPU cycles on each frame of an NTSC NES
+const CYCLES_PER_FRAME: u64 = 29781;
+
+/// Something on the bus that takes the reads and writes of some addresses instead of the RAM
+pub trait Device {
+    /// How many addresses it takes, from the one it's attached on
+    fn size(&self) -> u16 {
+        1
+    }
+    /// `offset` is counted from the first address of the device, `cycles` since the reset
+    fn read(&mut self, offset: u16, cycles: u64) -> u8;
+    fn write(&mut self, offset: u16, value: u8, cycles: u64);
+    /// Called by `Machine::reset`, that counts the cycles from 7 again
+    fn reset(&mut self) {}
+}
+
+/// Writes each byte stored on it as a character, Ej.: `STA $F001`
+pub struct CharOut<W: Write> {
+    output: W,
+}
+impl<W: Write> CharOut<W> {
+    pub fn new(output: W) -> Self {
+        CharOut { output }
+    }
+}
+impl<W: Write> Device for CharOut<W> {
+    fn read(&mut self, _: u16, _: u64) -> u8 {
+        0
+    }
+    fn write(&mut self, _: u16, value: u8, _: u64) {
+        // There is nowhere to report the error from the middle of an instruction
+        let _ = self.output.write_all(&[value]);
+        if value == b'\n' {
+            let _ = self.output.flush();
+        }
+    }
+}
+
+/// Cycles since the reset or the last write, on 4 bytes in little endian.
+/// Reading the first one takes the count the others return
+#[derive(Default)]
+pub struct Timer {
+    since: u64,
+    latched: u32,
+}
+impl Timer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+impl Device for Timer {
+    fn size(&self) -> u16 {
+        4
+    }
+    fn read(&mut self, offset: u16, cycles: u64) -> u8 {
+        if offset == 0 {
+            self.latched = cycles.saturating_sub(self.since) as u32;
+        }
+        self.latched.to_le_bytes()[offset as usize]
+    }
+    fn write(&mut self, _: u16, _: u8, cycles: u64) {
+        self.since = cycles;
+    }
+    fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+/// Bytes for the program to read: the first address takes the next one (0 once they run out),
+/// the second one is 1 while there are bytes left
+pub struct InputFifo {
+    bytes: VecDeque<u8>,
+}
+impl InputFifo {
+    pub fn new(bytes: Vec<u8>) -> Self {
+        InputFifo {
+            bytes: bytes.into(),
+        }
+    }
+}
+impl Device for InputFifo {
+    fn size(&self) -> u16 {
+        2
+    }
+    fn read(&mut self, offset: u16, _: u64) -> u8 {
+        match offset {
+            0 => self.bytes.pop_front().unwrap_or(0),
+            _ => !self.bytes.is_empty() as u8,
+        }
+    }
+    fn write(&mut self, _: u16, _: u8, _: u64) {}
+}
+
+/// A NES controller on `$4016` that presses the buttons of a recording, one state per frame.
+/// Writing 1 and then 0 latches the buttons, and each read returns the next one
+/// (A, B, Select, Start, Up, Down, Left, Right), and 1 after them
+pub struct Controller {
+    /// Buttons of each frame, A on bit 0
+    frames: Vec<u8>,
+    strobe: bool,
+    shift: u8,
+    /// Buttons returned so far
+    read: u8,
+}
+impl Controller {
+    pub fn new(frames: Vec<u8>) -> Self {
+        Controller {
+            frames,
+            strobe: false,
+            shift: 0,
+            read: 8,
+        }
+    }
+    /// The input log of an FCEUX movie (.fm2): lines like `|0|R..U...A|........||`,
+    /// where the first controller is written as `RLDUTSBA`, with `.` for the buttons up
+    pub fn from_fm2(movie: &str) -> Result<Self, Error> {
+        let frames = movie
+            .lines()
+            .filter(|line| line.starts_with('|'))
+            .map(|line| {
+                let buttons = line.split('|').nth(2).unwrap_or_default();
+                if buttons.len() != 8 {
+                    return Err(Error::Simulator {
+                        cause: format!("'{}' doesn't have the 8 buttons of a controller", line),
+                    });
+                }
+                Ok(buttons
+                    .chars()
+                    .fold(0, |bits, c| bits << 1 | (c != '.' && c != ' ') as u8))
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(Controller::new(frames))
+    }
+    fn buttons(&self, cycles: u64) -> u8 {
+        let frame = (cycles / CYCLES_PER_FRAME) as usize;
+        self.frames.get(frame).copied().unwrap_or(0)
+    }
+}
+impl Device for Controller {
+    fn read(&mut self, _: u16, cycles: u64) -> u8 {
+        if self.strobe {
+            return self.buttons(cycles) & 1;
+        }
+        if self.read >= 8 {
+            return 1;
+        }
+        self.read += 1;
+        let bit = self.shift & 1;
+        self.shift >>= 1;
+        bit
+    }
+    fn write(&mut self, _: u16, value: u8, cycles: u64) {
+        let strobe = value & 1 != 0;
+        if self.strobe && !strobe {
+            self.shift = self.buttons(cycles);
+            self.read = 0;
+        }
+        self.strobe = strobe;
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_devices() {
+        use super::{CharOut, Controller, Device, InputFifo, Timer};
+        use crate::opcodes::Cpu;
+        use crate::simulator::{Machine, Stop};
+        use std::cell::RefCell;
+        use std::io::Write;
+        use std::rc::Rc;
+        #[derive(Clone, Default)]
+        struct Shared(Rc<RefCell<Vec<u8>>>);
+        impl Write for Shared {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.borrow_mut().write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        let code = [
+            0xA9, 0x48, 0x8D, 0x01, 0xF0, // LDA #'H', STA $F001
+            0xA9, 0x49, 0x8D, 0x01, 0xF0, // LDA #'I', STA $F001
+            0xAE, 0x04, 0xF0, // LDX $F004
+            0xAC, 0x05, 0xF0, // LDY $F005
+            0xA9, 0x01, 0x8D, 0x16, 0x40, // LDA #$01, STA $4016
+            0xA9, 0x00, 0x8D, 0x16, 0x40, // LDA #$00, STA $4016
+            0xAD, 0x16, 0x40, // LDA $4016
+            0x00, // BRK
+        ];
+        let mut image = vec![0u8; 0x10000];
+        image[0x8000..0x8000 + code.len()].copy_from_slice(&code);
+        let output = Shared::default();
+        let movie = "version 3\n|0|.......A|........||\n";
+        let mut machine = Machine::builder(Cpu::Mos6502)
+            .image(&image)
+            .device(0xF001, CharOut::new(output.clone()))
+            .device(0xF004, InputFifo::new(b"x".to_vec()))
+            .device(0x4016, Controller::from_fm2(movie).unwrap())
+            .start(0x8000)
+            .build()
+            .unwrap();
+        assert_eq!(machine.run(1000, &[]), Stop::Break(0x801D));
+        assert_eq!(&output.0.borrow()[..], b"HI");
+        assert_eq!(machine.registers.x, b'x');
+        assert_eq!(machine.registers.y, 0);
+        assert_eq!(machine.registers.a, 1);
+        // The RAM under the devices isn't touched
+        assert_eq!(machine.memory()[0xF001], 0);
+
+        let mut timer = Timer::new();
+        timer.write(0, 0, 100);
+        assert_eq!(timer.read(0, 100 + 0x1234), 0x34);
+        assert_eq!(timer.read(1, 100 + 0x5678), 0x12);
+        assert_eq!(timer.read(0, 50), 0);
+        // The reset counts the cycles from 7 again, and the timer with them
+        let mut machine = Machine::builder(Cpu::Mos6502)
+            .image(&image)
+            .device(0xF010, Timer::new())
+            .build()
+            .unwrap();
+        machine.cycles = 1000;
+        machine.write(0xF010, 0);
+        machine.reset(Some(0x8000));
+        assert_eq!(machine.read(0xF010), 7);
+        let mut controller = Controller::from_fm2("|0|R..U...A|........||\n").unwrap();
+        controller.write(0, 1, 0);
+        controller.write(0, 0, 0);
+        let buttons: Vec<u8> = (0..9).map(|_| controller.read(0, 0)).collect();
+        assert_eq!(buttons, [1, 0, 0, 0, 1, 0, 0, 1, 1]);
+        assert!(Controller::from_fm2("|0|RL|||").is_err());
+    }
+}
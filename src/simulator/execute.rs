@@ -0,0 +1,553 @@
+use super::Machine;
+use crate::addressing_modes::AddressingMode;
+use crate::opcodes::flags::{C, D, I, M, N, V, X, Z};
+use crate::opcodes::{Cpu, OpcodeData, OpcodeType};
+
+/// What an instruction works on
+#[derive(Clone, Copy)]
+enum Operand {
+    None,
+    Accumulator,
+    Immediate(u8),
+    Address(u16),
+}
+
+fn same_page(a: u16, b: u16) -> bool {
+    a & 0xFF00 == b & 0xFF00
+}
+
+impl Machine {
+    fn is_nmos(&self) -> bool {
+        self.cpu != Cpu::Wdc65C02
+    }
+    /// The 2A03 ignores the D flag
+    fn decimal_mode(&self) -> bool {
+        self.flag(D) && self.cpu != Cpu::Ricoh2A03
+    }
+    fn flag(&self, flag: u8) -> bool {
+        self.registers.p & flag != 0
+    }
+    fn set_flag(&mut self, flag: u8, value: bool) {
+        if value {
+            self.registers.p |= flag;
+        } else {
+            self.registers.p &= !flag;
+        }
+    }
+    fn set_nz(&mut self, value: u8) {
+        self.set_flag(N, value & 0x80 != 0);
+        self.set_flag(Z, value == 0);
+    }
+    pub(super) fn push(&mut self, value: u8) {
+        self.write(0x100 | self.registers.s as u16, value);
+        self.registers.s = self.registers.s.wrapping_sub(1);
+    }
+    fn pull(&mut self) -> u8 {
+        self.registers.s = self.registers.s.wrapping_add(1);
+        self.read(0x100 | self.registers.s as u16)
+    }
+    /// Pointers on the zero page wrap around it
+    fn read_zero_page_word(&mut self, addr: u8) -> u16 {
+        self.read(addr as u16) as u16 | (self.read(addr.wrapping_add(1) as u16) as u16) << 8
+    }
+
+    /// Where the operand is, and whether indexing it crossed a page
+    fn operand(&mut self, mode: AddressingMode, value: u16) -> (Operand, bool) {
+        let x = self.registers.x as u16;
+        let y = self.registers.y as u16;
+        let zero_page = value as u8;
+        let indexed = |base: u16, index: u16| {
+            let addr = base.wrapping_add(index);
+            (Operand::Address(addr), !same_page(base, addr))
+        };
+        match mode {
+            AddressingMode::IMPL => (Operand::None, false),
+            AddressingMode::A => (Operand::Accumulator, false),
+            AddressingMode::IMM => (Operand::Immediate(zero_page), false),
+            AddressingMode::ZPG | AddressingMode::ZPGREL => {
+                (Operand::Address(zero_page as u16), false)
+            }
+            AddressingMode::ZPGX => (
+                Operand::Address(zero_page.wrapping_add(x as u8) as u16),
+                false,
+            ),
+            AddressingMode::ZPGY => (
+                Operand::Address(zero_page.wrapping_add(y as u8) as u16),
+                false,
+            ),
+            AddressingMode::ABS => (Operand::Address(value), false),
+            AddressingMode::ABSX => indexed(value, x),
+            AddressingMode::ABSY => indexed(value, y),
+            AddressingMode::IND => {
+                // The NMOS CPUs don't carry to the high byte: `JMP ($10FF)` reads $10FF and $1000
+                let high = if self.is_nmos() {
+                    value & 0xFF00 | value.wrapping_add(1) & 0x00FF
+                } else {
+                    value.wrapping_add(1)
+                };
+                let addr = self.read(value) as u16 | (self.read(high) as u16) << 8;
+                (Operand::Address(addr), false)
+            }
+            AddressingMode::INDX => {
+                let addr = self.read_zero_page_word(zero_page.wrapping_add(x as u8));
+                (Operand::Address(addr), false)
+            }
+            AddressingMode::INDY => {
+                let base = self.read_zero_page_word(zero_page);
+                indexed(base, y)
+            }
+            AddressingMode::ZPGIND => {
+                (Operand::Address(self.read_zero_page_word(zero_page)), false)
+            }
+            AddressingMode::ABSINDX => {
+                let addr = self.read_word(value.wrapping_add(x));
+                (Operand::Address(addr), false)
+            }
+            AddressingMode::REL => {
+                let target = self.registers.pc.wrapping_add(zero_page as i8 as u16);
+                (Operand::Address(target), false)
+            }
+            _ => unreachable!("The simulator doesn't have the 65816 addressing modes"),
+        }
+    }
+    fn load(&mut self, operand: Operand) -> u8 {
+        match operand {
+            Operand::Immediate(value) => value,
+            Operand::Address(addr) => self.read(addr),
+            Operand::Accumulator => self.registers.a,
+            Operand::None => 0,
+        }
+    }
+    fn store(&mut self, operand: Operand, value: u8) {
+        match operand {
+            Operand::Address(addr) => self.write(addr, value),
+            Operand::Accumulator => self.registers.a = value,
+            _ => {}
+        }
+    }
+    /// Read, change and write back the operand. The NMOS CPUs write the value
+    /// they read before writing the result
+    fn modify(&mut self, operand: Operand, change: impl Fn(&mut Machine, u8) -> u8) -> u8 {
+        let value = self.load(operand);
+        if let (Operand::Address(addr), true) = (operand, self.is_nmos()) {
+            self.write(addr, value);
+        }
+        let result = change(self, value);
+        self.store(operand, result);
+        result
+    }
+    /// Take a branch to `target`, it takes longer when it goes to another page
+    fn branch(&mut self, opcode: &OpcodeData, target: u16) {
+        let penalty = opcode.page_penalty() as u64;
+        self.cycles += penalty;
+        if !same_page(self.registers.pc, target) {
+            self.cycles += penalty;
+        }
+        self.registers.pc = target;
+    }
+    fn compare(&mut self, register: u8, value: u8) {
+        self.set_flag(C, register >= value);
+        self.set_nz(register.wrapping_sub(value));
+    }
+    fn shift_left(&mut self, value: u8, carry_in: bool) -> u8 {
+        self.set_flag(C, value & 0x80 != 0);
+        let result = value << 1 | carry_in as u8;
+        self.set_nz(result);
+        result
+    }
+    fn shift_right(&mut self, value: u8, carry_in: bool) -> u8 {
+        self.set_flag(C, value & 0x01 != 0);
+        let result = value >> 1 | (carry_in as u8) << 7;
+        self.set_nz(result);
+        result
+    }
+    fn add_binary(&mut self, value: u8) {
+        let a = self.registers.a;
+        let sum = a as u16 + value as u16 + self.flag(C) as u16;
+        let result = sum as u8;
+        self.set_flag(V, (a ^ result) & (value ^ result) & 0x80 != 0);
+        self.set_flag(C, sum > 0xFF);
+        self.registers.a = result;
+        self.set_nz(result);
+    }
+    fn adc(&mut self, value: u8) {
+        if !self.decimal_mode() {
+            return self.add_binary(value);
+        }
+        let a = self.registers.a;
+        let carry = self.flag(C) as i16;
+        let binary = a.wrapping_add(value).wrapping_add(carry as u8);
+        // From "Decimal Mode" by Bruce Clark, on 6502.org
+        let mut low = (a & 0x0F) as i16 + (value & 0x0F) as i16 + carry;
+        if low >= 0x0A {
+            low = ((low + 0x06) & 0x0F) + 0x10;
+        }
+        let mut sum = (a & 0xF0) as i16 + (value & 0xF0) as i16 + low;
+        let signed = (a & 0xF0) as i8 as i16 + (value & 0xF0) as i8 as i16 + low;
+        self.set_flag(V, !(-128..=127).contains(&signed));
+        let negative = sum & 0x80 != 0;
+        if sum >= 0xA0 {
+            sum += 0x60;
+        }
+        self.set_flag(C, sum >= 0x100);
+        let result = sum as u8;
+        self.registers.a = result;
+        if self.is_nmos() {
+            // N comes from the result before fixing the high digit, Z from the binary sum
+            self.set_flag(N, negative);
+            self.set_flag(Z, binary == 0);
+        } else {
+            self.set_nz(result);
+            self.cycles += 1;
+        }
+    }
+    fn sbc(&mut self, value: u8) {
+        let a = self.registers.a;
+        let carry = self.flag(C) as i16;
+        let decimal = self.decimal_mode();
+        // The flags are the ones of the binary subtraction, but on the 65C02 N and Z
+        self.add_binary(!value);
+        if !decimal {
+            return;
+        }
+        let low = (a & 0x0F) as i16 - (value & 0x0F) as i16 + carry - 1;
+        let result = if self.is_nmos() {
+            let low = if low < 0 {
+                ((low - 0x06) & 0x0F) - 0x10
+            } else {
+                low
+            };
+            let mut difference = (a & 0xF0) as i16 - (value & 0xF0) as i16 + low;
+            if difference < 0 {
+                difference -= 0x60;
+            }
+            difference as u8
+        } else {
+            let mut difference = a as i16 - value as i16 + carry - 1;
+            if difference < 0 {
+                difference -= 0x60;
+            }
+            if low < 0 {
+                difference -= 0x06;
+            }
+            self.cycles += 1;
+            difference as u8
+        };
+        self.registers.a = result;
+        if !self.is_nmos() {
+            self.set_nz(result);
+        }
+    }
+    /// `ARR` (6502X): `AND` and `ROR` with odd flags, and odder ones on decimal mode
+    fn arr(&mut self, value: u8) {
+        let and = self.registers.a & value;
+        let result = and >> 1 | (self.flag(C) as u8) << 7;
+        if !self.decimal_mode() {
+            self.set_nz(result);
+            self.set_flag(C, result & 0x40 != 0);
+            self.set_flag(V, (result ^ result << 1) & 0x40 != 0);
+            self.registers.a = result;
+            return;
+        }
+        // From "NMOS 6510 Unintended Opcodes"
+        self.set_flag(N, self.flag(C));
+        self.set_flag(Z, result == 0);
+        self.set_flag(V, (and ^ result) & 0x40 != 0);
+        let mut result = result;
+        let (high, low) = (and >> 4, and & 0x0F);
+        if low + (low & 1) > 5 {
+            result = result & 0xF0 | result.wrapping_add(6) & 0x0F;
+        }
+        let carry = high + (high & 1) > 5;
+        if carry {
+            result = result.wrapping_add(0x60);
+        }
+        self.set_flag(C, carry);
+        self.registers.a = result;
+    }
+
+    /// Run `opcode`, the PC already points to the next instruction
+    pub(super) fn execute(&mut self, opcode: &OpcodeData, value: u16) {
+        use OpcodeType::*;
+        let name = opcode.name();
+        let (operand, crossed) = self.operand(opcode.addr_mode(), value);
+        if crossed {
+            self.cycles += opcode.page_penalty() as u64;
+        }
+        let target = match operand {
+            Operand::Address(addr) => addr,
+            _ => 0,
+        };
+        // Bit of `RMB0`-`SMB7` and `BBR0`-`BBS7`, the last character of the name
+        let bit = || 1u8 << (format!("{:?}", name).as_bytes()[3] - b'0');
+        match name {
+            ADC => {
+                let value = self.load(operand);
+                self.adc(value);
+            }
+            SBC => {
+                let value = self.load(operand);
+                self.sbc(value);
+            }
+            AND => {
+                self.registers.a &= self.load(operand);
+                self.set_nz(self.registers.a);
+            }
+            ORA => {
+                self.registers.a |= self.load(operand);
+                self.set_nz(self.registers.a);
+            }
+            EOR => {
+                self.registers.a ^= self.load(operand);
+                self.set_nz(self.registers.a);
+            }
+            ASL => {
+                self.modify(operand, |m, v| m.shift_left(v, false));
+            }
+            LSR => {
+                self.modify(operand, |m, v| m.shift_right(v, false));
+            }
+            ROL => {
+                let carry = self.flag(C);
+                self.modify(operand, |m, v| m.shift_left(v, carry));
+            }
+            ROR => {
+                let carry = self.flag(C);
+                self.modify(operand, |m, v| m.shift_right(v, carry));
+            }
+            BIT => {
+                let value = self.load(operand);
+                self.set_flag(Z, self.registers.a & value == 0);
+                // `BIT #` only changes Z
+                if opcode.addr_mode() != AddressingMode::IMM {
+                    self.set_flag(N, value & 0x80 != 0);
+                    self.set_flag(V, value & 0x40 != 0);
+                }
+            }
+            BCC | BCS | BEQ | BNE | BMI | BPL | BVC | BVS | BRA => {
+                let taken = match name {
+                    BCC => !self.flag(C),
+                    BCS => self.flag(C),
+                    BEQ => self.flag(Z),
+                    BNE => !self.flag(Z),
+                    BMI => self.flag(N),
+                    BPL => !self.flag(N),
+                    BVC => !self.flag(V),
+                    BVS => self.flag(V),
+                    _ => true,
+                };
+                if taken {
+                    self.branch(opcode, target);
+                }
+            }
+            BBR0 | BBR1 | BBR2 | BBR3 | BBR4 | BBR5 | BBR6 | BBR7 | BBS0 | BBS1 | BBS2 | BBS3
+            | BBS4 | BBS5 | BBS6 | BBS7 => {
+                let set = self.load(operand) & bit() != 0;
+                if set == format!("{:?}", name).starts_with("BBS") {
+                    let offset = (value >> 8) as u8 as i8 as u16;
+                    self.branch(opcode, self.registers.pc.wrapping_add(offset));
+                }
+            }
+            RMB0 | RMB1 | RMB2 | RMB3 | RMB4 | RMB5 | RMB6 | RMB7 => {
+                let bit = bit();
+                self.modify(operand, |_, v| v & !bit);
+            }
+            SMB0 | SMB1 | SMB2 | SMB3 | SMB4 | SMB5 | SMB6 | SMB7 => {
+                let bit = bit();
+                self.modify(operand, |_, v| v | bit);
+            }
+            CLC => self.set_flag(C, false),
+            CLD => self.set_flag(D, false),
+            CLI => self.set_flag(I, false),
+            CLV => self.set_flag(V, false),
+            SEC => self.set_flag(C, true),
+            SED => self.set_flag(D, true),
+            SEI => self.set_flag(I, true),
+            CMP => {
+                let value = self.load(operand);
+                self.compare(self.registers.a, value);
+            }
+            CPX => {
+                let value = self.load(operand);
+                self.compare(self.registers.x, value);
+            }
+            CPY => {
+                let value = self.load(operand);
+                self.compare(self.registers.y, value);
+            }
+            DEC => {
+                let result = self.modify(operand, |_, v| v.wrapping_sub(1));
+                self.set_nz(result);
+            }
+            INC => {
+                let result = self.modify(operand, |_, v| v.wrapping_add(1));
+                self.set_nz(result);
+            }
+            DEX => {
+                self.registers.x = self.registers.x.wrapping_sub(1);
+                self.set_nz(self.registers.x);
+            }
+            DEY => {
+                self.registers.y = self.registers.y.wrapping_sub(1);
+                self.set_nz(self.registers.y);
+            }
+            INX => {
+                self.registers.x = self.registers.x.wrapping_add(1);
+                self.set_nz(self.registers.x);
+            }
+            INY => {
+                self.registers.y = self.registers.y.wrapping_add(1);
+                self.set_nz(self.registers.y);
+            }
+            JMP => self.registers.pc = target,
+            JSR => {
+                let back = self.registers.pc.wrapping_sub(1);
+                self.push((back >> 8) as u8);
+                self.push(back as u8);
+                self.registers.pc = target;
+            }
+            RTS => {
+                let low = self.pull() as u16;
+                let high = self.pull() as u16;
+                self.registers.pc = (high << 8 | low).wrapping_add(1);
+            }
+            RTI => {
+                let p = self.pull();
+                self.registers.p = p & !X | M;
+                let low = self.pull() as u16;
+                let high = self.pull() as u16;
+                self.registers.pc = high << 8 | low;
+            }
+            LDA => {
+                self.registers.a = self.load(operand);
+                self.set_nz(self.registers.a);
+            }
+            LDX => {
+                self.registers.x = self.load(operand);
+                self.set_nz(self.registers.x);
+            }
+            LDY => {
+                self.registers.y = self.load(operand);
+                self.set_nz(self.registers.y);
+            }
+            STA => self.store(operand, self.registers.a),
+            STX => self.store(operand, self.registers.x),
+            STY => self.store(operand, self.registers.y),
+            STZ => self.store(operand, 0),
+            NOP => {
+                self.load(operand);
+            }
+            // The copy on the stack has B and bit 5 set
+            PHP => self.push(self.registers.p | X | M),
+            PHA => self.push(self.registers.a),
+            PHX => self.push(self.registers.x),
+            PHY => self.push(self.registers.y),
+            PLP => {
+                let p = self.pull();
+                self.registers.p = p & !X | M;
+            }
+            PLA => {
+                self.registers.a = self.pull();
+                self.set_nz(self.registers.a);
+            }
+            PLX => {
+                self.registers.x = self.pull();
+                self.set_nz(self.registers.x);
+            }
+            PLY => {
+                self.registers.y = self.pull();
+                self.set_nz(self.registers.y);
+            }
+            TAX => {
+                self.registers.x = self.registers.a;
+                self.set_nz(self.registers.x);
+            }
+            TAY => {
+                self.registers.y = self.registers.a;
+                self.set_nz(self.registers.y);
+            }
+            TSX => {
+                self.registers.x = self.registers.s;
+                self.set_nz(self.registers.x);
+            }
+            TXA => {
+                self.registers.a = self.registers.x;
+                self.set_nz(self.registers.a);
+            }
+            TYA => {
+                self.registers.a = self.registers.y;
+                self.set_nz(self.registers.a);
+            }
+            TXS => self.registers.s = self.registers.x,
+            TRB => {
+                let a = self.registers.a;
+                let value = self.load(operand);
+                self.set_flag(Z, a & value == 0);
+                self.store(operand, value & !a);
+            }
+            TSB => {
+                let a = self.registers.a;
+                let value = self.load(operand);
+                self.set_flag(Z, a & value == 0);
+                self.store(operand, value | a);
+            }
+            // Undocumented (6502X)
+            LAX => {
+                let value = self.load(operand);
+                self.registers.a = value;
+                self.registers.x = value;
+                self.set_nz(value);
+            }
+            SAX => self.store(operand, self.registers.a & self.registers.x),
+            DCP => {
+                let result = self.modify(operand, |_, v| v.wrapping_sub(1));
+                self.compare(self.registers.a, result);
+            }
+            ISC => {
+                let result = self.modify(operand, |_, v| v.wrapping_add(1));
+                self.sbc(result);
+            }
+            SLO => {
+                let result = self.modify(operand, |m, v| m.shift_left(v, false));
+                self.registers.a |= result;
+                self.set_nz(self.registers.a);
+            }
+            RLA => {
+                let carry = self.flag(C);
+                let result = self.modify(operand, |m, v| m.shift_left(v, carry));
+                self.registers.a &= result;
+                self.set_nz(self.registers.a);
+            }
+            SRE => {
+                let result = self.modify(operand, |m, v| m.shift_right(v, false));
+                self.registers.a ^= result;
+                self.set_nz(self.registers.a);
+            }
+            RRA => {
+                let carry = self.flag(C);
+                let result = self.modify(operand, |m, v| m.shift_right(v, carry));
+                self.adc(result);
+            }
+            ANC => {
+                self.registers.a &= self.load(operand);
+                self.set_nz(self.registers.a);
+                self.set_flag(C, self.flag(N));
+            }
+            ALR => {
+                let value = self.registers.a & self.load(operand);
+                self.registers.a = self.shift_right(value, false);
+            }
+            ARR => {
+                let value = self.load(operand);
+                self.arr(value);
+            }
+            AXS => {
+                let value = self.load(operand);
+                let and = self.registers.a & self.registers.x;
+                self.compare(and, value);
+                self.registers.x = and.wrapping_sub(value);
+            }
+            _ => unreachable!("{:?} isn't an instruction of the {}", name, self.cpu),
+        }
+    }
+}
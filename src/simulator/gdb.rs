@@ -0,0 +1,543 @@
+use super::{Access, Machine, Stop, Watchpoint};
+use crate::assembler::{Assembled, Label, SourceLine};
+use crate::error::Error;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+/// Registers in the order of `g`: A, X, Y, S and P take a byte, PC takes two
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.gnu.gdb.6502.cpu">
+    <reg name="a" bitsize="8" regnum="0"/>
+    <reg name="x" bitsize="8"/>
+    <reg name="y" bitsize="8"/>
+    <reg name="s" bitsize="8"/>
+    <reg name="p" bitsize="8"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+/// Cycles run between checks for a Ctrl-C from GDB
+const CHUNK: u64 = 10_000;
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if text.len() % 2 != 0 {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Ej.: `8000,2` -> `(0x8000, 2)`
+fn address_and_length(text: &str) -> Option<(usize, usize)> {
+    let mut parts = text.splitn(2, ',');
+    let addr = usize::from_str_radix(parts.next()?, 16).ok()?;
+    let len = usize::from_str_radix(parts.next()?, 16).ok()?;
+    Some((addr, len))
+}
+
+/// The addresses of `len` bytes from `addr`, if they are all in the first 64KiB
+fn memory_range(addr: usize, len: usize) -> Option<std::ops::Range<usize>> {
+    let end = addr.checked_add(len)?;
+    if end <= 0x10000 {
+        Some(addr..end)
+    } else {
+        None
+    }
+}
+
+/// `$packet#checksum`
+fn frame(packet: &str) -> String {
+    let checksum = packet.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+    format!("${}#{:02x}", packet, checksum)
+}
+
+/// `m`/`l` and the part of `data` that `offset,length` asks for, like `qXfer` answers
+fn transfer(data: &str, args: &str) -> String {
+    match address_and_length(args) {
+        Some((offset, len)) if offset <= data.len() => {
+            let end = offset.saturating_add(len).min(data.len());
+            let more = if end < data.len() { "m" } else { "l" };
+            format!("{}{}", more, data.get(offset..end).unwrap_or_default())
+        }
+        _ => "E01".to_string(),
+    }
+}
+
+/// The state of a debugging session, answering the packets of GDB's remote protocol.
+/// The symbols and the lines of the source come from the ELF file of `exec_file`
+/// (`Assembled::elf`), and the `monitor` commands reach them too
+pub struct Stub {
+    machine: Machine,
+    breakpoints: Vec<u16>,
+    instructions: Vec<SourceLine>,
+    /// Sorted by address
+    labels: Vec<Label>,
+    /// Name of the source, for the locations
+    file: String,
+    /// Answer to `?`
+    last_stop: String,
+    /// ELF file GDB loads when it connects, told with `qXfer:exec-file:read`
+    pub exec_file: Option<String>,
+    /// GDB asked not to acknowledge the packets
+    pub no_ack: bool,
+    /// GDB detached or killed the program
+    pub finished: bool,
+}
+impl Stub {
+    /// Debug `machine`, that runs `code` assembled from `file`
+    pub fn new(machine: Machine, code: &Assembled, file: &str) -> Self {
+        let mut labels = code.labels.clone();
+        labels.sort_by_key(|label| label.address);
+        Stub {
+            machine,
+            breakpoints: Vec::new(),
+            instructions: code.instructions.clone(),
+            labels,
+            file: file.to_string(),
+            last_stop: "S05".to_string(),
+            exec_file: None,
+            no_ack: false,
+            finished: false,
+        }
+    }
+
+    /// Ej.: `program.asm:12 (main+$3)`
+    pub fn location(&self, addr: u16) -> String {
+        let addr = addr as usize;
+        let line = self
+            .instructions
+            .iter()
+            .rev()
+            .find(|i| addr >= i.address && addr < i.address + i.size);
+        let label = self.labels.iter().rev().find(|label| label.address <= addr);
+        let mut location = match line {
+            Some(line) => format!("{}:{}", self.file, line.line),
+            None => format!("${:04X}", addr),
+        };
+        match label {
+            Some(label) if label.address == addr => location += &format!(" ({})", label.name),
+            Some(label) => location += &format!(" ({}+${:X})", label.name, addr - label.address),
+            None => {}
+        }
+        location
+    }
+
+    /// Address of a label, or of the first instruction of a line
+    fn find(&self, name: &str) -> Option<u16> {
+        if let Ok(line) = name.parse::<usize>() {
+            return self
+                .instructions
+                .iter()
+                .find(|i| i.line == line)
+                .map(|i| i.address as u16);
+        }
+        self.labels
+            .iter()
+            .find(|label| label.name == name)
+            .map(|label| label.address as u16)
+    }
+
+    /// `monitor` commands, that use the labels and lines of the source
+    fn monitor(&mut self, command: &str) -> String {
+        let mut words = command.split_whitespace();
+        match (words.next(), words.next()) {
+            (Some("where"), None) => self.location(self.machine.registers.pc),
+            (Some("symbols"), filter) => self
+                .labels
+                .iter()
+                .filter(|label| filter.map_or(true, |filter| label.name.contains(filter)))
+                .map(|label| format!("${:04X} {}", label.address, label.name))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            (Some("address"), Some(name)) => match self.find(name) {
+                Some(addr) => format!("${:04X} {}", addr, self.location(addr)),
+                None => format!("'{}' isn't a label or a line with code", name),
+            },
+            (Some("break"), Some(name)) => match self.find(name) {
+                Some(addr) => {
+                    self.breakpoints.push(addr);
+                    format!("Breakpoint on ${:04X} {}", addr, self.location(addr))
+                }
+                None => format!("'{}' isn't a label or a line with code", name),
+            },
+            _ => "Commands: where, symbols [filter], address <label|line>, break <label|line>"
+                .to_string(),
+        }
+    }
+
+    /// Run one instruction, or until a breakpoint, a watchpoint or `interrupted`
+    fn resume(&mut self, step: bool, interrupted: &mut dyn FnMut() -> bool) -> String {
+        // The instruction on the PC runs even if it has a breakpoint
+        let mut stop = self.machine.step();
+        while stop.is_none() && !step {
+            if interrupted() {
+                return "S02".to_string();
+            }
+            let limit = self.machine.cycles + CHUNK;
+            match self.machine.run(limit, &self.breakpoints) {
+                Stop::CycleLimit => {}
+                other => stop = Some(other),
+            }
+        }
+        match stop {
+            Some(Stop::Halt(_)) => "S04".to_string(),
+            Some(Stop::Watch(addr, access)) => {
+                let kind = match access {
+                    Access::Write => "watch",
+                    Access::Read => "rwatch",
+                    Access::Any => "awatch",
+                };
+                format!("T05{}:{:04x};", kind, addr)
+            }
+            _ => "S05".to_string(),
+        }
+    }
+
+    fn read_register(&self, number: usize) -> Option<Vec<u8>> {
+        let registers = &self.machine.registers;
+        match number {
+            0 => Some(vec![registers.a]),
+            1 => Some(vec![registers.x]),
+            2 => Some(vec![registers.y]),
+            3 => Some(vec![registers.s]),
+            4 => Some(vec![registers.p]),
+            5 => Some(registers.pc.to_le_bytes().to_vec()),
+            _ => None,
+        }
+    }
+
+    fn write_register(&mut self, number: usize, value: &[u8]) -> Option<()> {
+        let registers = &mut self.machine.registers;
+        match (number, value) {
+            (0, &[a]) => registers.a = a,
+            (1, &[x]) => registers.x = x,
+            (2, &[y]) => registers.y = y,
+            (3, &[s]) => registers.s = s,
+            (4, &[p]) => registers.p = p,
+            (5, &[low, high]) => registers.pc = u16::from_le_bytes([low, high]),
+            _ => return None,
+        }
+        Some(())
+    }
+
+    /// `Z`/`z` packets, Ej.: `0,8000,1` is a breakpoint on `$8000`
+    fn set_point(&mut self, args: &str, insert: bool) -> Option<()> {
+        let mut parts = args.splitn(2, ',');
+        let kind = parts.next()?;
+        let (addr, len) = address_and_length(parts.next()?)?;
+        memory_range(addr, len)?;
+        let addr = addr as u16;
+        let access = match kind {
+            "0" | "1" => {
+                if insert {
+                    self.breakpoints.push(addr);
+                } else if let Some(i) = self.breakpoints.iter().position(|&b| b == addr) {
+                    self.breakpoints.remove(i);
+                }
+                return Some(());
+            }
+            "2" => Access::Write,
+            "3" => Access::Read,
+            "4" => Access::Any,
+            _ => return None,
+        };
+        let watchpoint = Watchpoint {
+            start: addr,
+            len: len.max(1) as u16,
+            access,
+        };
+        if insert {
+            self.machine.watch(watchpoint);
+        } else {
+            self.machine.unwatch(watchpoint);
+        }
+        Some(())
+    }
+
+    /// The answer to a packet (without `$` and the checksum), `None` if it doesn't have one.
+    /// `interrupted` is checked while the program runs
+    pub fn handle(
+        &mut self,
+        packet: &str,
+        interrupted: &mut dyn FnMut() -> bool,
+    ) -> Option<String> {
+        let ok = |done: Option<()>| done.map_or("E01", |_| "OK").to_string();
+        let first = packet
+            .char_indices()
+            .nth(1)
+            .map_or(packet.len(), |(i, _)| i);
+        let (command, args) = packet.split_at(first);
+        let reply = match command {
+            "?" => self.last_stop.clone(),
+            "g" => (0..6)
+                .flat_map(|r| self.read_register(r).unwrap())
+                .map(|b| format!("{:02x}", b))
+                .collect(),
+            "G" => ok(from_hex(args).and_then(|bytes| {
+                let (bytes, pc) = (bytes.get(..5)?, bytes.get(5..7)?);
+                for (r, &byte) in bytes.iter().enumerate() {
+                    self.write_register(r, &[byte])?;
+                }
+                self.write_register(5, pc)
+            })),
+            "p" => usize::from_str_radix(args, 16)
+                .ok()
+                .and_then(|r| self.read_register(r))
+                .map_or("E01".to_string(), |bytes| hex(&bytes)),
+            "P" => ok((|| {
+                let mut parts = args.splitn(2, '=');
+                let r = usize::from_str_radix(parts.next()?, 16).ok()?;
+                self.write_register(r, &from_hex(parts.next()?)?)
+            })()),
+            "m" => match address_and_length(args).and_then(|(addr, len)| memory_range(addr, len)) {
+                Some(range) => hex(&self.machine.memory()[range]),
+                None => "E01".to_string(),
+            },
+            "M" => ok((|| {
+                let mut parts = args.splitn(2, ':');
+                let (addr, len) = address_and_length(parts.next()?)?;
+                let bytes = from_hex(parts.next()?)?;
+                let range = memory_range(addr, len)?;
+                if bytes.len() != len {
+                    return None;
+                }
+                self.machine.memory[range].copy_from_slice(&bytes);
+                Some(())
+            })()),
+            "c" | "s" => {
+                if let Ok(addr) = u16::from_str_radix(args, 16) {
+                    self.machine.registers.pc = addr;
+                }
+                self.last_stop = self.resume(command == "s", interrupted);
+                self.last_stop.clone()
+            }
+            "Z" | "z" => ok(self.set_point(args, command == "Z")),
+            "k" => {
+                self.finished = true;
+                return None;
+            }
+            "D" => {
+                self.finished = true;
+                "OK".to_string()
+            }
+            "H" => "OK".to_string(),
+            _ => self.query(packet, interrupted),
+        };
+        Some(reply)
+    }
+
+    /// Packets with a name (`qSupported`, `vCont`, ...)
+    fn query(&mut self, packet: &str, interrupted: &mut dyn FnMut() -> bool) -> String {
+        if packet.starts_with("qSupported") {
+            let exec_file = if self.exec_file.is_some() {
+                ";qXfer:exec-file:read+"
+            } else {
+                ""
+            };
+            format!(
+                "PacketSize=1000;qXfer:features:read+{};QStartNoAckMode+;swbreak+;hwbreak+",
+                exec_file
+            )
+        } else if packet == "QStartNoAckMode" {
+            self.no_ack = true;
+            "OK".to_string()
+        } else if let Some(args) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            transfer(TARGET_XML, args)
+        } else if let Some(args) = packet.strip_prefix("qXfer:exec-file:read:") {
+            // After the process, that is always the same one
+            match (&self.exec_file, args.split_once(':')) {
+                (Some(file), Some((_, args))) => transfer(file, args),
+                _ => "".to_string(),
+            }
+        } else if let Some(command) = packet.strip_prefix("qRcmd,") {
+            match from_hex(command).and_then(|command| String::from_utf8(command).ok()) {
+                Some(command) => hex(format!("{}\n", self.monitor(&command)).as_bytes()),
+                None => "E01".to_string(),
+            }
+        } else if packet == "vCont?" {
+            "vCont;c;s".to_string()
+        } else if let Some(actions) = packet.strip_prefix("vCont;") {
+            // There is a single thread, so the first action is the one that matters
+            let step = actions.starts_with('s');
+            self.last_stop = self.resume(step, interrupted);
+            self.last_stop.clone()
+        } else {
+            match packet {
+                "qAttached" => "1",
+                "qC" => "QC1",
+                "qfThreadInfo" => "m1",
+                "qsThreadInfo" => "l",
+                "qSymbol::" => "OK",
+                "qOffsets" => "Text=0;Data=0;Bss=0",
+                // Anything else isn't supported
+                _ => "",
+            }
+            .to_string()
+        }
+    }
+}
+
+/// What GDB sent
+enum Received {
+    Packet(String),
+    /// A packet that isn't UTF-8, like the binary ones (`X`) that aren't supported
+    Invalid,
+    /// Ctrl-C
+    Interrupt,
+}
+
+/// Read the next packet, acknowledging it unless `no_ack`. `None` when GDB disconnects
+fn receive(stream: &mut TcpStream, no_ack: bool) -> Result<Option<Received>, Error> {
+    let mut byte = [0u8];
+    loop {
+        if stream.read(&mut byte)? == 0 {
+            return Ok(None);
+        }
+        match byte[0] {
+            0x03 => return Ok(Some(Received::Interrupt)),
+            b'$' => break,
+            // Acknowledgements of our answers, and noise
+            _ => {}
+        }
+    }
+    let mut packet = Vec::new();
+    let mut escaped = false;
+    loop {
+        if stream.read(&mut byte)? == 0 {
+            return Ok(None);
+        }
+        match byte[0] {
+            b'#' if !escaped => break,
+            b'}' if !escaped => escaped = true,
+            b if escaped => {
+                packet.push(b ^ 0x20);
+                escaped = false;
+            }
+            b => packet.push(b),
+        }
+    }
+    let mut checksum = [0u8; 2];
+    stream.read_exact(&mut checksum)?;
+    if !no_ack {
+        stream.write_all(b"+")?;
+    }
+    Ok(Some(match String::from_utf8(packet) {
+        Ok(packet) => Received::Packet(packet),
+        Err(_) => Received::Invalid,
+    }))
+}
+
+/// Wait for GDB on `port` of localhost, and answer it until it detaches
+pub fn serve(mut stub: Stub, port: u16) -> Result<(), Error> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    println!("Waiting for GDB on 127.0.0.1:{}", port);
+    let (mut stream, client) = listener.accept()?;
+    println!("GDB connected from {}", client);
+    stream.set_nodelay(true)?;
+    while !stub.finished {
+        let no_ack = stub.no_ack;
+        let packet = match receive(&mut stream, no_ack)? {
+            Some(Received::Packet(packet)) => packet,
+            Some(Received::Invalid) => {
+                stream.write_all(frame("E01").as_bytes())?;
+                continue;
+            }
+            // It isn't running, so it's already stopped
+            Some(Received::Interrupt) => {
+                stream.write_all(frame("S02").as_bytes())?;
+                continue;
+            }
+            None => break,
+        };
+        let reader = stream.try_clone()?;
+        // A Ctrl-C is the only thing GDB sends while the program runs
+        let mut interrupted = || {
+            let mut byte = [0u8];
+            let _ = reader.set_nonblocking(true);
+            let read = (&reader).read(&mut byte);
+            let _ = reader.set_nonblocking(false);
+            matches!(read, Ok(1) if byte[0] == 0x03)
+        };
+        if let Some(reply) = stub.handle(&packet, &mut interrupted) {
+            stream.write_all(frame(&reply).as_bytes())?;
+        }
+    }
+    println!(
+        "GDB detached on {}",
+        stub.location(stub.machine.registers.pc)
+    );
+    Ok(())
+}
+
+mod tests {
+    #[test]
+    fn test_gdb() {
+        use super::Stub;
+        use crate::assembler::{assemble_source, Metadata};
+        use crate::simulator::Machine;
+        let source = ".org $8000\nmain:\n\tLDA #$05\n\tSTA $0200\n\tINX\n\tBRK";
+        let code = assemble_source(source, &Metadata::default()).unwrap();
+        let mut machine = Machine::new(code.cpu, &code).unwrap();
+        machine.reset(Some(0x8000));
+        let mut stub = Stub::new(machine, &code, "main.asm");
+        let mut send = |packet: &str| stub.handle(packet, &mut || false).unwrap();
+        assert_eq!(send("g"), "000000fd240080");
+        assert_eq!(send("m8000,3"), "a9058d");
+        assert_eq!(send("s"), "S05");
+        assert_eq!(send("p0"), "05");
+        assert_eq!(send("p5"), "0280");
+        // A watchpoint on $0200 stops after the STA
+        assert_eq!(send("Z2,200,1"), "OK");
+        assert_eq!(send("c"), "T05watch:0200;");
+        assert_eq!(send("m200,1"), "05");
+        assert_eq!(send("z2,200,1"), "OK");
+        assert_eq!(send("P1=41"), "OK");
+        assert_eq!(send("Z0,8006,1"), "OK");
+        assert_eq!(send("c"), "S05");
+        assert_eq!(send("p1"), "42");
+        assert_eq!(send("M200,2:abcd"), "OK");
+        assert_eq!(send("m200,2"), "abcd");
+        assert_eq!(send("m200,2:ab"), "E01");
+        // `monitor where`
+        assert_eq!(
+            send("qRcmd,7768657265"),
+            "6d61696e2e61736d3a3620286d61696e2b2436290a"
+        );
+        assert!(send("qXfer:features:read:target.xml:0,1000").starts_with("l<?xml"));
+        assert_eq!(send("qFoo"), "");
+        // Without an ELF file there is nothing for GDB to load
+        let supported = send("qSupported:multiprocess+;xmlRegisters=i386");
+        assert!(!supported.contains("exec-file"));
+        assert_eq!(send("qSymbol::"), "OK");
+        assert_eq!(send("qXfer:exec-file:read::0,1000"), "");
+        assert_eq!(send("qXfer:libraries:read::0,1000"), "");
+        // Malformed packets
+        assert_eq!(send("\u{e9}1"), "");
+        assert_eq!(send("mffffffffffffffff,2"), "E01");
+        assert_eq!(send("Mffffffffffffffff,2:abcd"), "E01");
+        assert_eq!(send("Z2,ffffffffffffffff,2"), "E01");
+        assert_eq!(send("qRcmd,ff"), "E01");
+        assert_eq!(
+            send("qXfer:features:read:target.xml:0,ffffffffffffffff"),
+            format!("l{}", super::TARGET_XML)
+        );
+        // GDB loads the symbols and the lines from the one it's told about
+        stub.exec_file = Some("/src/main.elf".to_string());
+        let mut send = |packet: &str| stub.handle(packet, &mut || false).unwrap();
+        assert!(send("qSupported:multiprocess+").contains(";qXfer:exec-file:read+;"));
+        assert_eq!(send("qXfer:exec-file:read:1:0,1000"), "l/src/main.elf");
+        assert_eq!(send("qXfer:exec-file:read::5,3"), "mmai");
+        assert_eq!(send("qXfer:exec-file:read::0"), "E01");
+        assert_eq!(stub.handle("k", &mut || false), None);
+        assert!(stub.finished);
+    }
+}
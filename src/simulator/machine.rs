@@ -0,0 +1,351 @@
+use super::Device;
+use crate::error::Error;
+use crate::opcodes::{flags, get_opcode, Cpu, OpcodeType};
+
+/// Address of the reset vector
+const RESET_VECTOR: u16 = 0xFFFC;
+/// Address of the NMI vector
+const NMI_VECTOR: u16 = 0xFFFA;
+/// Cycles it takes to enter an interrupt
+pub(super) const INTERRUPT_CYCLES: u64 = 7;
+
+/// Registers of the CPU
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Registers {
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    /// Stack pointer, the stack is on page 1
+    pub s: u8,
+    /// Status, made of the bits on `opcodes::flags`
+    pub p: u8,
+    pub pc: u16,
+}
+impl Registers {
+    /// `NV-BDIZC`, upper case when the flag is set
+    pub fn flags(&self) -> String {
+        "NV-BDIZC"
+            .chars()
+            .enumerate()
+            .map(|(i, c)| {
+                if self.p & (0x80 >> i) != 0 {
+                    c
+                } else {
+                    c.to_ascii_lowercase()
+                }
+            })
+            .collect()
+    }
+}
+impl std::fmt::Display for Registers {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "A:${:02X} X:${:02X} Y:${:02X} S:${:02X} P:${:02X} [{}] PC:${:04X}",
+            self.a,
+            self.x,
+            self.y,
+            self.s,
+            self.p,
+            self.flags(),
+            self.pc
+        )
+    }
+}
+
+/// Why `Machine::run` stopped
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stop {
+    /// A `BRK` on this address, it wasn't run
+    Break(u16),
+    /// The PC got to one of the trap addresses, or to an instruction that jumps to itself
+    Trap(u16),
+    /// It ran for the maximum number of cycles
+    CycleLimit,
+    /// An instruction that stops the CPU (`STP`, `WAI` or an opcode it doesn't have)
+    Halt(u16),
+    /// The instruction that just ran used a watched address
+    Watch(u16, Access),
+}
+
+/// Which uses of memory a watchpoint stops on
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    /// Both reads and writes
+    Any,
+}
+impl std::fmt::Display for Access {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Access::Read => write!(f, "read"),
+            Access::Write => write!(f, "write"),
+            Access::Any => write!(f, "access"),
+        }
+    }
+}
+
+/// Ej.: writes to `$0200-$02FF`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Watchpoint {
+    pub start: u16,
+    pub len: u16,
+    pub access: Access,
+}
+impl Watchpoint {
+    fn matches(&self, addr: u16, access: Access) -> bool {
+        addr.wrapping_sub(self.start) < self.len
+            && (self.access == Access::Any || self.access == access)
+    }
+}
+impl std::fmt::Display for Stop {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Stop::Break(pc) => write!(f, "BRK on ${:04X}", pc),
+            Stop::Trap(pc) => write!(f, "trap on ${:04X}", pc),
+            Stop::CycleLimit => write!(f, "cycle limit"),
+            Stop::Halt(pc) => write!(f, "CPU halted on ${:04X}", pc),
+            Stop::Watch(addr, access) => write!(f, "{} of ${:04X}", access, addr),
+        }
+    }
+}
+
+/// A CPU with 64KiB of RAM
+pub struct Machine {
+    pub registers: Registers,
+    /// Cycles since the reset, including the 7 it takes
+    pub cycles: u64,
+    pub(super) memory: Vec<u8>,
+    pub(super) cpu: Cpu,
+    watchpoints: Vec<Watchpoint>,
+    /// First watched address used by the instruction that is running
+    watched: Option<(u16, Access)>,
+    /// Each device and its first address
+    devices: Vec<(u16, Box<dyn Device>)>,
+    /// Interrupts entered since the start
+    pub interrupts: u64,
+    /// Cycles between NMIs, and the cycle the next one comes on
+    nmi_every: Option<u64>,
+    next_nmi: u64,
+}
+impl Machine {
+    /// `image` is placed from address 0, as `assemble` returns it
+    pub fn new(cpu: Cpu, image: &[u8]) -> Result<Machine, Error> {
+        if cpu == Cpu::Wdc65816 {
+            return Err(Error::Simulator {
+                cause: "The simulator can't run 65816 code".to_string(),
+            });
+        }
+        let mut memory = vec![0u8; 0x10000];
+        let size = image.len().min(memory.len());
+        memory[..size].copy_from_slice(&image[..size]);
+        Ok(Machine {
+            registers: Registers {
+                a: 0,
+                x: 0,
+                y: 0,
+                s: 0,
+                p: 0,
+                pc: 0,
+            },
+            cycles: 0,
+            memory,
+            cpu,
+            watchpoints: Vec::new(),
+            watched: None,
+            devices: Vec::new(),
+            interrupts: 0,
+            nmi_every: None,
+            next_nmi: 0,
+        })
+    }
+    /// Ej.: `Machine::builder(Cpu::Mos6502).image(&code).device(0xF001, CharOut::new(stdout()))`
+    pub fn builder(cpu: Cpu) -> Builder {
+        Builder {
+            cpu,
+            image: Vec::new(),
+            devices: Vec::new(),
+            start: None,
+        }
+    }
+    /// Send the reads and writes from `start` on (as many addresses as it takes) to `device`
+    pub fn attach(&mut self, start: u16, device: Box<dyn Device>) {
+        self.devices.push((start, device));
+    }
+    fn device(&mut self, addr: u16) -> Option<(u16, &mut Box<dyn Device>)> {
+        self.devices
+            .iter_mut()
+            .map(|(start, device)| (addr.wrapping_sub(*start), device))
+            .find(|(offset, device)| *offset < device.size())
+    }
+    /// Start on `start`, or where the reset vector points to
+    pub fn reset(&mut self, start: Option<u16>) {
+        let vector = self.read_word(RESET_VECTOR);
+        self.registers = Registers {
+            s: 0xFD,
+            p: flags::I | flags::M,
+            pc: start.unwrap_or(vector),
+            ..self.registers
+        };
+        self.cycles = 7;
+        self.next_nmi = self.cycles + self.nmi_every.unwrap_or(0);
+        for (_, device) in &mut self.devices {
+            device.reset();
+        }
+    }
+    /// Enter an NMI after each `cycles` (Ej.: 29781, a frame of the NES), or never with `None`
+    pub fn nmi_every(&mut self, cycles: Option<u64>) {
+        self.nmi_every = cycles.filter(|&cycles| cycles > 0);
+        self.next_nmi = self.cycles + self.nmi_every.unwrap_or(0);
+    }
+    /// Enter an NMI: push the PC and the status and jump to where `$FFFA` points to
+    pub fn nmi(&mut self) {
+        let pc = self.registers.pc;
+        self.push((pc >> 8) as u8);
+        self.push(pc as u8);
+        // The copy on the stack has B clear
+        self.push(self.registers.p & !flags::X | flags::M);
+        self.registers.p |= flags::I;
+        if self.cpu == Cpu::Wdc65C02 {
+            self.registers.p &= !flags::D;
+        }
+        self.registers.pc = self.read_word(NMI_VECTOR);
+        self.cycles += INTERRUPT_CYCLES;
+        self.interrupts += 1;
+    }
+    pub fn read(&mut self, addr: u16) -> u8 {
+        self.check_watchpoints(addr, Access::Read);
+        let cycles = self.cycles;
+        match self.device(addr) {
+            Some((offset, device)) => device.read(offset, cycles),
+            None => self.memory[addr as usize],
+        }
+    }
+    pub fn write(&mut self, addr: u16, value: u8) {
+        self.check_watchpoints(addr, Access::Write);
+        let cycles = self.cycles;
+        match self.device(addr) {
+            Some((offset, device)) => device.write(offset, value, cycles),
+            None => self.memory[addr as usize] = value,
+        }
+    }
+    /// Read without the side effects `read` may have (Ej.: to fetch instructions).
+    /// The addresses of the devices return the RAM under them
+    pub(super) fn peek(&self, addr: u16) -> u8 {
+        self.memory[addr as usize]
+    }
+    /// Stop after the instruction that reads or writes one of these addresses
+    pub fn watch(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
+    }
+    pub fn unwatch(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.retain(|&w| w != watchpoint);
+    }
+    fn check_watchpoints(&mut self, addr: u16, access: Access) {
+        if self.watched.is_none() && self.watchpoints.iter().any(|w| w.matches(addr, access)) {
+            self.watched = Some((addr, access));
+        }
+    }
+    pub(super) fn read_word(&mut self, addr: u16) -> u16 {
+        self.read(addr) as u16 | (self.read(addr.wrapping_add(1)) as u16) << 8
+    }
+    /// All the RAM
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+    /// Run one instruction, unless it stops the CPU, and enter the NMI if it's its time.
+    /// `Stop::Watch` is returned after running it
+    pub fn step(&mut self) -> Option<Stop> {
+        let pc = self.registers.pc;
+        let opcode = match get_opcode(self.peek(pc), self.cpu) {
+            Some(opcode) => opcode,
+            None => return Some(Stop::Halt(pc)),
+        };
+        match opcode.name() {
+            OpcodeType::BRK => return Some(Stop::Break(pc)),
+            OpcodeType::STP | OpcodeType::WAI => return Some(Stop::Halt(pc)),
+            _ => {}
+        }
+        let size = opcode.size() as u16;
+        let operand = match size {
+            1 => 0,
+            2 => self.peek(pc.wrapping_add(1)) as u16,
+            _ => self.peek(pc.wrapping_add(1)) as u16 | (self.peek(pc.wrapping_add(2)) as u16) << 8,
+        };
+        self.registers.pc = pc.wrapping_add(size);
+        self.cycles += opcode.cycles() as u64;
+        self.execute(opcode, operand);
+        if let Some(every) = self.nmi_every {
+            if self.cycles >= self.next_nmi {
+                self.next_nmi += every;
+                self.nmi();
+            }
+        }
+        self.watched
+            .take()
+            .map(|(addr, access)| Stop::Watch(addr, access))
+    }
+    /// Run until a `BRK`, a trap address or `max_cycles`
+    pub fn run(&mut self, max_cycles: u64, traps: &[u16]) -> Stop {
+        self.run_with(max_cycles, traps, &mut |_| {})
+    }
+    /// `run`, calling `before_step` before each instruction
+    pub fn run_with(
+        &mut self,
+        max_cycles: u64,
+        traps: &[u16],
+        before_step: &mut dyn FnMut(&Machine),
+    ) -> Stop {
+        loop {
+            let pc = self.registers.pc;
+            if traps.contains(&pc) {
+                return Stop::Trap(pc);
+            }
+            if self.cycles >= max_cycles {
+                return Stop::CycleLimit;
+            }
+            before_step(self);
+            if let Some(stop) = self.step() {
+                return stop;
+            }
+            // `JMP *` or a branch to itself never ends, unless an interrupt comes
+            if self.registers.pc == pc && self.nmi_every.is_none() {
+                return Stop::Trap(pc);
+            }
+        }
+    }
+}
+
+/// Sets up a `Machine`, made with `Machine::builder`
+pub struct Builder {
+    cpu: Cpu,
+    image: Vec<u8>,
+    devices: Vec<(u16, Box<dyn Device>)>,
+    start: Option<u16>,
+}
+impl Builder {
+    /// Placed from address 0, as `assemble` returns it
+    pub fn image(mut self, image: &[u8]) -> Self {
+        self.image = image.to_vec();
+        self
+    }
+    pub fn device(mut self, start: u16, device: impl Device + 'static) -> Self {
+        self.devices.push((start, Box::new(device)));
+        self
+    }
+    /// Start on `start` instead of where the reset vector points to
+    pub fn start(mut self, start: u16) -> Self {
+        self.start = Some(start);
+        self
+    }
+    /// The machine, after a reset
+    pub fn build(self) -> Result<Machine, Error> {
+        let mut machine = Machine::new(self.cpu, &self.image)?;
+        for (start, device) in self.devices {
+            machine.attach(start, device);
+        }
+        machine.reset(self.start);
+        Ok(machine)
+    }
+}
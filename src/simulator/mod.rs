@@ -0,0 +1,101 @@
+mod coverage;
+mod devices;
+mod execute;
+mod gdb;
+mod machine;
+mod profile;
+mod testing;
+mod trace;
+
+pub use coverage::{Coverage, Executed};
+pub use devices::{CharOut, Controller, Device, InputFifo, Timer};
+pub use gdb::{serve, Stub};
+pub use machine::{Access, Builder, Machine, Registers, Stop, Watchpoint};
+pub use profile::{Profiler, Routine};
+pub use testing::{run_test_with, Failure};
+
+mod tests {
+    #[test]
+    fn test_run() {
+        use super::{Machine, Stop};
+        use crate::opcodes::{flags, Cpu};
+        // Code on $8000, with the reset vector pointing to it
+        let machine = |cpu: Cpu, code: &[u8]| {
+            let mut image = vec![0u8; 0x10000];
+            image[0x8000..0x8000 + code.len()].copy_from_slice(code);
+            image[0xFFFC..].copy_from_slice(&[0x00, 0x80, 0x00, 0x00]);
+            let mut machine = Machine::new(cpu, &image).unwrap();
+            machine.reset(None);
+            machine
+        };
+        // LDA #$02, CLC, ADC #$03, STA $0200, BRK
+        let code = [0xA9, 0x02, 0x18, 0x69, 0x03, 0x8D, 0x00, 0x02, 0x00];
+        let mut m = machine(Cpu::Mos6502, &code);
+        assert_eq!(m.run(1000, &[]), Stop::Break(0x8008));
+        assert_eq!(m.registers.a, 0x05);
+        assert_eq!(m.memory()[0x0200], 0x05);
+        assert_eq!(m.cycles, 7 + 2 + 2 + 2 + 4);
+        // SED, CLC, LDA #$99, ADC #$01, BRK
+        let code = [0xF8, 0x18, 0xA9, 0x99, 0x69, 0x01, 0x00];
+        let mut m = machine(Cpu::Mos6502, &code);
+        m.run(1000, &[]);
+        assert_eq!(m.registers.a, 0x00);
+        // The NMOS CPUs take N and Z from the wrong places
+        assert_eq!(
+            m.registers.p & (flags::N | flags::Z | flags::C),
+            flags::N | flags::C
+        );
+        let mut m = machine(Cpu::Wdc65C02, &code);
+        m.run(1000, &[]);
+        assert_eq!(
+            m.registers.p & (flags::N | flags::Z | flags::C),
+            flags::Z | flags::C
+        );
+        assert_eq!(m.cycles, 7 + 2 + 2 + 2 + 3);
+        let mut m = machine(Cpu::Ricoh2A03, &code);
+        m.run(1000, &[]);
+        assert_eq!(m.registers.a, 0x9A);
+        // SED, SEC, LDA #$46, SBC #$12, BRK
+        let code = [0xF8, 0x38, 0xA9, 0x46, 0xE9, 0x12, 0x00];
+        for &cpu in &[Cpu::Mos6502, Cpu::Wdc65C02] {
+            let mut m = machine(cpu, &code);
+            m.run(1000, &[]);
+            assert_eq!(m.registers.a, 0x34);
+        }
+        // JMP ($10FF)
+        let mut m = machine(Cpu::Mos6502, &[0x6C, 0xFF, 0x10]);
+        m.write(0x10FF, 0x34);
+        m.write(0x1000, 0x12);
+        m.write(0x1100, 0x56);
+        m.step();
+        assert_eq!(m.registers.pc, 0x1234);
+        let mut m = machine(Cpu::Wdc65C02, &[0x6C, 0xFF, 0x10]);
+        m.write(0x10FF, 0x34);
+        m.write(0x1100, 0x56);
+        m.step();
+        assert_eq!(m.registers.pc, 0x5634);
+        // LDX #$01, LDA $80FF,X crosses a page. BNE to the next page
+        let mut m = machine(Cpu::Mos6502, &[0xA2, 0x01, 0xBD, 0xFF, 0x80]);
+        m.run(7 + 2 + 5, &[]);
+        assert_eq!(m.registers.pc, 0x8005);
+        assert_eq!(m.run(1000, &[0x8005]), Stop::Trap(0x8005));
+        // JSR $8006, JMP *, RTS
+        let code = [0x20, 0x06, 0x80, 0x4C, 0x03, 0x80, 0x60];
+        let mut m = machine(Cpu::Mos6502, &code);
+        assert_eq!(m.run(1000, &[]), Stop::Trap(0x8003));
+        assert_eq!(m.registers.s, 0xFD);
+        assert_eq!(m.cycles, 7 + 6 + 6 + 3);
+        // BRA to the next instruction, BRK. Always taken, so it takes as long as a taken branch
+        let mut m = machine(Cpu::Wdc65C02, &[0x80, 0x00, 0x00]);
+        assert_eq!(m.run(1000, &[]), Stop::Break(0x8002));
+        assert_eq!(m.cycles, 7 + 3);
+        // LAX only exists on the 6502X
+        let mut m = machine(Cpu::Mos6502, &[0xA7, 0x10]);
+        assert_eq!(m.run(1000, &[]), Stop::Halt(0x8000));
+        let mut m = machine(Cpu::Mos6502X, &[0xA7, 0x10, 0x00]);
+        m.write(0x10, 0x42);
+        m.run(1000, &[]);
+        assert_eq!((m.registers.a, m.registers.x), (0x42, 0x42));
+        assert!(Machine::new(Cpu::Wdc65816, &[]).is_err());
+    }
+}
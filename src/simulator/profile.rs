@@ -0,0 +1,271 @@
+use super::machine::INTERRUPT_CYCLES;
+use super::Machine;
+use crate::assembler::Assembled;
+use crate::opcodes::get_opcode;
+use std::collections::HashMap;
+
+/// Cycles spent on a `.proc`, or on the code from a global label to the next one
+#[derive(Debug, Clone, PartialEq)]
+pub struct Routine {
+    pub name: String,
+    /// Times it was called with `JSR`/`JSL` or entered by an interrupt
+    /// (the routine the run started on counts once)
+    pub calls: u64,
+    /// Cycles from each call to its return, including the routines it called
+    pub inclusive: u64,
+    /// Cycles of its own instructions
+    pub exclusive: u64,
+    /// Most cycles a single call took
+    pub worst: u64,
+    /// From `.budget`
+    pub budget: Option<u64>,
+}
+impl Routine {
+    fn new(name: String, budget: Option<u64>) -> Self {
+        Routine {
+            name,
+            calls: 0,
+            inclusive: 0,
+            exclusive: 0,
+            worst: 0,
+            budget,
+        }
+    }
+}
+
+/// A call that hasn't returned yet
+struct Frame {
+    routine: usize,
+    /// Cycles when it was called
+    start: u64,
+    /// It has returned once the stack pointer gets to this
+    returned: u16,
+}
+
+/// The instruction that was about to run on the last `record`
+struct Previous {
+    pc: u16,
+    cycles: u64,
+    s: u8,
+    interrupts: u64,
+}
+
+/// Counts the cycles of each routine and line, feeding it with `record` as the `before_step`
+/// of `Machine::run_with` and calling `finish` when it stops
+pub struct Profiler {
+    routines: Vec<Routine>,
+    /// Routine that owns each address
+    owners: Vec<usize>,
+    /// Source line of each address that starts an instruction
+    lines: HashMap<usize, usize>,
+    line_cycles: HashMap<usize, u64>,
+    stack: Vec<Frame>,
+    previous: Option<Previous>,
+}
+impl Profiler {
+    pub fn new(code: &Assembled) -> Self {
+        // Code before the first label
+        let mut routines = vec![Routine::new("(no label)".to_string(), None)];
+        let mut owners = vec![0; 0x10000];
+        let mut labels: Vec<(usize, &str)> = code
+            .labels
+            .iter()
+            .filter(|label| !label.name.contains('.') && label.address < owners.len())
+            .filter(|label| !code.procs.iter().any(|proc| proc.name == label.name))
+            .map(|label| (label.address, &label.name[..]))
+            .collect();
+        labels.sort();
+        for (i, &(address, name)) in labels.iter().enumerate() {
+            let end = labels.get(i + 1).map_or(owners.len(), |next| next.0);
+            routines.push(Routine::new(name.to_string(), None));
+            for owner in &mut owners[address..end] {
+                *owner = routines.len() - 1;
+            }
+        }
+        // Inner `.proc`s are placed after the ones that contain them
+        let mut procs: Vec<_> = code.procs.iter().collect();
+        procs.sort_by_key(|proc| std::cmp::Reverse(proc.size()));
+        for proc in procs {
+            let budget = proc.budget.map(|budget| budget as u64);
+            routines.push(Routine::new(proc.name.clone(), budget));
+            let end = proc.end.min(owners.len());
+            for owner in &mut owners[proc.start.min(end)..end] {
+                *owner = routines.len() - 1;
+            }
+        }
+        Profiler {
+            routines,
+            owners,
+            lines: code
+                .instructions
+                .iter()
+                .map(|instruction| (instruction.address, instruction.line))
+                .collect(),
+            line_cycles: HashMap::new(),
+            stack: Vec::new(),
+            previous: None,
+        }
+    }
+    /// Count the instruction that ran since the last call
+    pub fn record(&mut self, machine: &Machine) {
+        let registers = &machine.registers;
+        match self.previous.take() {
+            // Returning from where the run started pulls at least one byte more
+            None => self.call(registers.pc, machine.cycles, registers.s as u16 + 1),
+            Some(previous) => {
+                // An interrupt entered after the instruction, the PC and the stack pointer it
+                // left are the ones the `RTI` goes back to
+                let interrupted = machine.interrupts != previous.interrupts;
+                let (pc, s, entry) = if interrupted {
+                    let stack =
+                        |i: u8| machine.memory()[0x100 | registers.s.wrapping_add(i) as usize];
+                    let pc = stack(2) as u16 | (stack(3) as u16) << 8;
+                    (pc, registers.s.wrapping_add(3), INTERRUPT_CYCLES)
+                } else {
+                    (registers.pc, registers.s, 0)
+                };
+                let cycles = machine.cycles - entry - previous.cycles;
+                let owner = self.owners[previous.pc as usize];
+                self.routines[owner].exclusive += cycles;
+                if let Some(line) = self.lines.get(&(previous.pc as usize)) {
+                    *self.line_cycles.entry(*line).or_default() += cycles;
+                }
+                let opcode = get_opcode(machine.memory()[previous.pc as usize], machine.cpu);
+                if let Some(name) = opcode.map(|opcode| opcode.name()) {
+                    if name.is_call_op() {
+                        // The `JSR` belongs to the caller.
+                        // The stack pointer is back where it was before it after returning
+                        self.call(pc, machine.cycles - entry, previous.s as u16);
+                    } else if name.is_return_op() {
+                        while self.stack.last().is_some_and(|f| f.returned <= s as u16) {
+                            self.ret(machine.cycles - entry);
+                        }
+                    }
+                }
+                if interrupted {
+                    // Entering it counts as part of the handler
+                    self.call(registers.pc, machine.cycles - entry, s as u16);
+                    let handler = self.owners[registers.pc as usize];
+                    self.routines[handler].exclusive += entry;
+                }
+            }
+        }
+        self.previous = Some(Previous {
+            pc: registers.pc,
+            cycles: machine.cycles,
+            s: registers.s,
+            interrupts: machine.interrupts,
+        });
+    }
+    /// Count the last instruction, and the calls that didn't return
+    pub fn finish(&mut self, machine: &Machine) {
+        if self.previous.is_some() {
+            self.record(machine);
+        }
+        while !self.stack.is_empty() {
+            self.ret(machine.cycles);
+        }
+        self.previous = None;
+    }
+    fn call(&mut self, target: u16, cycles: u64, returned: u16) {
+        let routine = self.owners[target as usize];
+        self.routines[routine].calls += 1;
+        self.stack.push(Frame {
+            routine,
+            start: cycles,
+            returned,
+        });
+    }
+    fn ret(&mut self, cycles: u64) {
+        let frame = self.stack.pop().unwrap();
+        let took = cycles - frame.start;
+        let routine = &mut self.routines[frame.routine];
+        routine.worst = routine.worst.max(took);
+        // Recursive calls are already counted by the outer one
+        if !self.stack.iter().any(|f| f.routine == frame.routine) {
+            routine.inclusive += took;
+        }
+    }
+    /// The routines that ran, with the most inclusive cycles first
+    pub fn routines(&self) -> Vec<&Routine> {
+        let mut routines: Vec<&Routine> = self
+            .routines
+            .iter()
+            .filter(|r| r.calls > 0 || r.exclusive > 0)
+            .collect();
+        routines.sort_by_key(|r| {
+            (
+                std::cmp::Reverse(r.inclusive),
+                std::cmp::Reverse(r.exclusive),
+            )
+        });
+        routines
+    }
+    /// The `count` lines that took the most cycles, Ej.: `(12, 3400)`
+    pub fn hottest_lines(&self, count: usize) -> Vec<(usize, u64)> {
+        let mut lines: Vec<(usize, u64)> = self.line_cycles.iter().map(|(&l, &c)| (l, c)).collect();
+        lines.sort_by_key(|&(line, cycles)| (std::cmp::Reverse(cycles), line));
+        lines.truncate(count);
+        lines
+    }
+    /// Ej.: `nmi took 2400 cycles, its budget is 2273`
+    pub fn over_budget(&self) -> Vec<String> {
+        self.routines
+            .iter()
+            .filter_map(|r| match r.budget {
+                Some(budget) if r.worst > budget => Some(format!(
+                    "{} took {} cycles, its budget is {}",
+                    r.name, r.worst, budget
+                )),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_profile() {
+        use super::Profiler;
+        use crate::assembler::{assemble_source, Metadata};
+        use crate::simulator::{Machine, Stop};
+        let source = ".org $8000\nmain:\n\tJMP main\n\
+            .proc nmi\n\t.budget 20\n\tLDX #2\n@loop:\n\tJSR wait\n\tDEX\n\tBNE @loop\n\tRTI\n.endproc\n\
+            wait:\n\tNOP\n\tRTS\n.org $FFFA\n.dw nmi";
+        let code = assemble_source(source, &Metadata::default()).unwrap();
+        assert_eq!(code.procs[0].budget, Some(20));
+        let mut machine = Machine::new(code.cpu, &code).unwrap();
+        machine.reset(Some(0x8000));
+        // The NMIs come after the JMPs that end on cycles 109 and 209
+        machine.nmi_every(Some(100));
+        let mut profiler = Profiler::new(&code);
+        let stop = machine.run_with(300, &[], &mut |m| profiler.record(m));
+        profiler.finish(&machine);
+        assert_eq!(stop, Stop::CycleLimit);
+        assert_eq!((machine.interrupts, machine.registers.s), (2, 0xFD));
+        let routines = profiler.routines();
+        let summary: Vec<(&str, u64, u64, u64)> = routines
+            .iter()
+            .map(|r| (&r.name[..], r.calls, r.inclusive, r.exclusive))
+            .collect();
+        // wait: NOP + RTS = 8,
+        // nmi: entry + LDX + 2 * (JSR + DEX + BNE) - 1 + RTI = 7 + 2 + 2 * 11 - 1 + 6 = 36
+        assert_eq!(
+            summary,
+            vec![
+                ("main", 1, 300 - 7, 300 - 7 - 2 * (36 + 16)),
+                ("nmi", 2, 2 * (36 + 16), 2 * 36),
+                ("wait", 4, 4 * 8, 4 * 8),
+            ]
+        );
+        assert_eq!(routines[1].worst, 36 + 16);
+        assert_eq!(
+            profiler.hottest_lines(1),
+            vec![(3, 300 - 7 - 2 * (36 + 16))]
+        );
+        assert_eq!(
+            profiler.over_budget(),
+            vec!["nmi took 52 cycles, its budget is 20"]
+        );
+    }
+}
@@ -0,0 +1,159 @@
+use super::{Machine, Stop};
+use crate::assembler::Test;
+use crate::parser::{Expectation, Expr, Target};
+
+/// An `.expect` that didn't hold, or a test that didn't get to its end
+#[derive(Debug, Clone, PartialEq)]
+pub struct Failure {
+    /// Line of the `.expect`, or of the `.test`
+    pub line: usize,
+    pub message: String,
+}
+
+/// Value an expectation looks at
+fn read_target(machine: &Machine, target: &Target) -> isize {
+    let registers = &machine.registers;
+    match target {
+        Target::A => registers.a as isize,
+        Target::X => registers.x as isize,
+        Target::Y => registers.y as isize,
+        Target::S => registers.s as isize,
+        Target::P => registers.p as isize,
+        Target::PC => registers.pc as isize,
+        Target::Flag(flag) => (registers.p & flag != 0) as isize,
+        Target::Memory(addr) => machine.memory()[number(addr) as usize & 0xFFFF] as isize,
+    }
+}
+
+/// The assembler leaves the expressions of `.expect` evaluated
+fn number(expr: &Expr) -> isize {
+    match expr {
+        Expr::Number(n) => *n,
+        expr => panic!("{:?} should have been evaluated by the assembler", expr),
+    }
+}
+
+/// Ej.: `A == $05 (A is $04)`
+fn check(machine: &Machine, expectation: &Expectation) -> Option<String> {
+    let found = read_target(machine, &expectation.target);
+    if expectation
+        .comparison
+        .holds(found, number(&expectation.value))
+    {
+        None
+    } else {
+        Some(format!(
+            "{} (found ${:02X})",
+            expectation.text.trim(),
+            found
+        ))
+    }
+}
+
+/// Run `test` on `machine` (with the code assembled with that test on it, and maybe some
+/// devices) from its first instruction until its end or a `BRK`, checking each `.expect`
+/// when the PC gets to it and calling `before_step` before each instruction.
+/// It also returns the machine as the test left it
+pub fn run_test_with(
+    mut machine: Machine,
+    test: &Test,
+    max_cycles: u64,
+    before_step: &mut dyn FnMut(&Machine),
+) -> (Vec<Failure>, Machine) {
+    machine.reset(Some(test.start as u16));
+    let end = test.end as u16;
+    let mut traps: Vec<u16> = test.expects.iter().map(|e| e.address as u16).collect();
+    traps.push(end);
+    let mut reached = vec![false; test.expects.len()];
+    let mut failures = Vec::new();
+    let failure = |message: String| Failure {
+        line: test.line,
+        message,
+    };
+    loop {
+        let stop = machine.run_with(max_cycles, &traps, before_step);
+        let pc = match stop {
+            Stop::Trap(pc) if traps.contains(&pc) => pc,
+            Stop::Break(_) => break,
+            stop => {
+                failures.push(failure(format!("{}\n{}", stop, machine.registers)));
+                break;
+            }
+        };
+        for (i, expect) in test.expects.iter().enumerate() {
+            if expect.address as u16 != pc {
+                continue;
+            }
+            reached[i] = true;
+            for message in expect
+                .expectations
+                .iter()
+                .filter_map(|e| check(&machine, e))
+            {
+                failures.push(Failure {
+                    line: expect.line,
+                    message,
+                });
+            }
+        }
+        if pc == end {
+            break;
+        }
+        // Get past the trap
+        before_step(&machine);
+        if let Some(stop) = machine.step() {
+            if let Stop::Break(_) = stop {
+                break;
+            }
+            failures.push(failure(format!("{}\n{}", stop, machine.registers)));
+            break;
+        }
+    }
+    for (expect, _) in test.expects.iter().zip(reached).filter(|(_, r)| !r) {
+        failures.push(Failure {
+            line: expect.line,
+            message: format!("never got to ${:04X}", expect.address),
+        });
+    }
+    (failures, machine)
+}
+
+mod tests {
+    #[test]
+    fn test_tests() {
+        use super::run_test_with;
+        use crate::assembler::{assemble_source, Metadata};
+        use crate::opcodes::Cpu;
+        use crate::simulator::Machine;
+        let source = ".org $8000\nadd:\n\tCLC\n\tADC #1\n\tRTS\n\
+            .test \"adds one\"\n\tLDA #4\n\tJSR add\n\t.expect A == 5, c == 0\n\tSTA result\n\t.expect mem[result] == 5\n.endtest\n\
+            .test \"fails\"\n\tLDX #1\n\t.expect X != 1, Z == 1\n\tBRK\n\t.expect PC == 0\n.endtest\n\
+            result = $0200";
+        let metadata = |test| Metadata {
+            test,
+            ..Metadata::default()
+        };
+        let code = assemble_source(source, &metadata(None)).unwrap();
+        assert_eq!(code.tests.len(), 2);
+        assert_eq!(code.tests[0].name, "adds one");
+        assert_eq!(code.tests[0].line, 6);
+        // The tests are only placed when they are chosen
+        assert_eq!(code.tests[1].start, 0x8004);
+        assert_eq!(code[0x8004], 0x00);
+        let code = assemble_source(source, &metadata(Some(0))).unwrap();
+        let test = &code.tests[0];
+        assert_eq!((test.start, test.end), (0x8004, 0x800C));
+        assert_eq!(test.expects[1].line, 11);
+        let run_test = |code, test| {
+            let machine = Machine::new(Cpu::Mos6502, code).unwrap();
+            run_test_with(machine, test, 1000, &mut |_| {}).0
+        };
+        assert_eq!(run_test(&code, test), vec![]);
+        let code = assemble_source(source, &metadata(Some(1))).unwrap();
+        let failures = run_test(&code, &code.tests[1]);
+        let lines: Vec<usize> = failures.iter().map(|f| f.line).collect();
+        assert_eq!(lines, vec![15, 15, 17]);
+        assert_eq!(failures[0].message, "X != 1 (found $01)");
+        assert!(assemble_source(".test \"open\"", &metadata(None)).is_err());
+    }
+}
@@ -0,0 +1,208 @@
+use super::Machine;
+use crate::addressing_modes::AddressingMode;
+use crate::opcodes::{get_opcode, Cpu, OpcodeData, OpcodeType};
+
+/// Dots the NTSC PPU draws on each CPU cycle, and on each scanline
+const DOTS_PER_CYCLE: u64 = 3;
+const DOTS_PER_SCANLINE: u64 = 341;
+const SCANLINES: u64 = 262;
+
+impl Machine {
+    fn peek_word(&self, addr: u16, wrap_page: bool) -> u16 {
+        let high = if wrap_page {
+            addr & 0xFF00 | addr.wrapping_add(1) & 0x00FF
+        } else {
+            addr.wrapping_add(1)
+        };
+        self.peek(addr) as u16 | (self.peek(high) as u16) << 8
+    }
+
+    /// Ej.: `LDA ($89),Y = 0300 @ 0300 = 89`, with the values the operands have before running it
+    fn trace_disassembly(&self, opcode: &OpcodeData, value: u16) -> String {
+        let x = self.registers.x;
+        let y = self.registers.y;
+        let zero_page = value as u8;
+        let is_jump = opcode.name().is_jump_op() || opcode.name().is_call_op();
+        let operand = match opcode.addr_mode() {
+            AddressingMode::IMPL => String::new(),
+            AddressingMode::A => "A".to_string(),
+            AddressingMode::IMM => format!("#${:02X}", zero_page),
+            AddressingMode::ZPG => format!("${:02X} = {:02X}", zero_page, self.peek(value)),
+            AddressingMode::ZPGX | AddressingMode::ZPGY => {
+                let (register, index) = if opcode.addr_mode() == AddressingMode::ZPGX {
+                    ('X', x)
+                } else {
+                    ('Y', y)
+                };
+                let addr = zero_page.wrapping_add(index);
+                format!(
+                    "${:02X},{} @ {:02X} = {:02X}",
+                    zero_page,
+                    register,
+                    addr,
+                    self.peek(addr as u16)
+                )
+            }
+            AddressingMode::ABS if is_jump => format!("${:04X}", value),
+            AddressingMode::ABS => format!("${:04X} = {:02X}", value, self.peek(value)),
+            AddressingMode::ABSX | AddressingMode::ABSY => {
+                let (register, index) = if opcode.addr_mode() == AddressingMode::ABSX {
+                    ('X', x)
+                } else {
+                    ('Y', y)
+                };
+                let addr = value.wrapping_add(index as u16);
+                format!(
+                    "${:04X},{} @ {:04X} = {:02X}",
+                    value,
+                    register,
+                    addr,
+                    self.peek(addr)
+                )
+            }
+            AddressingMode::IND => {
+                let nmos = self.cpu != Cpu::Wdc65C02;
+                format!("(${:04X}) = {:04X}", value, self.peek_word(value, nmos))
+            }
+            AddressingMode::INDX => {
+                let pointer = zero_page.wrapping_add(x);
+                let addr = self.peek_word(pointer as u16, true);
+                format!(
+                    "(${:02X},X) @ {:02X} = {:04X} = {:02X}",
+                    zero_page,
+                    pointer,
+                    addr,
+                    self.peek(addr)
+                )
+            }
+            AddressingMode::INDY => {
+                let base = self.peek_word(zero_page as u16, true);
+                let addr = base.wrapping_add(y as u16);
+                format!(
+                    "(${:02X}),Y = {:04X} @ {:04X} = {:02X}",
+                    zero_page,
+                    base,
+                    addr,
+                    self.peek(addr)
+                )
+            }
+            AddressingMode::ZPGIND => {
+                let addr = self.peek_word(zero_page as u16, true);
+                format!(
+                    "(${:02X}) = {:04X} = {:02X}",
+                    zero_page,
+                    addr,
+                    self.peek(addr)
+                )
+            }
+            AddressingMode::ABSINDX => {
+                let addr = self.peek_word(value.wrapping_add(x as u16), false);
+                format!("(${:04X},X) = {:04X}", value, addr)
+            }
+            AddressingMode::REL => {
+                let next = self.registers.pc.wrapping_add(2);
+                format!("${:04X}", next.wrapping_add(zero_page as i8 as u16))
+            }
+            AddressingMode::ZPGREL => {
+                let next = self.registers.pc.wrapping_add(3);
+                let target = next.wrapping_add((value >> 8) as u8 as i8 as u16);
+                format!("${:02X},${:04X}", zero_page, target)
+            }
+            _ => unreachable!("The simulator doesn't have the 65816 addressing modes"),
+        };
+        // nestest.log calls ISC `ISB`
+        let name = match opcode.name() {
+            OpcodeType::ISC => "ISB".to_string(),
+            name => format!("{:?}", name),
+        };
+        if operand.is_empty() {
+            name
+        } else {
+            format!("{} {}", name, operand)
+        }
+    }
+
+    /// The instruction on the PC and the registers before running it, like on `nestest.log`.
+    /// Ej.: `C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7`.
+    /// `PPU` is the scanline and dot an NTSC PPU would be on after `cycles`
+    pub fn trace_line(&self) -> String {
+        let pc = self.registers.pc;
+        let opcode = get_opcode(self.peek(pc), self.cpu);
+        let size = opcode.map_or(1, |opcode| opcode.size() as u16);
+        let bytes: Vec<String> = (0..size)
+            .map(|i| format!("{:02X}", self.peek(pc.wrapping_add(i))))
+            .collect();
+        let value = match size {
+            1 => 0,
+            2 => self.peek(pc.wrapping_add(1)) as u16,
+            _ => self.peek_word(pc.wrapping_add(1), false),
+        };
+        // Undocumented opcodes are marked with `*`
+        let (mark, disassembly) = match opcode {
+            Some(opcode) => {
+                let official = !matches!(self.cpu, Cpu::Mos6502X | Cpu::Ricoh2A03)
+                    || get_opcode(self.peek(pc), Cpu::Mos6502).is_some();
+                let mark = if official { ' ' } else { '*' };
+                (mark, self.trace_disassembly(opcode, value))
+            }
+            None => ('*', "???".to_string()),
+        };
+        let dots = self.cycles * DOTS_PER_CYCLE;
+        format!(
+            "{:04X}  {:<8} {}{:<32}A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{:>3},{:>3} CYC:{}",
+            pc,
+            bytes.join(" "),
+            mark,
+            disassembly,
+            self.registers.a,
+            self.registers.x,
+            self.registers.y,
+            self.registers.p,
+            self.registers.s,
+            dots / DOTS_PER_SCANLINE % SCANLINES,
+            dots % DOTS_PER_SCANLINE,
+            self.cycles
+        )
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_trace() {
+        use crate::opcodes::Cpu;
+        use crate::simulator::Machine;
+        let mut image = vec![0u8; 0x10000];
+        // JMP $C5F5, then LDA ($80),Y, *LAX $10, BCS *-2, BRK
+        image[0xC000..0xC003].copy_from_slice(&[0x4C, 0xF5, 0xC5]);
+        image[0xC5F5..0xC5FD].copy_from_slice(&[0xB1, 0x80, 0xA7, 0x10, 0xB0, 0xFC, 0x00, 0x00]);
+        image[0x80..0x82].copy_from_slice(&[0x00, 0x03]);
+        image[0x0301] = 0x89;
+        let mut m = Machine::new(Cpu::Mos6502X, &image).unwrap();
+        m.reset(Some(0xC000));
+        m.registers.y = 1;
+        let mut lines = Vec::new();
+        m.run_with(100, &[0xC5F9], &mut |m| lines.push(m.trace_line()));
+        assert_eq!(lines, vec![
+            "C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:01 P:24 SP:FD PPU:  0, 21 CYC:7",
+            "C5F5  B1 80     LDA ($80),Y = 0300 @ 0301 = 89  A:00 X:00 Y:01 P:24 SP:FD PPU:  0, 30 CYC:10",
+            "C5F7  A7 10    *LAX $10 = 00                    A:89 X:00 Y:01 P:A4 SP:FD PPU:  0, 45 CYC:15",
+        ]);
+        assert!(m.trace_line().starts_with("C5F9  B0 FC     BCS $C5F7 "));
+        // The `*SBC #imm` alias of nestest, that works like the official one
+        image[0xC000..0xC006].copy_from_slice(&[0x38, 0xA9, 0x40, 0xEB, 0x40, 0x00]);
+        for &cpu in &[Cpu::Mos6502X, Cpu::Ricoh2A03] {
+            let mut m = Machine::new(cpu, &image).unwrap();
+            m.reset(Some(0xC000));
+            let mut lines = Vec::new();
+            m.run_with(100, &[0xC005], &mut |m| lines.push(m.trace_line()));
+            assert_eq!(lines, vec![
+                "C000  38        SEC                             A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7",
+                "C001  A9 40     LDA #$40                        A:00 X:00 Y:00 P:25 SP:FD PPU:  0, 27 CYC:9",
+                "C003  EB 40    *SBC #$40                        A:40 X:00 Y:00 P:25 SP:FD PPU:  0, 33 CYC:11",
+            ]);
+            assert!(m
+                .trace_line()
+                .contains("A:00 X:00 Y:00 P:27 SP:FD PPU:  0, 39 CYC:13"));
+        }
+    }
+}